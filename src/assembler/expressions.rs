@@ -0,0 +1,361 @@
+//! `expressions` evaluates the constant expressions allowed in integer
+//! operands and `.equ` definitions, such as `#BUF_SIZE*2+1`, `#end-start`
+//! and `#'A'`.
+//!
+//! Expressions are made of decimal numbers, character literals, the names of
+//! `.equ` constants and labels, the operators `+ - * / %` and parentheses,
+//! with the usual precedence. They are evaluated with 64 bit arithmetic; the
+//! caller checks the result fits the operand it is encoded in.
+use std::collections::HashMap;
+
+use crate::assembler::label_parsers::is_label_char;
+use crate::assembler::symbols::SymbolTable;
+
+/// How deeply constants may be defined in terms of other constants.
+const MAX_DEPTH: usize = 64;
+
+/// How deeply parentheses, negations and constants may nest within one
+/// expression, so that deep nesting is an error rather than a stack overflow.
+const MAX_NESTING: usize = 256;
+
+/// The constants defined with `.equ`, kept as unevaluated expressions so they
+/// can refer to labels declared later in the program.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct Constants {
+    definitions: HashMap<String, String>,
+}
+
+impl Constants {
+    /// Returns an empty set of constants.
+    pub fn new() -> Constants {
+        Constants { definitions: HashMap::new() }
+    }
+
+    /// Defines `name` as `expression`. Returns false, leaving the constants
+    /// unchanged, if `name` was already defined.
+    pub fn define(&mut self, name: &str, expression: &str) -> bool {
+        if self.definitions.contains_key(name) {
+            return false;
+        }
+        self.definitions.insert(name.to_string(), expression.to_string());
+        true
+    }
+
+    /// Returns true if `name` is a constant.
+    pub fn contains(&self, name: &str) -> bool {
+        self.definitions.contains_key(name)
+    }
+
+    /// Returns the value of the constant `name`.
+    pub fn value(&self, name: &str, symbols: &SymbolTable) -> Result<i64, String> {
+        self.lookup(name, symbols, 0, 0)
+    }
+
+    /// Evaluates `expression`, looking names up as constants first and
+    /// labels second.
+    pub fn evaluate(&self, expression: &str, symbols: &SymbolTable) -> Result<i64, String> {
+        self.evaluate_at_depth(expression, symbols, 0, 0)
+    }
+
+    /// Evaluates `expression` as the definition of a constant `depth`
+    /// constants deep, already nested `nesting` deep.
+    fn evaluate_at_depth(
+        &self,
+        expression: &str,
+        symbols: &SymbolTable,
+        depth: usize,
+        nesting: usize,
+    ) -> Result<i64, String> {
+        let lookup = |name: &str, nesting| self.lookup(name, symbols, depth, nesting);
+        let mut evaluator = Evaluator { chars: expression.chars().collect(), pos: 0, nesting, lookup: &lookup };
+        let value = evaluator.expression()?;
+        evaluator.skip_whitespace();
+        match evaluator.peek() {
+            None => Ok(value),
+            Some(c) => Err(format!("unexpected `{}` in expression `{}`", c, expression)),
+        }
+    }
+
+    fn lookup(&self, name: &str, symbols: &SymbolTable, depth: usize, nesting: usize) -> Result<i64, String> {
+        if let Some(definition) = self.definitions.get(name) {
+            if depth >= MAX_DEPTH {
+                return Err(format!("constant `{}` is defined in terms of itself", name));
+            }
+            return self.evaluate_at_depth(definition, symbols, depth + 1, nesting);
+        }
+        symbols
+            .symbol_value(name)
+            .map(|offset| offset as i64)
+            .ok_or_else(|| format!("undefined name `{}`", name))
+    }
+}
+
+/// Returns the names of the constants and labels `expression` refers to.
+pub fn names(expression: &str) -> Vec<String> {
+    let mut names = vec![];
+    let mut chars = expression.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\'' {
+            // Skip the rest of a character literal, including an escaped quote.
+            if chars.next() == Some('\\') {
+                chars.next();
+            }
+            chars.next();
+        } else if is_label_char(c) && !c.is_ascii_digit() {
+            let mut name = c.to_string();
+            while let Some(&c) = chars.peek().filter(|&&c| is_label_char(c)) {
+                name.push(c);
+                chars.next();
+            }
+            names.push(name);
+        } else if c.is_ascii_digit() {
+            while chars.peek().is_some_and(|&c| is_label_char(c)) {
+                chars.next();
+            }
+        }
+    }
+    names
+}
+
+/// A recursive descent evaluator over the characters of an expression.
+struct Evaluator<'a> {
+    chars: Vec<char>,
+    pos: usize,
+    /// How deeply the evaluator has recursed, counting from the outermost
+    /// expression.
+    nesting: usize,
+    lookup: &'a dyn Fn(&str, usize) -> Result<i64, String>,
+}
+
+impl<'a> Evaluator<'a> {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).cloned()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    /// Consumes `c` if it is the next character after any whitespace.
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_whitespace();
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Goes one level deeper, failing past `MAX_NESTING`. The caller
+    /// decrements `nesting` once it is done.
+    fn nest(&mut self) -> Result<(), String> {
+        self.nesting += 1;
+        if self.nesting > MAX_NESTING {
+            return Err(format!("expression is nested more than {} levels deep", MAX_NESTING));
+        }
+        Ok(())
+    }
+
+    /// expression := term (('+' | '-') term)*
+    fn expression(&mut self) -> Result<i64, String> {
+        let mut value = self.term()?;
+        loop {
+            if self.eat('+') {
+                value = value.checked_add(self.term()?).ok_or_else(overflow)?;
+            } else if self.eat('-') {
+                value = value.checked_sub(self.term()?).ok_or_else(overflow)?;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    /// term := unary (('*' | '/' | '%') unary)*
+    fn term(&mut self) -> Result<i64, String> {
+        let mut value = self.unary()?;
+        loop {
+            if self.eat('*') {
+                value = value.checked_mul(self.unary()?).ok_or_else(overflow)?;
+            } else if self.eat('/') {
+                let divisor = self.unary()?;
+                value = value.checked_div(divisor).ok_or_else(|| divide_error(divisor))?;
+            } else if self.eat('%') {
+                let divisor = self.unary()?;
+                value = value.checked_rem(divisor).ok_or_else(|| divide_error(divisor))?;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    /// unary := '-' unary | primary
+    fn unary(&mut self) -> Result<i64, String> {
+        if self.eat('-') {
+            self.nest()?;
+            let value = self.unary()?;
+            self.nesting -= 1;
+            return value.checked_neg().ok_or_else(overflow);
+        }
+        self.primary()
+    }
+
+    /// primary := number | character | name | '(' expression ')'
+    fn primary(&mut self) -> Result<i64, String> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some('(') => {
+                self.pos += 1;
+                self.nest()?;
+                let value = self.expression()?;
+                self.nesting -= 1;
+                if !self.eat(')') {
+                    return Err("missing `)` in expression".to_string());
+                }
+                Ok(value)
+            }
+            Some('\'') => self.character(),
+            Some(c) if c.is_ascii_digit() => {
+                let digits = self.take_while(|c| c.is_ascii_digit());
+                digits.parse::<i64>().map_err(|_| overflow())
+            }
+            Some(c) if is_label_char(c) => {
+                let name = self.take_while(is_label_char);
+                self.nest()?;
+                let value = (self.lookup)(&name, self.nesting);
+                self.nesting -= 1;
+                value
+            }
+            Some(c) => Err(format!("unexpected `{}` in expression", c)),
+            None => Err("expression ends too soon".to_string()),
+        }
+    }
+
+    /// Evaluates a character literal such as `'A'` or `'\n'` to its code point.
+    fn character(&mut self) -> Result<i64, String> {
+        self.pos += 1;
+        let c = match self.peek() {
+            Some('\\') => {
+                self.pos += 1;
+                match self.peek().and_then(unescape) {
+                    Some(c) => c,
+                    None => return Err("unknown escape in character literal".to_string()),
+                }
+            }
+            Some(c) => c,
+            None => return Err("unterminated character literal".to_string()),
+        };
+        self.pos += 1;
+        if self.peek() != Some('\'') {
+            return Err("unterminated character literal".to_string());
+        }
+        self.pos += 1;
+        Ok(c as i64)
+    }
+
+    fn take_while(&mut self, predicate: fn(char) -> bool) -> String {
+        let start = self.pos;
+        while self.peek().is_some_and(predicate) {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos].iter().collect()
+    }
+}
+
+/// Returns the character that `c` stands for after a `\` in a character
+/// literal: `n`, `t`, `r`, `0`, `\` or `'`.
+pub fn unescape(c: char) -> Option<char> {
+    match c {
+        'n' => Some('\n'),
+        't' => Some('\t'),
+        'r' => Some('\r'),
+        '0' => Some('\0'),
+        '\\' | '\'' => Some(c),
+        _ => None,
+    }
+}
+
+fn overflow() -> String {
+    "expression overflows".to_string()
+}
+
+fn divide_error(divisor: i64) -> String {
+    if divisor == 0 {
+        "division by zero in expression".to_string()
+    } else {
+        overflow()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evaluate() {
+        let constants = Constants::new();
+        let symbols = SymbolTable::new();
+        assert_eq!(constants.evaluate("1+2*3", &symbols), Ok(7));
+        assert_eq!(constants.evaluate("(1+2)*3", &symbols), Ok(9));
+        assert_eq!(constants.evaluate("10-4-3", &symbols), Ok(3));
+        assert_eq!(constants.evaluate("-7/2 + 7 % 4", &symbols), Ok(0));
+        assert_eq!(constants.evaluate("'A'+1", &symbols), Ok(66));
+        assert_eq!(constants.evaluate("'\\n'", &symbols), Ok(10));
+        assert_eq!(constants.evaluate("' '", &symbols), Ok(32));
+    }
+
+    #[test]
+    fn test_evaluate_names() {
+        let mut constants = Constants::new();
+        let mut symbols = SymbolTable::new();
+        symbols.add_symbol("start", 4);
+        symbols.add_symbol("end", 20);
+        assert!(constants.define("BUF_SIZE", "256"));
+        assert!(constants.define("LEN", "end - start"));
+        assert!(!constants.define("LEN", "0"));
+        assert_eq!(constants.evaluate("BUF_SIZE*2+1", &symbols), Ok(513));
+        assert_eq!(constants.value("LEN", &symbols), Ok(16));
+        assert_eq!(constants.evaluate("nope", &symbols), Err("undefined name `nope`".to_string()));
+    }
+
+    #[test]
+    fn test_names() {
+        assert_eq!(names("end-start+BUF_2*2"), vec!["end", "start", "BUF_2"]);
+        assert_eq!(names("'a'+'\\''+x"), vec!["x"]);
+        assert!(names("12").is_empty());
+    }
+
+    #[test]
+    fn test_evaluate_errors() {
+        let mut constants = Constants::new();
+        let symbols = SymbolTable::new();
+        constants.define("A", "B");
+        constants.define("B", "A+1");
+        assert!(constants.value("A", &symbols).unwrap_err().contains("in terms of itself"));
+        assert_eq!(constants.evaluate("1/0", &symbols), Err("division by zero in expression".to_string()));
+        assert_eq!(constants.evaluate("99999999999*99999999999", &symbols), Err(overflow()));
+        assert!(constants.evaluate("(1", &symbols).is_err());
+        assert!(constants.evaluate("1 2", &symbols).is_err());
+        assert!(constants.evaluate("'ab'", &symbols).is_err());
+    }
+
+    #[test]
+    fn test_evaluate_nesting_limit() {
+        let mut constants = Constants::new();
+        let symbols = SymbolTable::new();
+        let too_deep = Err("expression is nested more than 256 levels deep".to_string());
+        assert_eq!(constants.evaluate(&format!("{}1{}", "(".repeat(100_000), ")".repeat(100_000)), &symbols), too_deep);
+        assert_eq!(constants.evaluate(&format!("{}1", "-".repeat(200_000)), &symbols), too_deep);
+        assert_eq!(constants.evaluate(&format!("{}1{}", "(".repeat(200), ")".repeat(200)), &symbols), Ok(1));
+
+        // Nesting inside constants counts towards the same limit.
+        let parens = |n| format!("{}1{}", "(".repeat(n), ")".repeat(n));
+        constants.define("A", &parens(200));
+        constants.define("B", "A");
+        let wrapped = |n| format!("{}B{}", "(".repeat(n), ")".repeat(n));
+        assert_eq!(constants.evaluate(&wrapped(54), &symbols), Ok(1));
+        assert_eq!(constants.evaluate(&wrapped(55), &symbols), too_deep);
+    }
+}
@@ -0,0 +1,215 @@
+//! `formatter` rewrites assembly source in the one layout used across the
+//! project, for `iridescent fmt`.
+//!
+//! Labels start in the first column and everything else is indented, with
+//! mnemonics in lower case and their operands lined up in a column after
+//! them. Trailing comments are lined up with the other trailing comments in
+//! the same block of lines, and runs of blank lines are collapsed into one.
+//! `.macro` and `.endm` start in the first column, like labels, so macro
+//! bodies stand out.
+use nom::types::CompleteStr;
+
+use crate::assembler::program_parsers;
+use crate::assembler::source::{split_comment, split_label};
+use crate::assembler::AssembleError;
+use crate::instruction::Opcode;
+
+/// The column instructions and directives start in.
+const INDENT: usize = 8;
+
+/// The width mnemonics are padded to, so their operands start in one column.
+const MNEMONIC_WIDTH: usize = 8;
+
+/// A formatted line, before trailing comments are lined up.
+struct Line {
+    code: String,
+    comment: Option<String>,
+}
+
+/// Returns `source` formatted, or the first line that doesn't parse as an
+/// instruction. Macro calls and macro bodies are formatted without being
+/// parsed, since their meaning depends on the macro's definition.
+pub fn format_source(source: &str) -> Result<String, AssembleError> {
+    let mut lines: Vec<Option<Line>> = vec![];
+    let mut in_macro = false;
+    for (index, text) in source.lines().enumerate() {
+        let (code, comment) = split_comment(text);
+        let code = code.trim();
+        let comment = comment.map(|comment| format!(";{}", comment.trim_end()));
+        if code.is_empty() {
+            match comment {
+                // Comments on lines of their own keep to the first column if
+                // they started there.
+                Some(comment) if text.starts_with(';') => lines.push(Some(Line { code: comment, comment: None })),
+                Some(comment) => lines.push(Some(Line { code: indent(comment), comment: None })),
+                None => lines.push(None),
+            }
+            continue;
+        }
+
+        let code = if code.starts_with('.') {
+            let (directive, rest) = split_first_word(code);
+            let code = if rest.is_empty() { directive.to_string() } else { format!("{} {}", directive, rest) };
+            match directive {
+                ".macro" => {
+                    in_macro = true;
+                    code
+                }
+                ".endm" => {
+                    in_macro = false;
+                    code
+                }
+                _ => indent(code),
+            }
+        } else {
+            let (label, statement) = split_label(code);
+            let (first, _) = split_first_word(statement);
+            let opcode = Opcode::from_mnemonic(first);
+            if opcode.is_some() && !in_macro && !parses(code) {
+                return Err(AssembleError { line: index + 1, message: format!("unable to parse `{}`", code) });
+            }
+            let mut words = split_words(statement);
+            if let (Some(opcode), Some(first)) = (opcode, words.first_mut()) {
+                *first = opcode.mnemonic().to_string();
+            }
+            let statement = match words.split_first() {
+                Some((first, [])) => first.clone(),
+                Some((first, operands)) => format!("{:<width$} {}", first, operands.join(" "), width = MNEMONIC_WIDTH - 1),
+                None => String::new(),
+            };
+            match label {
+                Some(label) if statement.is_empty() => format!("{}:", label),
+                Some(label) => format!("{:<width$} {}", format!("{}:", label), statement, width = INDENT - 1),
+                None => indent(statement),
+            }
+        };
+        lines.push(Some(Line { code, comment }));
+    }
+
+    let mut formatted = String::new();
+    let mut blocks = lines.split(Option::is_none).filter(|block| !block.is_empty()).peekable();
+    while let Some(block) = blocks.next() {
+        let column = block
+            .iter()
+            .flatten()
+            .filter(|line| line.comment.is_some())
+            .map(|line| line.code.len() + 1)
+            .max()
+            .unwrap_or(0);
+        for line in block.iter().flatten() {
+            match &line.comment {
+                Some(comment) => formatted.push_str(&format!("{:<width$}{}", line.code, comment, width = column)),
+                None => formatted.push_str(&line.code),
+            }
+            formatted.push('\n');
+        }
+        if blocks.peek().is_some() {
+            formatted.push('\n');
+        }
+    }
+    Ok(formatted)
+}
+
+/// Returns true if `code` parses as a label and instruction.
+fn parses(code: &str) -> bool {
+    match program_parsers::source_line(CompleteStr(code)) {
+        Ok((rest, (_, instruction))) => rest.trim().is_empty() && instruction.is_some(),
+        Err(_) => false,
+    }
+}
+
+fn indent(code: String) -> String {
+    format!("{:width$}{}", "", code, width = INDENT)
+}
+
+fn split_first_word(code: &str) -> (&str, &str) {
+    let mut words = code.splitn(2, char::is_whitespace);
+    (words.next().unwrap_or(""), words.next().unwrap_or("").trim())
+}
+
+/// Splits `code` at whitespace outside of character literals such as `' '`.
+fn split_words(code: &str) -> Vec<String> {
+    let mut words = vec![];
+    let mut word = String::new();
+    let mut quoted = false;
+    let mut escaped = false;
+    for c in code.chars() {
+        if c.is_whitespace() && !quoted {
+            if !word.is_empty() {
+                words.push(std::mem::take(&mut word));
+            }
+            continue;
+        }
+        word.push(c);
+        if escaped {
+            escaped = false;
+        } else if quoted && c == '\\' {
+            escaped = true;
+        } else if c == '\'' {
+            quoted = !quoted;
+        }
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    const MESSY: &str = "\
+; Counts $0 down to zero.
+   .equ   START 10
+
+
+  LOAD $0 #START   ;  the counter
+load $1   @loop
+loop:   dec $0 ; once per pass
+   JNEQ $1
+      ; fall through when done
+done:
+LOAD $2 #' '
+  .macro twice r
+inc \\r
+  inc \\r
+.endm
+  twice $2
+hlt
+";
+
+    const FORMATTED: &str = "\
+; Counts $0 down to zero.
+        .equ START 10
+
+        load    $0 #START ;  the counter
+        load    $1 @loop
+loop:   dec     $0        ; once per pass
+        jneq    $1
+        ; fall through when done
+done:
+        load    $2 #' '
+.macro twice r
+        inc     \\r
+        inc     \\r
+.endm
+        twice   $2
+        hlt
+";
+
+    #[test]
+    fn test_format_source() {
+        assert_eq!(format_source(MESSY).unwrap(), FORMATTED);
+        assert_eq!(format_source(FORMATTED).unwrap(), FORMATTED);
+        assert_eq!(assemble(MESSY), assemble(FORMATTED));
+        assert_eq!(format_source("\n\nhlt\n\n"), Ok("        hlt\n".to_string()));
+    }
+
+    #[test]
+    fn test_format_source_errors() {
+        let error = format_source("hlt\nload $0 #\n").unwrap_err();
+        assert_eq!(error, AssembleError { line: 2, message: "unable to parse `load $0 #`".to_string() });
+    }
+}
@@ -0,0 +1,145 @@
+//! `includes` splices the files named by `.include "file.iasm"` directives
+//! into the source before macros are expanded, so included files can share
+//! both routines and macros.
+//!
+//! An included file is looked for next to the file including it first, then
+//! in each directory of the include path in order.
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::assembler::source::{split_comment, Location, SourceLine};
+use crate::assembler::AssembleError;
+
+/// Returns the lines of `source`, read from `file`, with every `.include`
+/// directive replaced by the lines of the file it names.
+pub fn read(source: &str, file: &str, include_path: &[PathBuf]) -> Result<Vec<SourceLine>, AssembleError> {
+    let dir = Path::new(file).parent().map(Path::to_path_buf).unwrap_or_default();
+    let mut stack = vec![];
+    if let Ok(path) = fs::canonicalize(file) {
+        stack.push((path, file.to_string()));
+    }
+    let mut reader = Reader { include_path, stack, lines: vec![] };
+    reader.read(source, None, &dir, None)?;
+    Ok(reader.lines)
+}
+
+struct Reader<'a> {
+    include_path: &'a [PathBuf],
+    /// The files currently being read, canonicalized and as displayed,
+    /// outermost first.
+    stack: Vec<(PathBuf, String)>,
+    lines: Vec<SourceLine>,
+}
+
+impl<'a> Reader<'a> {
+    /// Appends the lines of `source`, without their comments, where `source`
+    /// is `file` or the source being assembled when `file` is `None`.
+    /// `included_at` is the line of the outermost `.include` that led here.
+    fn read(
+        &mut self,
+        source: &str,
+        file: Option<&str>,
+        dir: &Path,
+        included_at: Option<usize>,
+    ) -> Result<(), AssembleError> {
+        for (index, text) in source.lines().enumerate() {
+            let (text, _) = split_comment(text);
+            let line = SourceLine {
+                line: included_at.unwrap_or(index + 1),
+                text: text.to_string(),
+                origin: Location { file: file.map(str::to_string), line: index + 1 },
+                expanded_from: None,
+            };
+            let rest = match text.trim().strip_prefix(".include") {
+                Some(rest) if rest.is_empty() || rest.starts_with(char::is_whitespace) => rest.trim(),
+                _ => {
+                    self.lines.push(line);
+                    continue;
+                }
+            };
+
+            let name = match rest.strip_prefix('"').and_then(|rest| rest.strip_suffix('"')) {
+                Some(name) if !name.is_empty() => name,
+                _ => return Err(line.error("`.include` needs a file name in double quotes".to_string())),
+            };
+            let path = self.find(name, dir).ok_or_else(|| {
+                line.error(format!("can't find `{}` next to the including file or on the include path", name))
+            })?;
+            let display = path.to_string_lossy().into_owned();
+            let canonical = fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+            if let Some(start) = self.stack.iter().position(|(open, _)| *open == canonical) {
+                let mut cycle: Vec<&str> = self.stack[start..].iter().map(|(_, shown)| shown.as_str()).collect();
+                cycle.push(&display);
+                return Err(line.error(format!("include cycle: {}", cycle.join(" -> "))));
+            }
+            let included = fs::read_to_string(&path)
+                .map_err(|e| line.error(format!("can't read `{}`: {}", display, e)))?;
+
+            let included_dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+            self.stack.push((canonical, display.clone()));
+            self.read(&included, Some(&display), &included_dir, Some(line.line))?;
+            self.stack.pop();
+        }
+        Ok(())
+    }
+
+    /// Returns the first existing file called `name` next to the including
+    /// file or in the include path.
+    fn find(&self, name: &str, dir: &Path) -> Option<PathBuf> {
+        std::iter::once(dir)
+            .chain(self.include_path.iter().map(PathBuf::as_path))
+            .map(|dir| dir.join(name))
+            .find(|path| path.is_file())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a fresh, empty directory for a test to write files into.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("iridescent-includes-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_read_includes_files() {
+        let dir = test_dir("nested");
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        fs::write(dir.join("a.iasm"), "inc $0\n.include \"b.iasm\"\n").unwrap();
+        fs::write(dir.join("lib").join("b.iasm"), "dec $0\n").unwrap();
+        let main = dir.join("main.iasm");
+        let main = main.to_str().unwrap();
+
+        let lines = read("load $0 #1\n.include \"a.iasm\"\nhlt\n", main, &[dir.join("lib")]).unwrap();
+        let texts: Vec<&str> = lines.iter().map(|line| line.text.as_str()).collect();
+        assert_eq!(texts, vec!["load $0 #1", "inc $0", "dec $0", "hlt"]);
+        assert_eq!(lines[2].line, 2);
+        assert_eq!(lines[2].origin.line, 1);
+        assert!(lines[2].origin.file.as_ref().unwrap().ends_with("b.iasm"));
+        assert_eq!(lines[3].origin, Location { file: None, line: 3 });
+    }
+
+    #[test]
+    fn test_read_errors() {
+        let dir = test_dir("errors");
+        let main = dir.join("main.iasm");
+        let main = main.to_str().unwrap();
+
+        let error = read(".include \"missing.iasm\"\n", main, &[]).unwrap_err();
+        assert_eq!(error.line, 1);
+        assert!(error.message.starts_with("can't find `missing.iasm`"));
+
+        assert!(read(".include missing.iasm\n", main, &[]).is_err());
+
+        fs::write(dir.join("a.iasm"), "hlt\n.include \"b.iasm\"\n").unwrap();
+        fs::write(dir.join("b.iasm"), ".include \"a.iasm\"\n").unwrap();
+        let error = read("\n.include \"a.iasm\"\n", main, &[]).unwrap_err();
+        assert_eq!(error.line, 2);
+        assert!(error.message.starts_with("include cycle: "));
+        assert!(error.message.contains("b.iasm line 1"));
+    }
+}
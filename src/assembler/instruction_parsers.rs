@@ -1,10 +1,18 @@
+use std::collections::HashSet;
+use std::fmt;
+
 use crate::assembler::Token;
+use crate::assembler::expressions::{self, Constants};
+use crate::assembler::symbols::SymbolTable;
+use crate::instruction::{Opcode, Operand};
+use crate::object::{Relocation, Target};
 use crate::assembler::opcode_parsers::*;
-use crate::assembler::operand_parsers::integer_operand;
-use crate::assembler::register_parsers::register;
+use crate::assembler::operand_parsers::operand;
+use crate::vm::REGISTER_COUNT;
 use nom::named;
 use nom::types::CompleteStr;
 use nom::*;
+/// A single parsed instruction: an opcode and up to three operands.
 #[derive(Debug, PartialEq, Clone)]
 pub struct AssemblerInstruction {
     opcode: Token, 
@@ -14,35 +22,175 @@ pub struct AssemblerInstruction {
 }
 
 impl AssemblerInstruction {
-    /// Represents an Opcode instruction in terms of assembly.
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let mut results = vec![];
+    /// Returns an instruction with the opcode `code` and the first three of
+    /// `operands`.
+    pub fn new(code: Opcode, operands: Vec<Token>) -> AssemblerInstruction {
+        let mut operands = operands.into_iter();
+        AssemblerInstruction {
+            opcode: Token::Op { code },
+            operand_1: operands.next(),
+            operand_2: operands.next(),
+            operand_3: operands.next(),
+        }
+    }
+
+    /// Returns the instruction's opcode.
+    pub fn opcode(&self) -> Opcode {
         match self.opcode {
-            Token::Op { code } => match code {
+            Token::Op { code } => code,
+            _ => Opcode::IGL,
+        }
+    }
+
+    /// Returns the instruction's operands, in order.
+    pub fn operands(&self) -> Vec<&Token> {
+        [&self.operand_1, &self.operand_2, &self.operand_3].into_iter().flatten().collect()
+    }
+
+    /// Returns how many bytes the instruction assembles to.
+    pub fn byte_len(&self) -> usize {
+        let operands = [&self.operand_1, &self.operand_2, &self.operand_3];
+        1 + operands
+            .iter()
+            .filter_map(|operand| operand.as_ref())
+            .map(|operand| match operand {
+                Token::Register { .. } => 1,
+                _ => 2,
+            })
+            .sum::<usize>()
+    }
+
+    /// Returns the names of the labels and constants the operands refer to.
+    pub fn names(&self) -> Vec<String> {
+        let mut names = vec![];
+        for operand in [&self.operand_1, &self.operand_2, &self.operand_3].into_iter().flatten() {
+            match operand {
+                Token::LabelUsage { name } => names.push(name.clone()),
+                Token::Expression { text } => names.extend(expressions::names(text)),
+                _ => {}
+            }
+        }
+        names
+    }
+
+    /// Describes the problem if the operands aren't the ones the opcode
+    /// takes, or a register operand names a register the VM doesn't have.
+    pub fn check_operands(&self) -> Result<(), String> {
+        let opcode = self.opcode();
+        let kinds = opcode.operands();
+        let operands = self.operands();
+        let matches = operands.len() == kinds.len()
+            && operands.iter().zip(kinds).all(|(token, kind)| {
+                matches!(
+                    (token, kind),
+                    (Token::Register { .. }, Operand::Register | Operand::Padding)
+                        | (Token::IntegerOperand { .. } | Token::LabelUsage { .. } | Token::Expression { .. }, Operand::Integer)
+                )
+            });
+        if !matches {
+            return Err(format!("`{}` takes {}", opcode.mnemonic(), describe_operands(kinds)));
+        }
+        for operand in [&self.operand_1, &self.operand_2, &self.operand_3] {
+            if let Some(Token::Register { reg_num }) = operand {
+                if *reg_num as usize >= REGISTER_COUNT {
+                    return Err(format!(
+                        "register ${} is out of range; registers are $0 to ${}",
+                        reg_num,
+                        REGISTER_COUNT - 1
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces every label usage operand with the offset of the label it
+    /// names and every expression with its value, then checks each integer
+    /// fits the operand it is encoded in. Describes the first problem found.
+    ///
+    /// Returns the operands that depend on where the code ends up, with
+    /// offsets relative to the start of the instruction. Usages of `externs`
+    /// are left as 0, to be filled in by the linker.
+    pub fn relocate_operands(
+        &mut self,
+        symbols: &SymbolTable,
+        constants: &Constants,
+        externs: &HashSet<String>,
+    ) -> Result<Vec<Relocation>, String> {
+        let bits = Operand::Integer.width() * 8;
+        let max = (1i64 << bits) - 1;
+        let mut relocations = vec![];
+        let mut offset = 1;
+        for operand in [&mut self.operand_1, &mut self.operand_2, &mut self.operand_3] {
+            let width = match operand {
+                Some(Token::Register { .. }) => Operand::Register.width(),
+                _ => Operand::Integer.width(),
+            };
+            let value = match operand {
+                Some(Token::LabelUsage { name }) if externs.contains(name) => {
+                    relocations.push(Relocation { offset, target: Target::Symbol(name.clone()) });
+                    0
+                }
+                Some(Token::LabelUsage { name }) => {
+                    let label = symbols
+                        .symbol_value(name)
+                        .ok_or_else(|| format!("undefined label `{}`", name))?;
+                    if label as i64 > max {
+                        return Err(format!("label `{}` is at offset {}, past the {} bit operand range", name, label, bits));
+                    }
+                    relocations.push(Relocation { offset, target: Target::Local });
+                    label as i64
+                }
+                Some(Token::Expression { text }) => {
+                    if let Some(name) = expressions::names(text).into_iter().find(|name| externs.contains(name)) {
+                        return Err(format!("external label `{}` can only be used on its own, as `@{}`", name, name));
+                    }
+                    let value = constants.evaluate(text, symbols)?;
+                    // Evaluating with every label moved on by one shows how the
+                    // value moves when the code does.
+                    match constants.evaluate(text, &symbols.shifted(1))? - value {
+                        0 => {}
+                        1 => relocations.push(Relocation { offset, target: Target::Local }),
+                        _ => return Err(format!("expression `{}` can't be relocated", text)),
+                    }
+                    value
+                }
+                Some(Token::IntegerOperand { value }) => *value as i64,
                 _ => {
-                    results.push(code as  u8);
+                    offset += width;
+                    continue;
                 }
-            },
-            _ => {
-                println!("Non-opcode found in opcode field");
-                std::process::exit(1);
+            };
+            if value < 0 || value > max {
+                return Err(format!("value {} doesn't fit in a {} bit operand", value, bits));
             }
+            *operand = Some(Token::IntegerOperand { value: value as i32 });
+            offset += width;
+        }
+        Ok(relocations)
+    }
+
+    /// Represents an Opcode instruction in terms of assembly. Fails if an
+    /// operand is still a label or expression, rather than a value.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, String> {
+        let mut results = vec![];
+        match self.opcode {
+            Token::Op { code } => {
+                results.push(code as u8);
+            },
+            _ => return Err(format!("`{}` found in opcode field", self.opcode)),
         };
 
-        for operand in vec![&self.operand_1, &self.operand_2, &self.operand_3] {
-            match operand {
-                Some(t) => AssemblerInstruction::extract_operand(t, &mut results),
-                None => {}
-            }
+        for t in [&self.operand_1, &self.operand_2, &self.operand_3].into_iter().flatten() {
+            AssemblerInstruction::extract_operand(t, &mut results)?;
         }
 
-        return results;
+        Ok(results)
     }
 
-    fn extract_operand(t: &Token, results: &mut Vec<u8>) {
-    
     /// Extracts a series of bytes representing an operand and adds
     /// the results to a vector.
+    fn extract_operand(t: &Token, results: &mut Vec<u8>) -> Result<(), String> {
     match t {
         Token::Register { reg_num } => {
             results.push(*reg_num);
@@ -54,54 +202,136 @@ impl AssemblerInstruction {
             results.push(byte2 as u8);
             results.push(byte1 as u8);
         }
-        _ => {
-            println!("Opcode found in operand field");
-            std::process::exit(1);
-        }
-    }        
+        _ => return Err(format!("`{}` found in operand field before it was resolved", t)),
+    }
+    Ok(())
+    }
+
+}
 
+/// Describes operands of the kinds `kinds` for an error message, such as
+/// "3 registers" or "a register and an integer". Padding is written as a
+/// register.
+fn describe_operands(kinds: &[Operand]) -> String {
+    let names: Vec<&str> = kinds
+        .iter()
+        .map(|kind| match kind {
+            Operand::Register | Operand::Padding => "a register",
+            Operand::Integer => "an integer",
+        })
+        .collect();
+    match names.as_slice() {
+        [] => "no operands".to_string(),
+        [name] => name.to_string(),
+        _ if names.iter().all(|&name| name == "a register") => format!("{} registers", names.len()),
+        [init @ .., last] => format!("{} and {}", init.join(", "), last),
     }
+}
 
+impl fmt::Display for AssemblerInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.opcode)?;
+        for operand in self.operands() {
+            write!(f, " {}", operand)?;
+        }
+        Ok(())
+    }
 }
 
-/// Parses instructions of the form LOAD $0 #100.
-named!(pub instruction_one<CompleteStr, AssemblerInstruction>,
+named!(#[doc = "Parses an instruction with up to three register, integer or label operands, such as `add $0 $1 $2` or `hlt`."],
+    pub instruction<CompleteStr, AssemblerInstruction>,
     do_parse!(
-        op: opcode_load >>
-        reg: register >>
-        integer_op: integer_operand >>
+        op: opcode >>
+        operand_1: opt!(operand) >>
+        operand_2: opt!(operand) >>
+        operand_3: opt!(operand) >>
         (
             AssemblerInstruction {
                 opcode: op,
-                operand_1: Some(reg),
-                operand_2: Some(integer_op),
-                operand_3: None
+                operand_1,
+                operand_2,
+                operand_3
             }
         )
     )
-
 );
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    use crate::instruction::Opcode;
+    #[test]
+    fn test_parse_instruction() {
+        let (rest, parsed) = instruction(CompleteStr("add $0 $1 $2\nhlt")).unwrap();
+        assert_eq!(rest, CompleteStr("hlt"));
+        assert_eq!(parsed.to_bytes(), Ok(vec![1, 0, 1, 2]));
+
+        let (rest, parsed) = instruction(CompleteStr("hlt")).unwrap();
+        assert_eq!(rest, CompleteStr(""));
+        assert_eq!(parsed.to_bytes(), Ok(vec![5]));
+
+        let (_, parsed) = instruction(CompleteStr("load $3 @loop")).unwrap();
+        assert_eq!(parsed.to_bytes(), Err("`@loop` found in operand field before it was resolved".to_string()));
+    }
+
+    #[test]
+    fn test_new_instruction() {
+        let built = AssemblerInstruction::new(
+            Opcode::ADDI,
+            vec![Token::Register { reg_num: 0 }, Token::Expression { text: "SIZE*2".to_string() }, Token::Register { reg_num: 1 }],
+        );
+        let (_, parsed) = instruction(CompleteStr("addi $0 #SIZE*2 $1")).unwrap();
+        assert_eq!(built, parsed);
+        assert_eq!(built.opcode(), Opcode::ADDI);
+        assert_eq!(built.operands().len(), 3);
+        assert_eq!(built.to_string(), "addi $0 #SIZE*2 $1");
+
+        let (_, parsed) = instruction(CompleteStr("load $3 @loop")).unwrap();
+        assert_eq!(parsed.to_string(), "load $3 @loop");
+    }
 
     #[test]
-    fn test_parse_instruction_one() {
-        let result = instruction_one(CompleteStr("load $0 #100\n"));
+    fn test_resolve_labels() {
+        let (_, mut parsed) = instruction(CompleteStr("load $3 @loop")).unwrap();
+        assert_eq!(parsed.byte_len(), 4);
+
+        let mut symbols = SymbolTable::new();
+        let constants = Constants::new();
+        let externs = HashSet::new();
         assert_eq!(
-            result,
-            Ok((
-                CompleteStr(""),
-                AssemblerInstruction {
-                    opcode: Token::Op { code: Opcode::LOAD},
-                    operand_1: Some(Token::Register { reg_num: 0}),
-                    operand_2: Some(Token::Number { value:  100}),
-                    operand_3: None
-                }
-            ))
-        )
+            parsed.relocate_operands(&symbols, &constants, &externs),
+            Err("undefined label `loop`".to_string())
+        );
+
+        symbols.add_symbol("loop", 260);
+        let relocations = parsed.relocate_operands(&symbols, &constants, &externs).unwrap();
+        assert_eq!(parsed.to_bytes(), Ok(vec![0, 3, 1, 4]));
+        assert_eq!(relocations, vec![Relocation { offset: 2, target: Target::Local }]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_relocate_operands() {
+        let mut symbols = SymbolTable::new();
+        symbols.add_symbol("start", 4);
+        symbols.add_symbol("end", 10);
+        let constants = Constants::new();
+        let externs: HashSet<String> = ["print".to_string()].into_iter().collect();
+
+        let (_, mut parsed) = instruction(CompleteStr("addi $0 #end-start $1")).unwrap();
+        assert_eq!(parsed.relocate_operands(&symbols, &constants, &externs), Ok(vec![]));
+        assert_eq!(parsed.to_bytes(), Ok(vec![45, 0, 0, 6, 1]));
+
+        let (_, mut parsed) = instruction(CompleteStr("addi $0 #end+2 $1")).unwrap();
+        let relocations = parsed.relocate_operands(&symbols, &constants, &externs).unwrap();
+        assert_eq!(relocations, vec![Relocation { offset: 2, target: Target::Local }]);
+
+        let (_, mut parsed) = instruction(CompleteStr("load $0 @print")).unwrap();
+        let relocations = parsed.relocate_operands(&symbols, &constants, &externs).unwrap();
+        assert_eq!(relocations, vec![Relocation { offset: 2, target: Target::Symbol("print".to_string()) }]);
+
+        let (_, mut parsed) = instruction(CompleteStr("load $0 #end*2")).unwrap();
+        assert!(parsed.relocate_operands(&symbols, &constants, &externs).is_err());
+        let (_, mut parsed) = instruction(CompleteStr("load $0 #print+1")).unwrap();
+        assert!(parsed.relocate_operands(&symbols, &constants, &externs).is_err());
+    }
+}
@@ -0,0 +1,62 @@
+use crate::assembler::Token;
+use nom::{named, ws, tag, take_while1, types::CompleteStr};
+
+/// Returns true for characters allowed in a label name.
+pub fn is_label_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+named!(#[doc = "Parses label declarations of the form `loop:`."],
+    pub label_declaration<CompleteStr, Token>,
+    ws!(
+        do_parse!(
+            name: take_while1!(is_label_char) >>
+            tag!(":") >>
+            (
+                Token::LabelDeclaration{name: name.to_string()}
+            )
+        )
+    )
+);
+
+named!(#[doc = "Parses label usages of the form `@loop`."],
+    pub label_usage<CompleteStr, Token>,
+    ws!(
+        do_parse!(
+            tag!("@") >>
+            name: take_while1!(is_label_char) >>
+            (
+                Token::LabelUsage{name: name.to_string()}
+            )
+        )
+    )
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_label_declaration() {
+        let result = label_declaration(CompleteStr("loop_2: add $0 $1 $2"));
+        assert!(result.is_ok());
+        let (rest, token) = result.unwrap();
+        assert_eq!(token, Token::LabelDeclaration{name: "loop_2".to_string()});
+        assert_eq!(rest, CompleteStr("add $0 $1 $2"));
+
+        let result = label_declaration(CompleteStr("loop"));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_label_usage() {
+        let result = label_usage(CompleteStr("@loop"));
+        assert!(result.is_ok());
+        let (rest, token) = result.unwrap();
+        assert_eq!(token, Token::LabelUsage{name: "loop".to_string()});
+        assert_eq!(rest, CompleteStr(""));
+
+        let result = label_usage(CompleteStr("loop"));
+        assert!(result.is_err());
+    }
+}
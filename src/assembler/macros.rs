@@ -0,0 +1,345 @@
+//! `macros` expands `.macro` definitions before the assembler parses any
+//! instructions.
+//!
+//! A definition names the macro and its parameters, and the body refers to
+//! each parameter as `\name`:
+//!
+//! ```text
+//! .macro blt_to a, b, target
+//! load $31 @\target
+//! blt \a \b $31
+//! .endm
+//!
+//! blt_to $0, $1, loop
+//! ```
+//!
+//! Labels declared in a body are local to each expansion: they, and the label
+//! usages and `#` expressions in the same body that refer to them, are
+//! renamed so a macro can be used more than once without declaring the same
+//! label twice.
+use std::collections::HashMap;
+
+use crate::assembler::label_parsers::is_label_char;
+use crate::assembler::source::{split_label, SourceLine};
+use crate::assembler::AssembleError;
+use crate::instruction::Opcode;
+
+/// How deeply macros may call other macros before expansion gives up.
+const MAX_DEPTH: usize = 64;
+
+#[derive(Debug)]
+struct Macro {
+    /// The `.macro` directive.
+    header: SourceLine,
+    parameters: Vec<String>,
+    body: Vec<SourceLine>,
+}
+
+/// Collects macro definitions from `source` and expands every call, returning
+/// the remaining lines. Blank lines are dropped.
+pub fn expand(source: Vec<SourceLine>) -> Result<Vec<SourceLine>, AssembleError> {
+    let mut macros: HashMap<String, Macro> = HashMap::new();
+    let mut lines = vec![];
+    let mut defining: Option<(String, Macro)> = None;
+
+    for source_line in source {
+        let trimmed = source_line.text.trim();
+        let error = |message: String| source_line.error(message);
+        if trimmed.is_empty() {
+            continue;
+        }
+        let mut words = trimmed.splitn(2, char::is_whitespace);
+        let directive = words.next().unwrap_or("");
+        let rest = words.next().unwrap_or("");
+
+        if directive == ".macro" {
+            if defining.is_some() {
+                return Err(error("macros can't be defined inside other macros".to_string()));
+            }
+            let mut names = split_arguments(rest).into_iter();
+            let name = names.next().ok_or_else(|| error("`.macro` needs a name".to_string()))?;
+            if !name.chars().all(is_label_char) {
+                return Err(error(format!("`{}` is not a valid macro name", name)));
+            }
+            if Opcode::from_mnemonic(&name).is_some() {
+                return Err(error(format!("macro `{}` has the same name as an opcode", name)));
+            }
+            if let Some(existing) = macros.get(&name) {
+                return Err(error(format!(
+                    "macro `{}` is already defined at {}",
+                    name, existing.header.origin
+                )));
+            }
+            let parameters: Vec<String> = names.collect();
+            let header = source_line.clone();
+            defining = Some((name, Macro { header, parameters, body: vec![] }));
+        } else if directive == ".endm" {
+            match defining.take() {
+                Some((name, definition)) => {
+                    macros.insert(name, definition);
+                }
+                None => return Err(error("`.endm` without a matching `.macro`".to_string())),
+            }
+        } else if let Some((name, definition)) = defining.as_mut() {
+            check_parameters(name, definition, &source_line)?;
+            let text = trimmed.to_string();
+            definition.body.push(SourceLine { text, ..source_line });
+        } else {
+            let text = trimmed.to_string();
+            lines.push(SourceLine { text, ..source_line });
+        }
+    }
+    if let Some((name, definition)) = defining {
+        return Err(definition.header.error(format!("macro `{}` is missing its `.endm`", name)));
+    }
+
+    let mut expander = Expander { macros: &macros, expansions: 0 };
+    let mut expanded = vec![];
+    for line in lines {
+        expander.expand_line(line, &mut vec![], &mut expanded)?;
+    }
+    Ok(expanded)
+}
+
+/// Splits a parameter or argument list separated by commas or whitespace.
+fn split_arguments(text: &str) -> Vec<String> {
+    text.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|argument| !argument.is_empty())
+        .map(|argument| argument.to_string())
+        .collect()
+}
+
+/// Checks that every `\name` in a body line is one of the macro's parameters.
+fn check_parameters(name: &str, definition: &Macro, line: &SourceLine) -> Result<(), AssembleError> {
+    for parameter in references(&line.text) {
+        if !definition.parameters.contains(&parameter) {
+            return Err(line.error(format!("macro `{}` has no parameter `{}`", name, parameter)));
+        }
+    }
+    Ok(())
+}
+
+/// Returns the names of the `\name` parameter references in `text`.
+fn references(text: &str) -> Vec<String> {
+    text.split('\\')
+        .skip(1)
+        .map(|after| after.chars().take_while(|&c| is_label_char(c)).collect())
+        .collect()
+}
+
+struct Expander<'a> {
+    macros: &'a HashMap<String, Macro>,
+    /// How many expansions have been made, used to make local labels unique.
+    expansions: usize,
+}
+
+impl<'a> Expander<'a> {
+    /// Appends `line` to `output`, expanding it first if it calls a macro.
+    /// `calls` holds the macros currently being expanded, innermost last.
+    fn expand_line(
+        &mut self,
+        line: SourceLine,
+        calls: &mut Vec<String>,
+        output: &mut Vec<SourceLine>,
+    ) -> Result<(), AssembleError> {
+        let (label, rest) = split_label(&line.text);
+        let mut words = rest.splitn(2, char::is_whitespace);
+        let name = words.next().unwrap_or("");
+        let definition = match self.macros.get(name) {
+            Some(definition) => definition,
+            None => {
+                output.push(line);
+                return Ok(());
+            }
+        };
+
+        let arguments = split_arguments(words.next().unwrap_or(""));
+        if arguments.len() != definition.parameters.len() {
+            return Err(line.error(format!(
+                "macro `{}` takes {} argument(s) but was given {}; it is defined at {}",
+                name,
+                definition.parameters.len(),
+                arguments.len(),
+                definition.header.origin
+            )));
+        }
+        if calls.iter().any(|call| call == name) {
+            return Err(line.error(format!("macro `{}` expands itself", name)));
+        }
+        if calls.len() >= MAX_DEPTH {
+            return Err(line.error(format!("macros are nested more than {} deep", MAX_DEPTH)));
+        }
+        if let Some(label) = label {
+            output.push(SourceLine { text: format!("{}:", label), ..line.clone() });
+        }
+
+        self.expansions += 1;
+        let locals: Vec<&str> = definition
+            .body
+            .iter()
+            .filter_map(|body_line| split_label(&body_line.text).0)
+            .collect();
+        let suffix = format!("__{}_{}", name, self.expansions);
+
+        calls.push(name.to_string());
+        for body_line in &definition.body {
+            let mut text = body_line.text.clone();
+            for (parameter, argument) in definition.parameters.iter().zip(&arguments) {
+                text = replace_word(&text, &format!("\\{}", parameter), argument);
+            }
+            for local in &locals {
+                text = replace_word(&text, &format!("{}:", local), &format!("{}{}:", local, suffix));
+                text = replace_word(&text, &format!("@{}", local), &format!("@{}{}", local, suffix));
+                text = rename_in_expressions(&text, local, &format!("{}{}", local, suffix));
+            }
+            let expanded = SourceLine {
+                line: line.line,
+                text,
+                origin: body_line.origin.clone(),
+                expanded_from: Some(name.to_string()),
+            };
+            self.expand_line(expanded, calls, output)?;
+        }
+        calls.pop();
+        Ok(())
+    }
+}
+
+/// Replaces each occurrence of `word` in `text` that isn't followed by
+/// another label character, so `\a` doesn't match the start of `\ab`.
+fn replace_word(text: &str, word: &str, replacement: &str) -> String {
+    let mut result = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find(word) {
+        let end = start + word.len();
+        let whole = !rest[end..].starts_with(is_label_char)
+            && (start == 0 || !rest[..start].ends_with(is_label_char) || !word.starts_with(is_label_char));
+        result.push_str(&rest[..start]);
+        result.push_str(if whole { replacement } else { word });
+        rest = &rest[end..];
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Renames the uses of the name `from` in the `#` expression operands of
+/// `text`, leaving character literals alone.
+fn rename_in_expressions(text: &str, from: &str, to: &str) -> String {
+    let mut result = String::new();
+    let mut in_expression = false;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '#' || c.is_whitespace() {
+            in_expression = c == '#';
+            result.push(c);
+        } else if !in_expression {
+            result.push(c);
+        } else if c == '\'' {
+            // Copy the rest of the literal, including an escaped quote.
+            result.push(c);
+            let escaped = chars.peek() == Some(&'\\');
+            result.extend(chars.by_ref().take(if escaped { 3 } else { 2 }));
+        } else if is_label_char(c) {
+            let mut name = c.to_string();
+            while let Some(c) = chars.next_if(|&c| is_label_char(c)) {
+                name.push(c);
+            }
+            result.push_str(if name == from { to } else { &name });
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expand(source: &str) -> Result<Vec<SourceLine>, AssembleError> {
+        let lines = source.lines().enumerate().map(|(index, text)| SourceLine::new(index + 1, text));
+        super::expand(lines.collect())
+    }
+
+    fn texts(lines: &[SourceLine]) -> Vec<&str> {
+        lines.iter().map(|line| line.text.as_str()).collect()
+    }
+
+    #[test]
+    fn test_expand_substitutes_parameters() {
+        let source = ".macro copy from, to\nadd \\from $31 \\to\n.endm\n\ncopy $1, $2\nhlt\n";
+        let lines = expand(source).unwrap();
+        assert_eq!(texts(&lines), vec!["add $1 $31 $2", "hlt"]);
+        assert_eq!(lines[0].line, 5);
+        assert_eq!(lines[0].expanded_from, Some("copy".to_string()));
+        assert_eq!(lines[0].origin.line, 2);
+        assert_eq!(lines[1].expanded_from, None);
+    }
+
+    #[test]
+    fn test_expand_uniquifies_local_labels() {
+        let source = ".macro spin\nagain: jmpb $0\njmp @again\n.endm\nstart: spin\nspin\n";
+        let lines = expand(source).unwrap();
+        assert_eq!(
+            texts(&lines),
+            vec![
+                "start:",
+                "again__spin_1: jmpb $0",
+                "jmp @again__spin_1",
+                "again__spin_2: jmpb $0",
+                "jmp @again__spin_2",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_expand_renames_local_labels_in_expressions() {
+        let source = ".macro m\nl: load $0 #l+4\nload $1 #(ll-l)*'l'\n.endm\nm\nm\n";
+        let lines = expand(source).unwrap();
+        assert_eq!(
+            texts(&lines),
+            vec![
+                "l__m_1: load $0 #l__m_1+4",
+                "load $1 #(ll-l__m_1)*'l'",
+                "l__m_2: load $0 #l__m_2+4",
+                "load $1 #(ll-l__m_2)*'l'",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_expand_nested_macros() {
+        let source = ".macro one r\ninc \\r\n.endm\n.macro two r\none \\r\none \\r\n.endm\ntwo $4\n";
+        let lines = expand(source).unwrap();
+        assert_eq!(texts(&lines), vec!["inc $4", "inc $4"]);
+        assert_eq!(lines[0].expanded_from, Some("one".to_string()));
+        assert_eq!(lines[0].origin.line, 2);
+    }
+
+    #[test]
+    fn test_expand_errors() {
+        let error = expand(".macro m a\ninc \\b\n.endm\n").unwrap_err();
+        assert_eq!(error, AssembleError { line: 2, message: "macro `m` has no parameter `b`".to_string() });
+
+        let error = expand(".macro m a\ninc \\a\n.endm\nm\n").unwrap_err();
+        assert_eq!(
+            error.message,
+            "macro `m` takes 1 argument(s) but was given 0; it is defined at line 1"
+        );
+        assert_eq!(error.line, 4);
+
+        let error = expand(".macro m\nm\n.endm\nm\n").unwrap_err();
+        assert_eq!(error.message, "macro `m` expands itself (in macro `m` at line 2)");
+
+        assert_eq!(expand(".macro m\nhlt\n").unwrap_err().line, 1);
+        assert_eq!(expand(".endm\n").unwrap_err().line, 1);
+        assert!(expand(".macro add\n.endm\n").is_err());
+    }
+
+    #[test]
+    fn test_replace_word() {
+        assert_eq!(replace_word("add \\a \\ab \\a", "\\a", "$1"), "add $1 \\ab $1");
+        assert_eq!(replace_word("x: jmp @x", "x:", "y:"), "y: jmp @x");
+        assert_eq!(replace_word("ax: jmp", "x:", "y:"), "ax: jmp");
+    }
+}
@@ -1,14 +1,561 @@
+//! `assembler` turns iridescent assembly source into bytecode for the VM.
+use std::error::Error;
+use std::collections::HashSet;
+use std::fmt;
+use std::path::PathBuf;
+
+use nom::types::CompleteStr;
+
+use crate::debug_info::DebugInfo;
 use crate::instruction::Opcode;
+use crate::lint::{self, Warning};
+use crate::object::{Object, Relocation, Target};
+use self::expressions::Constants;
+use self::optimizer::Change;
+use self::source::SourceLine;
+use self::symbols::SymbolTable;
+/// Parsers for opcode mnemonics.
 pub mod opcode_parsers;
+/// Parsers for integer operands.
 pub mod operand_parsers;
+/// Parsers for register operands.
 pub mod register_parsers;
+/// Parsers for whole programs.
 pub mod program_parsers;
+/// Opcode helpers used by the assembler.
 pub mod opcode;
+/// Parsers for individual instructions.
 pub mod instruction_parsers;
+/// Parsers for label declarations and usages.
+pub mod label_parsers;
+/// The table of labels declared in a program.
+pub mod symbols;
+/// Expansion of `.macro` definitions.
+pub mod macros;
+/// Expansion of `.include` directives.
+pub mod includes;
+/// Constant expressions and `.equ` definitions.
+pub mod expressions;
+/// Source lines tracked back to the file and line they were written on.
+pub mod source;
+/// The source formatter behind `iridescent fmt`.
+pub mod formatter;
+
+/// The optional optimization pass run by `assemble_optimized`.
+pub mod optimizer;
+
+/// The pieces of an assembly instruction recognised by the parsers.
 #[derive(Debug, PartialEq, Clone)]
 pub enum Token {
-    Op{code: Opcode},
-    Register{reg_num: u8},
-    IntegerOperand{value: i32},
-    Number{value: i32}
+    /// An opcode mnemonic.
+    Op{
+        /// The opcode it names.
+        code: Opcode
+    },
+    /// A register operand such as `$0`.
+    Register{
+        /// The register's index.
+        reg_num: u8
+    },
+    /// An integer operand such as `#100`.
+    IntegerOperand{
+        /// The operand's value.
+        value: i32
+    },
+    /// A label declaration such as `loop:`.
+    LabelDeclaration{
+        /// The label's name.
+        name: String
+    },
+    /// A label usage such as `@loop`, standing for the label's offset.
+    LabelUsage{
+        /// The label's name.
+        name: String
+    },
+    /// An integer operand given as a constant expression, such as
+    /// `#BUF_SIZE*2+1`.
+    Expression{
+        /// The expression, without the leading `#`.
+        text: String
+    },
+}
+
+impl fmt::Display for Token {
+    /// Writes the token the way it is written in assembly.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Op { code } => write!(f, "{}", code.mnemonic()),
+            Token::Register { reg_num } => write!(f, "${}", reg_num),
+            Token::IntegerOperand { value } => write!(f, "#{}", value),
+            Token::LabelDeclaration { name } => write!(f, "{}:", name),
+            Token::LabelUsage { name } => write!(f, "@{}", name),
+            Token::Expression { text } => write!(f, "#{}", text),
+        }
+    }
+}
+
+/// An error produced while assembling source into bytecode.
+#[derive(Debug, PartialEq, Clone)]
+pub struct AssembleError {
+    /// The 1-based source line the error was found on.
+    pub line: usize,
+    /// A description of what went wrong.
+    pub message: String,
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for AssembleError {}
+
+/// Assembles `source` into bytecode ready to be loaded into a `VM`.
+pub fn assemble(source: &str) -> Result<Vec<u8>, AssembleError> {
+    assemble_with_debug_info(source, "").map(|(bytecode, _)| bytecode)
+}
+
+/// Assembles `source` into bytecode, along with debug info mapping the
+/// bytecode back to lines of `file` and to label names. Files named by
+/// `.include` are looked for next to `file`.
+pub fn assemble_with_debug_info(source: &str, file: &str) -> Result<(Vec<u8>, DebugInfo), AssembleError> {
+    assemble_with_includes(source, file, &[])
+}
+
+/// Like `assemble_with_debug_info`, but also looks for files named by
+/// `.include` in each directory of `include_path`. Lines from included files
+/// are attributed to the file and line they were written on in the debug
+/// info.
+pub fn assemble_with_includes(
+    source: &str,
+    file: &str,
+    include_path: &[PathBuf],
+) -> Result<(Vec<u8>, DebugInfo), AssembleError> {
+    let assembled = assemble_source(source, file, include_path, Options::default())?;
+    Ok((assembled.object.code, assembled.debug_info))
+}
+
+/// Like `assemble_with_includes`, but runs the `optimizer` over the program
+/// before it is assembled. Returns what the optimizer changed as well.
+pub fn assemble_optimized(
+    source: &str,
+    file: &str,
+    include_path: &[PathBuf],
+) -> Result<(Vec<u8>, DebugInfo, Vec<Change>), AssembleError> {
+    let options = Options { optimize: true, ..Options::default() };
+    let assembled = assemble_source(source, file, include_path, options)?;
+    Ok((assembled.object.code, assembled.debug_info, assembled.changes))
+}
+
+/// Assembles `source` into a relocatable object to be combined with others by
+/// the `Linker`. Labels declared with `.global` are exported, and labels
+/// declared with `.extern` may be used as `@name` to refer to labels exported
+/// by other objects.
+pub fn assemble_object(source: &str, file: &str, include_path: &[PathBuf]) -> Result<Object, AssembleError> {
+    let options = Options { relocatable: true, ..Options::default() };
+    assemble_source(source, file, include_path, options).map(|assembled| assembled.object)
+}
+
+/// Assembles `source` like `assemble_object` and checks the result with
+/// `lint::lint`, adding a warning for each label that is never used. A label
+/// declared `.global` counts as used. Returns the warnings in program order,
+/// along with debug info to describe where they are.
+pub fn lint_source(
+    source: &str,
+    file: &str,
+    include_path: &[PathBuf],
+) -> Result<(Vec<Warning>, DebugInfo), AssembleError> {
+    let options = Options { relocatable: true, ..Options::default() };
+    let assembled = assemble_source(source, file, include_path, options)?;
+    let mut warnings = lint::lint(&assembled.object.code, Some(&assembled.debug_info));
+    for (name, offset) in assembled.debug_info.labels() {
+        if !assembled.used.contains(name) {
+            warnings.push(Warning::UnusedLabel { offset: *offset, name: name.clone() });
+        }
+    }
+    warnings.sort_by_key(Warning::offset);
+    Ok((warnings, assembled.debug_info))
+}
+
+/// How `assemble_source` assembles.
+#[derive(Debug, Default, Clone, Copy)]
+struct Options {
+    /// Assemble an object that may use external labels.
+    relocatable: bool,
+    /// Run the `optimizer` first.
+    optimize: bool,
+}
+
+/// The output of `assemble_source`.
+struct Assembled {
+    object: Object,
+    debug_info: DebugInfo,
+    /// The names of the labels and constants used by instructions, constants
+    /// and `.global`.
+    used: HashSet<String>,
+    /// What the optimizer changed.
+    changes: Vec<Change>,
+}
+
+/// Returns the rest of `text` if it starts with `directive` followed by
+/// whitespace or nothing.
+fn directive<'a>(text: &'a str, directive: &str) -> Option<&'a str> {
+    match text.strip_prefix(directive) {
+        Some(rest) if rest.is_empty() || rest.starts_with(char::is_whitespace) => Some(rest.trim()),
+        _ => None,
+    }
+}
+
+/// Assembles `source` as `options` say.
+fn assemble_source(
+    source: &str,
+    file: &str,
+    include_path: &[PathBuf],
+    options: Options,
+) -> Result<Assembled, AssembleError> {
+    let source = includes::read(source, file, include_path)?;
+    let mut lines = vec![];
+    let mut constants = Constants::new();
+    let mut equs: Vec<(SourceLine, String)> = vec![];
+    let mut globals: Vec<(SourceLine, String)> = vec![];
+    let mut externs: Vec<(SourceLine, String)> = vec![];
+    let mut used = HashSet::new();
+    for source_line in macros::expand(source)? {
+        if let Some(rest) = directive(&source_line.text, ".equ") {
+            let mut words = rest.splitn(2, char::is_whitespace);
+            let name = words.next().unwrap_or("");
+            let expression = words.next().unwrap_or("").trim();
+            if name.is_empty() || !name.chars().all(label_parsers::is_label_char) || expression.is_empty() {
+                return Err(source_line.error("`.equ` needs a name and a value".to_string()));
+            }
+            if !constants.define(name, expression) {
+                return Err(source_line.error(format!("constant `{}` is defined more than once", name)));
+            }
+            used.extend(expressions::names(expression));
+            equs.push((source_line.clone(), name.to_string()));
+            continue;
+        }
+        let linkage = match (directive(&source_line.text, ".global"), directive(&source_line.text, ".extern")) {
+            (Some(rest), _) => Some((rest, &mut globals)),
+            (_, Some(rest)) => Some((rest, &mut externs)),
+            _ => None,
+        };
+        if let Some((rest, names)) = linkage {
+            let declared: Vec<&str> = rest
+                .split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|name| !name.is_empty())
+                .collect();
+            if declared.is_empty() || declared.iter().any(|name| !name.chars().all(label_parsers::is_label_char)) {
+                return Err(source_line.error(format!("`{}` needs a list of label names", source_line.text)));
+            }
+            for name in declared {
+                used.insert(name.to_string());
+                names.push((source_line.clone(), name.to_string()));
+            }
+            continue;
+        }
+        match program_parsers::source_line(CompleteStr(&source_line.text)) {
+            Ok((rest, (label, instruction)))
+                if rest.trim().is_empty() && (label.is_some() || instruction.is_some()) =>
+            {
+                if let Some(instruction) = &instruction {
+                    instruction
+                        .check_operands()
+                        .map_err(|message| source_line.error(message))?;
+                }
+                lines.push((source_line, label, instruction));
+            }
+            _ => return Err(source_line.error(format!("unable to parse `{}`", source_line.text))),
+        }
+    }
+
+    let changes = if options.optimize {
+        optimizer::optimize(&mut lines, &constants, &used)
+    } else {
+        vec![]
+    };
+
+    // First pass: find the offset of every label.
+    let mut symbols = SymbolTable::new();
+    let mut offset = 0;
+    for (source_line, label, instruction) in &lines {
+        if let Some(Token::LabelDeclaration { name }) = label {
+            if !symbols.add_symbol(name, offset) {
+                return Err(source_line.error(format!("label `{}` is declared more than once", name)));
+            }
+        }
+        if let Some(instruction) = instruction {
+            offset += instruction.byte_len();
+        }
+    }
+    for (source_line, name) in &equs {
+        if symbols.symbol_value(name).is_some() {
+            return Err(source_line.error(format!("`{}` is declared as both a constant and a label", name)));
+        }
+        constants.value(name, &symbols).map_err(|message| source_line.error(message))?;
+    }
+    let mut object = Object::default();
+    for (source_line, name) in &globals {
+        match symbols.symbol_value(name) {
+            Some(offset) => object.exports.push((name.clone(), offset)),
+            None => return Err(source_line.error(format!("`{}` is declared global but never defined", name))),
+        }
+    }
+    for (source_line, name) in &externs {
+        if symbols.symbol_value(name).is_some() || constants.contains(name) {
+            return Err(source_line.error(format!("`{}` is declared extern but is also defined here", name)));
+        }
+    }
+    let externs: HashSet<String> = externs.into_iter().map(|(_, name)| name).collect();
+
+    // Second pass: resolve label usages and expressions, and emit bytecode.
+    let mut debug_info = DebugInfo::new(file);
+    for (name, offset) in symbols.symbols() {
+        debug_info.add_label(&name, offset);
+    }
+    for (source_line, _, instruction) in lines {
+        if let Some(mut instruction) = instruction {
+            used.extend(instruction.names());
+            let relocations = instruction
+                .relocate_operands(&symbols, &constants, &externs)
+                .map_err(|message| source_line.error(message))?;
+            for relocation in relocations {
+                if let (Target::Symbol(name), false) = (&relocation.target, options.relocatable) {
+                    return Err(source_line.error(format!(
+                        "`{}` is external; assemble an object file and link it",
+                        name
+                    )));
+                }
+                object.relocations.push(Relocation { offset: object.code.len() + relocation.offset, ..relocation });
+            }
+            match &source_line.origin.file {
+                Some(included) => debug_info.add_line_in(object.code.len(), included, source_line.origin.line),
+                None => debug_info.add_line(object.code.len(), source_line.line),
+            }
+            let mut bytes = instruction.to_bytes().map_err(|message| source_line.error(message))?;
+            object.code.append(&mut bytes);
+        }
+    }
+    Ok(Assembled { object, debug_info, used, changes })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    use crate::linker::Linker;
+
+    #[test]
+    fn test_assemble() {
+        let bytecode = assemble("load $0 #500\nhlt\n").unwrap();
+        assert_eq!(bytecode, vec![0, 0, 1, 244, 5]);
+    }
+
+    #[test]
+    fn test_assemble_empty() {
+        assert_eq!(assemble("\n  \n"), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_assemble_reports_line() {
+        let error = assemble("load $0 #1\nfrob $0\n").unwrap_err();
+        assert_eq!(error.line, 2);
+        assert_eq!(error.message, "unable to parse `frob $0`");
+    }
+
+    #[test]
+    fn test_assemble_comments() {
+        let source = "; counts down\nload $0 #';' ; a semicolon\n.macro m ; no parameters\nhlt ; stop\n.endm\nm\n";
+        assert_eq!(assemble(source), Ok(vec![0, 0, 0, 59, 5]));
+    }
+
+    #[test]
+    fn test_assemble_moves() {
+        let bytecode = assemble("mov $0 $1\nswap $1 $2\nloadx $3 $4\n").unwrap();
+        assert_eq!(bytecode, vec![40, 0, 1, 41, 1, 2, 42, 3, 4]);
+    }
+
+    #[test]
+    fn test_assemble_immediates() {
+        let bytecode = assemble("addi $0 #5 $1\ninc $1\ndec $2\n").unwrap();
+        assert_eq!(bytecode, vec![45, 0, 0, 5, 1, 43, 1, 44, 2]);
+    }
+
+    #[test]
+    fn test_assemble_macros() {
+        let source = ".macro countdown r\nagain: dec \\r\nload $31 @again\njneq $31\n.endm\n\ncountdown $0\ncountdown $1\nhlt\n";
+        let (bytecode, debug_info) = assemble_with_debug_info(source, "prog.iasm").unwrap();
+        assert_eq!(
+            bytecode,
+            vec![44, 0, 0, 31, 0, 0, 16, 31, 44, 1, 0, 31, 0, 8, 16, 31, 5]
+        );
+        assert_eq!(debug_info.describe(8), "again__countdown_2 (prog.iasm:8)");
+
+        let error = assemble(".macro bad\nfrob $0\n.endm\nbad\n").unwrap_err();
+        assert_eq!(
+            error,
+            AssembleError { line: 4, message: "unable to parse `frob $0` (in macro `bad` at line 2)".to_string() }
+        );
+    }
+
+    #[test]
+    fn test_assemble_expressions() {
+        let source = ".equ BUF_SIZE 256\n.equ LEN end - start\nstart: load $0 #BUF_SIZE*2+1\nload $1 #'A'\nload $2 #LEN\nend:\n";
+        let bytecode = assemble(source).unwrap();
+        assert_eq!(bytecode, vec![0, 0, 2, 1, 0, 1, 0, 65, 0, 2, 0, 12]);
+
+        let error = assemble("load $0 #70000\n").unwrap_err();
+        assert_eq!(error.message, "value 70000 doesn't fit in a 16 bit operand");
+        let error = assemble("load $0 #0-1\n").unwrap_err();
+        assert_eq!(error.message, "value -1 doesn't fit in a 16 bit operand");
+        let error = assemble("load $0 #X\n").unwrap_err();
+        assert_eq!(error.message, "undefined name `X`");
+
+        // A macro's local labels are renamed in its expressions too.
+        let bytecode = assemble(".macro m\nl: load $0 #l+4\n.endm\nm\nm\n").unwrap();
+        assert_eq!(bytecode, vec![0, 0, 0, 4, 0, 0, 0, 8]);
+
+        let error = assemble("\n.equ X 1\n.equ X 2\n").unwrap_err();
+        assert_eq!(error, AssembleError { line: 3, message: "constant `X` is defined more than once".to_string() });
+        let error = assemble(".equ X 1/0\n").unwrap_err();
+        assert_eq!(error, AssembleError { line: 1, message: "division by zero in expression".to_string() });
+        assert!(assemble(".equ X 1\nX: hlt\n").is_err());
+    }
+
+    #[test]
+    fn test_assemble_and_link_objects() {
+        let main = ".extern print\nload $0 @print\nload $1 @done\njmp $0\ndone: hlt\n";
+        let library = ".global print\nhlt\nprint: inc $2\n";
+        let main = assemble_object(main, "main.iasm", &[]).unwrap();
+        let library = assemble_object(library, "lib.iasm", &[]).unwrap();
+        assert_eq!(main.exports, vec![]);
+        assert_eq!(library.exports, vec![("print".to_string(), 1)]);
+        assert_eq!(
+            main.relocations,
+            vec![
+                Relocation { offset: 2, target: Target::Symbol("print".to_string()) },
+                Relocation { offset: 6, target: Target::Local },
+            ]
+        );
+
+        let program = Linker::new().add("main", main).add("lib", library).link().unwrap();
+        assert_eq!(program, vec![0, 0, 0, 12, 0, 1, 0, 10, 6, 0, 5, 5, 43, 2]);
+    }
+
+    #[test]
+    fn test_assemble_linkage_errors() {
+        let error = assemble(".extern print\nload $0 @print\n").unwrap_err();
+        assert_eq!(error.message, "`print` is external; assemble an object file and link it");
+        let error = assemble_object(".global nowhere\n", "", &[]).unwrap_err();
+        assert_eq!(error.message, "`nowhere` is declared global but never defined");
+        let error = assemble_object(".extern a\na: hlt\n", "", &[]).unwrap_err();
+        assert_eq!(error.message, "`a` is declared extern but is also defined here");
+        assert!(assemble_object(".extern\n", "", &[]).is_err());
+    }
+
+    #[test]
+    fn test_lint_source() {
+        let source = ".global main\n.equ SIZE end-start\nmain: load $0 #SIZE\nstart: hlt\nspare: inc $0\nend:\n";
+        let (warnings, debug_info) = lint_source(source, "prog.iasm", &[]).unwrap();
+        assert_eq!(
+            warnings,
+            vec![Warning::Unreachable { offset: 5 }, Warning::UnusedLabel { offset: 5, name: "spare".to_string() }]
+        );
+        assert_eq!(debug_info.describe(warnings[1].offset()), "spare (prog.iasm:5)");
+    }
+
+    #[test]
+    fn test_assemble_optimized() {
+        let source = "load $0 #2\nload $1 #3\nmul $0 $1 $2\nload $3 @a\njmp $3\ninc $2\na: load $3 @end\njmp $3\nend: hlt\n";
+        let (program, debug_info, changes) = assemble_optimized(source, "prog.iasm", &[]).unwrap();
+        assert_eq!(
+            changes.iter().map(Change::to_string).collect::<Vec<_>>(),
+            vec![
+                "line 3: folded `mul $0 $1 $2` into `load $2 #6`",
+                "line 4: jump to `a` now goes straight to `end`",
+                "line 6: removed unreachable `inc $2`",
+                "line 7: removed unreachable `load $3 @end`",
+                "line 8: removed unreachable `jmp $3`",
+            ]
+        );
+        assert!(debug_info.labels().contains(&("end".to_string(), 18)));
+
+        let mut vm = crate::vm::VM::new();
+        vm.load_program(program);
+        vm.run().unwrap();
+        assert_eq!(vm.register(2), 6);
+
+        // Without the optimizer nothing changes.
+        let (unoptimized, _) = assemble_with_includes(source, "prog.iasm", &[]).unwrap();
+        assert_eq!(unoptimized.len(), 27);
+
+        // A jump to an integer offset leaves the program as it was.
+        let source = "load $0 #16\naddi $1 #0 $1\njmp $0\nload $2 #7\nhlt\nload $3 #9\nhlt";
+        let (program, _, changes) = assemble_optimized(source, "prog.iasm", &[]).unwrap();
+        assert_eq!(changes, vec![]);
+        let mut vm = crate::vm::VM::new();
+        vm.load_program(program);
+        vm.run().unwrap();
+        assert_eq!(vm.register(3), 9);
+    }
+
+    #[test]
+    fn test_assemble_register_out_of_range() {
+        let error = assemble("load $31 #1
+add $0 $32 $1
+").unwrap_err();
+        assert_eq!(
+            error,
+            AssembleError { line: 2, message: "register $32 is out of range; registers are $0 to $31".to_string() }
+        );
+    }
+
+    #[test]
+    fn test_debug_info_names_included_files() {
+        let dir = std::env::temp_dir().join(format!("iridescent-debug-info-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("lib.iasm"), "; helpers\ninc $0\n").unwrap();
+        let main = dir.join("main.iasm");
+        let (_, debug_info) =
+            assemble_with_includes("load $0 #1\n.include \"lib.iasm\"\nhlt\n", main.to_str().unwrap(), &[]).unwrap();
+        let (file, line) = debug_info.location(4).unwrap();
+        assert!(file.ends_with("lib.iasm"));
+        assert_eq!(line, 2);
+        assert_eq!(debug_info.location(6), Some((main.to_str().unwrap(), 3)));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_assemble_wrong_operands() {
+        let error = |source| assemble(source).unwrap_err().to_string();
+        assert_eq!(error("add $0\nhlt"), "line 1: `add` takes 3 registers");
+        assert_eq!(error("inc #4\nhlt"), "line 1: `inc` takes a register");
+        assert_eq!(error("load $0 $1"), "line 1: `load` takes a register and an integer");
+        assert_eq!(error("addi $0 $1 $2"), "line 1: `addi` takes a register, an integer and a register");
+        assert_eq!(error("hlt $0 $1 $2"), "line 1: `hlt` takes no operands");
+        assert_eq!(error("jmp #5"), "line 1: `jmp` takes a register");
+        assert_eq!(error("igl"), "line 1: unable to parse `igl`");
+        assert_eq!(assemble("eq $0 $1 $2\nload $0 @end\nend: hlt"), Ok(vec![9, 0, 1, 2, 0, 0, 0, 8, 5]));
+    }
+
+    #[test]
+    fn test_assemble_labels() {
+        let source = "load $0 #0\nload $1 @end\nloop: add $0 $0 $0\njmp $1\n\nend:\nhlt\n";
+        let (bytecode, debug_info) = assemble_with_debug_info(source, "prog.iasm").unwrap();
+        assert_eq!(bytecode, vec![0, 0, 0, 0, 0, 1, 0, 14, 1, 0, 0, 0, 6, 1, 5]);
+        assert_eq!(debug_info.label_offset("loop"), Some(8));
+        assert_eq!(debug_info.describe(12), "loop+4 (prog.iasm:4)");
+        assert_eq!(debug_info.describe(14), "end (prog.iasm:7)");
+    }
+
+    #[test]
+    fn test_assemble_label_errors() {
+        let error = assemble("load $0 @nowhere\n").unwrap_err();
+        assert_eq!(error, AssembleError { line: 1, message: "undefined label `nowhere`".to_string() });
+
+        let error = assemble("a: hlt\na: hlt\n").unwrap_err();
+        assert_eq!(error, AssembleError { line: 2, message: "label `a` is declared more than once".to_string() });
+    }
 }
@@ -1,16 +1,25 @@
 use crate::assembler::Token;
 use crate::instruction::Opcode;
-use nom::{named, tag, do_parse, types::CompleteStr};
+use nom::{named, tag, do_parse, ws, map_opt, alpha1, types::CompleteStr};
 
-/// opcode parser provides a simple piece of logic to load
-/// various opcodes, to be parsed by nom.
-named!(pub opcode_load<CompleteStr, Token>,
+named!(#[doc = "opcode parser provides a simple piece of logic to load various opcodes, to be parsed by nom."],
+    pub opcode_load<CompleteStr, Token>,
 
     do_parse!(
         tag!("load") >> (Token::Op{code: Opcode::LOAD})
     )
 );
 
+named!(#[doc = "Parses any opcode mnemonic, such as `load` or `HLT`."],
+    pub opcode<CompleteStr, Token>,
+    ws!(
+        map_opt!(alpha1, |mnemonic: CompleteStr| {
+            Opcode::from_mnemonic(&mnemonic).map(|code| Token::Op { code })
+        })
+    )
+);
+
+#[cfg(test)]
 mod tests {
     use super::*;
 
@@ -18,12 +27,24 @@ mod tests {
     fn test_opcode_load() {
         let result = opcode_load(CompleteStr("load"));
 
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
         let (rest, token) = result.unwrap();
         assert_eq!(token, Token::Op{code: Opcode::LOAD});
         assert_eq!(rest, CompleteStr(""));
 
         let result = opcode_load(CompleteStr("oald"));
-        assert_eq!(result.is_ok(), false);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_opcode() {
+        let (rest, token) = opcode(CompleteStr("jmpf $0")).unwrap();
+        assert_eq!(token, Token::Op{code: Opcode::JMPF});
+        assert_eq!(rest, CompleteStr("$0"));
+
+        let (_, token) = opcode(CompleteStr("HLT")).unwrap();
+        assert_eq!(token, Token::Op{code: Opcode::HLT});
+
+        assert!(opcode(CompleteStr("bogus")).is_err());
     }
-}
\ No newline at end of file
+}
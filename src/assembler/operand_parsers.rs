@@ -1,30 +1,82 @@
 use crate::assembler::Token;
-use nom::{named, ws, tag, digit, types::CompleteStr};
-named!(pub integer_operand<CompleteStr, Token>,
+use crate::assembler::label_parsers::label_usage;
+use crate::assembler::register_parsers::register;
+use nom::{named, ws, tag, alt, anychar, do_parse, recognize, many1, opt, take_while1, types::CompleteStr};
+
+/// Returns true for characters allowed in an expression outside of a
+/// character literal.
+fn is_expression_char(c: char) -> bool {
+    !c.is_whitespace() && c != '\''
+}
+
+named!(#[doc = "Parses character literals such as `'A'` or `'\\n'`."],
+    pub character_literal<CompleteStr, CompleteStr>,
+    recognize!(
+        do_parse!(
+            tag!("'") >>
+            opt!(tag!("\\")) >>
+            anychar >>
+            tag!("'") >>
+            ()
+        )
+    )
+);
+
+named!(#[doc = "Parses the text of an expression, which runs until whitespace outside of a character literal."],
+    pub expression_text<CompleteStr, CompleteStr>,
+    recognize!(
+        many1!(
+            alt!(character_literal | take_while1!(is_expression_char))
+        )
+    )
+);
+
+named!(#[doc = "Parses integer operands of the form `#100`, or constant expressions such as `#BUF_SIZE*2+1` and `#'A'`."],
+    pub integer_operand<CompleteStr, Token>,
     ws!(
         do_parse!(
             tag!("#") >>
-            reg_num: digit >>
+            text: expression_text >>
             (
-                Token::Number{value: reg_num.parse::<i32>().unwrap()}
+                match text.parse::<i32>() {
+                    Ok(value) => Token::IntegerOperand{value},
+                    Err(_) => Token::Expression{text: text.to_string()},
+                }
             )
         )
     )
 );
 
+named!(#[doc = "Parses a register, integer or label usage operand."],
+    pub operand<CompleteStr, Token>,
+    alt!(register | integer_operand | label_usage)
+);
+
+#[cfg(test)]
 mod tests {
     use super::*;
     #[test]
     fn test_parse_integer_operand() {
         // Test a valid integer operand
         let result = integer_operand(CompleteStr("#10"));
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
         let (rest, value) = result.unwrap();
         assert_eq!(rest, CompleteStr(""));
         assert_eq!(value, Token::IntegerOperand{value: 10});
 
         // Test an invalid one (missing the #)
         let result = integer_operand(CompleteStr("10"));
-        assert_eq!(result.is_ok(), false);
+        assert!(result.is_err());
+}
+
+    #[test]
+    fn test_parse_expression_operand() {
+        let (rest, value) = integer_operand(CompleteStr("#BUF_SIZE*2+1 $1")).unwrap();
+        assert_eq!(rest, CompleteStr("$1"));
+        assert_eq!(value, Token::Expression{text: "BUF_SIZE*2+1".to_string()});
+
+        let (rest, value) = integer_operand(CompleteStr("#' '+1")).unwrap();
+        assert_eq!(rest, CompleteStr(""));
+        assert_eq!(value, Token::Expression{text: "' '+1".to_string()});
+    }
 }
-}
\ No newline at end of file
@@ -0,0 +1,512 @@
+//! `optimizer` is an optional pass over parsed assembly, run by
+//! `assemble_optimized` before any offsets are worked out. It removes
+//! arithmetic that does nothing, folds arithmetic on constants into LOADs,
+//! removes LOADs that are overwritten before they are read, shortens chains of
+//! jumps and removes code that can't be reached.
+//!
+//! Labels stay on their lines when the instruction beside them is removed, so
+//! they go on naming the offset of the next instruction. The passes assume
+//! every jump lands on a label: programs with relative jumps, with
+//! expressions that do arithmetic on labels, or with a jump register set to
+//! anything but a label, are left as they are, since removing code would move
+//! what those point at.
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::assembler::expressions::Constants;
+use crate::assembler::instruction_parsers::AssemblerInstruction;
+use crate::assembler::source::SourceLine;
+use crate::assembler::symbols::SymbolTable;
+use crate::assembler::Token;
+use crate::instruction::Opcode;
+use crate::syscall::{ARGUMENT_REGISTERS, RESULT_REGISTER};
+use crate::vm::REGISTER_COUNT;
+
+/// How many times the passes are run at most. Each can leave the others
+/// something new to do, so they are repeated until nothing changes.
+const MAX_ROUNDS: usize = 16;
+
+/// A parsed line of source: an optional label and an optional instruction.
+pub type Line = (SourceLine, Option<Token>, Option<AssemblerInstruction>);
+
+/// Something the optimizer changed. Instructions are shown as they were
+/// written, after macros are expanded.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Change {
+    /// An instruction that leaves every register as it was was removed.
+    RemovedNoOp {
+        /// The source line of the instruction.
+        line: usize,
+        /// The instruction removed.
+        instruction: String,
+    },
+    /// Arithmetic on values known when assembling was replaced with a LOAD of
+    /// the result.
+    Folded {
+        /// The source line of the instruction.
+        line: usize,
+        /// The instruction replaced.
+        instruction: String,
+        /// The LOAD that replaced it.
+        replacement: String,
+    },
+    /// A LOAD whose register is overwritten before it is read was removed.
+    RemovedDeadLoad {
+        /// The source line of the LOAD.
+        line: usize,
+        /// The LOAD removed.
+        instruction: String,
+    },
+    /// A jump to a label that only jumps on to another label now goes
+    /// straight to the last label in the chain.
+    ShortenedJumpChain {
+        /// The source line of the LOAD of the jump target.
+        line: usize,
+        /// The label jumped to before.
+        from: String,
+        /// The label jumped to now.
+        to: String,
+    },
+    /// An instruction that can't be reached was removed.
+    RemovedUnreachable {
+        /// The source line of the instruction.
+        line: usize,
+        /// The instruction removed.
+        instruction: String,
+    },
+}
+
+impl Change {
+    /// Returns the source line the change was made on.
+    pub fn line(&self) -> usize {
+        match self {
+            Change::RemovedNoOp { line, .. }
+            | Change::Folded { line, .. }
+            | Change::RemovedDeadLoad { line, .. }
+            | Change::ShortenedJumpChain { line, .. }
+            | Change::RemovedUnreachable { line, .. } => *line,
+        }
+    }
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: ", self.line())?;
+        match self {
+            Change::RemovedNoOp { instruction, .. } => write!(f, "removed `{}`, which does nothing", instruction),
+            Change::Folded { instruction, replacement, .. } => {
+                write!(f, "folded `{}` into `{}`", instruction, replacement)
+            }
+            Change::RemovedDeadLoad { instruction, .. } => {
+                write!(f, "removed `{}`, which is overwritten before it is read", instruction)
+            }
+            Change::ShortenedJumpChain { from, to, .. } => {
+                write!(f, "jump to `{}` now goes straight to `{}`", from, to)
+            }
+            Change::RemovedUnreachable { instruction, .. } => write!(f, "removed unreachable `{}`", instruction),
+        }
+    }
+}
+
+/// Optimizes `lines` in place and returns what was changed, in the order the
+/// changes were made. `referenced` holds the names used outside instructions,
+/// by `.equ` and `.global`; labels named there are kept reachable.
+pub fn optimize(lines: &mut [Line], constants: &Constants, referenced: &HashSet<String>) -> Vec<Change> {
+    if !can_optimize(lines, constants) {
+        return vec![];
+    }
+    let mut changes = vec![];
+    for _ in 0..MAX_ROUNDS {
+        let before = changes.len();
+        simplify(lines, constants, &mut changes);
+        shorten_jump_chains(lines, &mut changes);
+        remove_unreachable(lines, referenced, &mut changes);
+        if changes.len() == before {
+            break;
+        }
+    }
+    changes
+}
+
+/// Returns true if every jump in `lines` lands on a label, so code can be
+/// removed without moving a jump target: there are no relative jumps, no
+/// expressions involving labels, and every register a jump reads its target
+/// from is only ever set by a LOAD of a label.
+fn can_optimize(lines: &[Line], constants: &Constants) -> bool {
+    let instructions: Vec<&AssemblerInstruction> =
+        lines.iter().filter_map(|(_, _, instruction)| instruction.as_ref()).collect();
+    let mut targets = HashSet::new();
+    for instruction in &instructions {
+        let opcode = instruction.opcode();
+        let target = match opcode {
+            Opcode::JMPF | Opcode::JMPB | Opcode::JEQF | Opcode::JEQB | Opcode::JNEQF | Opcode::JNEQB => return false,
+            Opcode::JMP | Opcode::JEQ | Opcode::JNEQ => 0,
+            _ if opcode.is_jump() => 2,
+            _ => continue,
+        };
+        targets.extend(registers(instruction, &[target]));
+    }
+    instructions.iter().all(|instruction| {
+        let opcode = instruction.opcode();
+        let operands = instruction.operands();
+        let labels_known = operands.iter().all(|operand| match operand {
+            Token::Expression { text } => constants.evaluate(text, &SymbolTable::new()).is_ok(),
+            _ => true,
+        });
+        let mut writes = registers(instruction, opcode.register_writes());
+        if opcode == Opcode::SYSCALL {
+            writes.push(RESULT_REGISTER);
+        }
+        let loads_label = matches!((opcode, &operands[..]), (Opcode::LOAD, [_, Token::LabelUsage { .. }]));
+        labels_known && (loads_label || writes.iter().all(|register| !targets.contains(register)))
+    })
+}
+
+/// Returns the registers named by the register operands of `instruction` at
+/// the positions in `operands`, counting from 0.
+fn registers(instruction: &AssemblerInstruction, operands: &[usize]) -> Vec<usize> {
+    let all: Vec<usize> = instruction
+        .operands()
+        .into_iter()
+        .filter_map(|operand| match operand {
+            Token::Register { reg_num } => Some(*reg_num as usize),
+            _ => None,
+        })
+        .collect();
+    operands.iter().filter_map(|&n| all.get(n).cloned()).collect()
+}
+
+/// Returns the value of an integer operand, if it is known without knowing
+/// where labels are and fits in an operand.
+fn integer(operand: &Token, constants: &Constants) -> Option<i64> {
+    let value = match operand {
+        Token::IntegerOperand { value } => *value as i64,
+        Token::Expression { text } => constants.evaluate(text, &SymbolTable::new()).ok()?,
+        _ => return None,
+    };
+    (0..=u16::MAX as i64).contains(&value).then_some(value)
+}
+
+/// Returns true for instructions that leave every register as it was:
+/// adding or subtracting 0, multiplying by 1, and moving or swapping a
+/// register with itself.
+fn is_no_op(instruction: &AssemblerInstruction, constants: &Constants) -> bool {
+    let operands = instruction.operands();
+    let same_register = |first: usize, second: usize| match (operands.get(first), operands.get(second)) {
+        (Some(Token::Register { reg_num: a }), Some(Token::Register { reg_num: b })) => a == b,
+        _ => false,
+    };
+    let immediate = || operands.get(1).and_then(|operand| integer(operand, constants));
+    match instruction.opcode() {
+        Opcode::ADDI | Opcode::SUBI => same_register(0, 2) && immediate() == Some(0),
+        Opcode::MULI => same_register(0, 2) && immediate() == Some(1),
+        Opcode::MOV | Opcode::SWAP => same_register(0, 1),
+        _ => false,
+    }
+}
+
+/// Returns a LOAD of the result if `instruction` is ADD, SUB or MUL, or their
+/// immediate forms, on values in `known`, and the result fits in an operand.
+fn fold(
+    instruction: &AssemblerInstruction,
+    known: &[Option<i64>; REGISTER_COUNT],
+    constants: &Constants,
+) -> Option<AssemblerInstruction> {
+    let opcode = instruction.opcode();
+    let value_of = |register: usize| known[register];
+    let (a, b, destination) = match (opcode, &registers(instruction, &[0, 1, 2])[..]) {
+        (Opcode::ADD | Opcode::SUB | Opcode::MUL, &[a, b, destination]) => (value_of(a)?, value_of(b)?, destination),
+        (Opcode::ADDI | Opcode::SUBI | Opcode::MULI, &[a, destination]) => {
+            (value_of(a)?, integer(instruction.operands()[1], constants)?, destination)
+        }
+        _ => return None,
+    };
+    let value = match opcode {
+        Opcode::ADD | Opcode::ADDI => a + b,
+        Opcode::SUB | Opcode::SUBI => a - b,
+        _ => a * b,
+    };
+    if !(0..=u16::MAX as i64).contains(&value) {
+        return None;
+    }
+    Some(AssemblerInstruction::new(
+        Opcode::LOAD,
+        vec![Token::Register { reg_num: destination as u8 }, Token::IntegerOperand { value: value as i32 }],
+    ))
+}
+
+/// Removes no-ops, folds arithmetic on constants and removes dead LOADs.
+/// Register values are only followed through straight-line code: what is
+/// known is forgotten at every label and after every jump.
+fn simplify(lines: &mut [Line], constants: &Constants, changes: &mut Vec<Change>) {
+    let mut known = [None; REGISTER_COUNT];
+    // The line of the latest LOAD into each register not yet read.
+    let mut pending: HashMap<usize, usize> = HashMap::new();
+    for index in 0..lines.len() {
+        if lines[index].1.is_some() {
+            known = [None; REGISTER_COUNT];
+            pending.clear();
+        }
+        let line = lines[index].0.line;
+        let instruction = match &lines[index].2 {
+            Some(instruction) => instruction.clone(),
+            None => continue,
+        };
+        if is_no_op(&instruction, constants) {
+            changes.push(Change::RemovedNoOp { line, instruction: instruction.to_string() });
+            lines[index].2 = None;
+            continue;
+        }
+        let instruction = match fold(&instruction, &known, constants) {
+            Some(folded) => {
+                changes.push(Change::Folded {
+                    line,
+                    instruction: instruction.to_string(),
+                    replacement: folded.to_string(),
+                });
+                lines[index].2 = Some(folded.clone());
+                folded
+            }
+            None => instruction,
+        };
+
+        let opcode = instruction.opcode();
+        let mut reads = registers(&instruction, opcode.register_reads());
+        let mut writes = registers(&instruction, opcode.register_writes());
+        match opcode {
+            Opcode::SYSCALL => {
+                reads.extend(ARGUMENT_REGISTERS);
+                writes.push(RESULT_REGISTER);
+            }
+            Opcode::LOADX => reads.extend(0..REGISTER_COUNT),
+            _ => {}
+        }
+        for register in reads {
+            pending.remove(&register);
+        }
+        for register in writes {
+            if let Some(load) = pending.remove(&register) {
+                if let Some(dead) = lines[load].2.take() {
+                    changes.push(Change::RemovedDeadLoad { line: lines[load].0.line, instruction: dead.to_string() });
+                }
+            }
+            known[register] = None;
+        }
+        if let (Opcode::LOAD, &[register]) = (opcode, &registers(&instruction, &[0])[..]) {
+            known[register] = integer(instruction.operands()[1], constants);
+            pending.insert(register, index);
+        }
+        if opcode.is_jump() || opcode == Opcode::HLT {
+            known = [None; REGISTER_COUNT];
+            pending.clear();
+        }
+    }
+}
+
+/// Returns the index of the first line at or after `index` with an
+/// instruction.
+fn next_instruction(lines: &[Line], index: usize) -> Option<usize> {
+    (index..lines.len()).find(|&i| lines[i].2.is_some())
+}
+
+/// If line `index` is `load $r @label` and the next instruction is `jmp $r`,
+/// returns the register and the label.
+fn jump_through(lines: &[Line], index: usize) -> Option<(u8, String)> {
+    let load = lines[index].2.as_ref()?;
+    let (register, label) = match (load.opcode(), &load.operands()[..]) {
+        (Opcode::LOAD, &[Token::Register { reg_num }, Token::LabelUsage { name }]) => (*reg_num, name.clone()),
+        _ => return None,
+    };
+    let jump = lines[next_instruction(lines, index + 1)?].2.as_ref()?;
+    match (jump.opcode(), &jump.operands()[..]) {
+        (Opcode::JMP, &[Token::Register { reg_num }]) if *reg_num == register => Some((register, label)),
+        _ => None,
+    }
+}
+
+/// Where `load $r @a; jmp $r` jumps to a label whose code is
+/// `load $r @b; jmp $r`, loads `@b` instead, following the chain as far as
+/// it goes.
+fn shorten_jump_chains(lines: &mut [Line], changes: &mut Vec<Change>) {
+    let labels: HashMap<String, usize> = lines
+        .iter()
+        .enumerate()
+        .filter_map(|(index, (_, label, _))| match label {
+            Some(Token::LabelDeclaration { name }) => Some((name.clone(), index)),
+            _ => None,
+        })
+        .collect();
+    for index in 0..lines.len() {
+        let (register, target) = match jump_through(lines, index) {
+            Some(jump) => jump,
+            None => continue,
+        };
+        let mut destination = target.clone();
+        let mut visited = HashSet::new();
+        visited.insert(target.clone());
+        while let Some(next) = labels
+            .get(&destination)
+            .and_then(|&start| next_instruction(lines, start))
+            .and_then(|start| jump_through(lines, start))
+            .filter(|(next_register, _)| *next_register == register)
+            .map(|(_, next)| next)
+        {
+            if !visited.insert(next.clone()) {
+                break;
+            }
+            destination = next;
+        }
+        if destination != target {
+            lines[index].2 = Some(AssemblerInstruction::new(
+                Opcode::LOAD,
+                vec![Token::Register { reg_num: register }, Token::LabelUsage { name: destination.clone() }],
+            ));
+            changes.push(Change::ShortenedJumpChain { line: lines[index].0.line, from: target, to: destination });
+        }
+    }
+}
+
+/// Removes the instructions after a JMP or HLT, up to the next label that
+/// something refers to.
+fn remove_unreachable(lines: &mut [Line], referenced: &HashSet<String>, changes: &mut Vec<Change>) {
+    let mut used = referenced.clone();
+    used.extend(lines.iter().filter_map(|(_, _, instruction)| instruction.as_ref()).flat_map(|i| i.names()));
+    let mut reachable = true;
+    for (source_line, label, instruction) in lines.iter_mut() {
+        if let Some(Token::LabelDeclaration { name }) = label {
+            reachable |= used.contains(name);
+        }
+        match instruction {
+            Some(removed) if !reachable => {
+                changes.push(Change::RemovedUnreachable { line: source_line.line, instruction: removed.to_string() });
+                *instruction = None;
+            }
+            Some(kept) => reachable = !matches!(kept.opcode(), Opcode::JMP | Opcode::HLT),
+            None => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::program_parsers;
+    use nom::types::CompleteStr;
+
+    /// Parses `source`, optimizes it and returns the changes along with the
+    /// lines that are left, without blank ones.
+    fn optimize_source(source: &str, constants: &Constants) -> (Vec<Change>, Vec<String>) {
+        let mut lines: Vec<Line> = source
+            .lines()
+            .enumerate()
+            .map(|(index, text)| {
+                let (_, (label, instruction)) = program_parsers::source_line(CompleteStr(text)).unwrap();
+                (SourceLine::new(index + 1, text), label, instruction)
+            })
+            .collect();
+        let changes = optimize(&mut lines, constants, &HashSet::new());
+        let left = lines
+            .iter()
+            .filter(|(_, label, instruction)| label.is_some() || instruction.is_some())
+            .map(|(_, label, instruction)| {
+                let label = label.as_ref().map(|label| format!("{} ", label)).unwrap_or_default();
+                let instruction = instruction.as_ref().map(|i| i.to_string()).unwrap_or_default();
+                format!("{}{}", label, instruction).trim().to_string()
+            })
+            .collect();
+        (changes, left)
+    }
+
+    #[test]
+    fn test_remove_no_ops() {
+        let source = "load $0 @end\naddi $1 #0 $1\nmuli $1 #1 $1\nmov $2 $2\nsubi $1 #0 $2\njmp $0\nend: hlt";
+        let (changes, left) = optimize_source(source, &Constants::new());
+        assert_eq!(left, vec!["load $0 @end", "subi $1 #0 $2", "jmp $0", "end: hlt"]);
+        assert_eq!(changes.len(), 3);
+        assert_eq!(changes[0].to_string(), "line 2: removed `addi $1 #0 $1`, which does nothing");
+    }
+
+    #[test]
+    fn test_fold_constants() {
+        let mut constants = Constants::new();
+        constants.define("TEN", "5*2");
+        let source = "load $0 #TEN\nload $1 #3\nadd $0 $1 $1\nmuli $1 #4 $0\nsyscall $0\nhlt";
+        let (changes, left) = optimize_source(source, &constants);
+        assert_eq!(left, vec!["load $1 #13", "load $0 #52", "syscall $0", "hlt"]);
+        assert_eq!(
+            changes[0],
+            Change::Folded {
+                line: 3,
+                instruction: "add $0 $1 $1".to_string(),
+                replacement: "load $1 #13".to_string()
+            }
+        );
+        assert_eq!(changes[0].to_string(), "line 3: folded `add $0 $1 $1` into `load $1 #13`");
+        assert_eq!(changes[1], Change::RemovedDeadLoad { line: 2, instruction: "load $1 #3".to_string() });
+        assert_eq!(
+            changes[1].to_string(),
+            "line 2: removed `load $1 #3`, which is overwritten before it is read"
+        );
+
+        // Results that don't fit an operand, and values from before a
+        // label, aren't folded.
+        let source = "load $0 #0\nload $1 #1\nsub $0 $1 $2\nloop: addi $1 #1 $1\nsyscall $2\nhlt";
+        let (changes, left) = optimize_source(source, &Constants::new());
+        assert_eq!(changes, vec![]);
+        assert_eq!(left.len(), 6);
+    }
+
+    #[test]
+    fn test_keep_loads_that_are_read() {
+        let source = "load $1 #1\nloadx $0 $2\nload $1 #2\nload $3 #4\njeq $3\nload $3 #5\nhlt";
+        let (changes, _) = optimize_source(source, &Constants::new());
+        assert_eq!(changes, vec![]);
+    }
+
+    #[test]
+    fn test_shorten_jump_chains() {
+        let source = "load $0 @a\njmp $0\na: load $0 @b\njmp $0\nb: load $0 @c\njmp $0\nc: hlt";
+        let (changes, left) = optimize_source(source, &Constants::new());
+        assert_eq!(changes[0], Change::ShortenedJumpChain { line: 1, from: "a".to_string(), to: "c".to_string() });
+        assert_eq!(changes[0].to_string(), "line 1: jump to `a` now goes straight to `c`");
+        // Once nothing jumps to `a` or `b`, their code can't be reached.
+        assert_eq!(left, vec!["load $0 @c", "jmp $0", "a:", "b:", "c: hlt"]);
+
+        // A chain through a different register, or round a loop, stops.
+        let source = "load $0 @a\njmp $0\na: load $1 @a\njmp $1";
+        assert_eq!(optimize_source(source, &Constants::new()).0, vec![]);
+        let source = "load $0 @a\njmp $0\na: load $0 @b\njmp $0\nb: load $0 @a\njmp $0";
+        let (changes, _) = optimize_source(source, &Constants::new());
+        assert_eq!(changes[0], Change::ShortenedJumpChain { line: 1, from: "a".to_string(), to: "b".to_string() });
+    }
+
+    #[test]
+    fn test_remove_unreachable() {
+        let source = "load $0 @end\njmp $0\ninc $1\nunused: inc $1\nend: hlt\ninc $1";
+        let (changes, left) = optimize_source(source, &Constants::new());
+        assert_eq!(left, vec!["load $0 @end", "jmp $0", "unused:", "end: hlt"]);
+        assert_eq!(
+            changes.iter().map(Change::line).collect::<Vec<_>>(),
+            vec![3, 4, 6]
+        );
+        assert_eq!(changes[0].to_string(), "line 3: removed unreachable `inc $1`");
+    }
+
+    #[test]
+    fn test_leave_relative_jumps_alone() {
+        let source = "load $0 #4\njmpf $0\naddi $1 #0 $1\nhlt";
+        assert_eq!(optimize_source(source, &Constants::new()).0, vec![]);
+        let source = "load $0 #end+1\njmp $0\nhlt\nend: hlt";
+        assert_eq!(optimize_source(source, &Constants::new()).0, vec![]);
+    }
+
+    #[test]
+    fn test_leave_jumps_to_integers_alone() {
+        let source = "load $0 #16\naddi $1 #0 $1\njmp $0\nload $2 #7\nhlt\nload $3 #9\nhlt";
+        assert_eq!(optimize_source(source, &Constants::new()).0, vec![]);
+        let source = "load $0 @end\naddi $0 #0 $0\nload $1 #0\nbeq $1 $1 $0\nhlt\nend: hlt";
+        assert_eq!(optimize_source(source, &Constants::new()).0, vec![]);
+        let source = "load $4 @end\nmov $4 $0\naddi $1 #0 $1\njeq $0\nend: hlt";
+        assert_eq!(optimize_source(source, &Constants::new()).0, vec![]);
+    }
+}
@@ -1,58 +1,33 @@
 use nom::types::CompleteStr;
 use nom::*;
-use crate::assembler::instruction_parsers::{AssemblerInstruction, instruction_one};
+use crate::assembler::Token;
+use crate::assembler::instruction_parsers::{AssemblerInstruction, instruction};
+use crate::assembler::label_parsers::label_declaration;
 
-#[derive(Debug, PartialEq, Clone)]
-
-pub struct Program {
-    /// Represents the program that will be fed into the VM, as a vector
-    /// of Assembler instructions.`
-    instructions: Vec<AssemblerInstruction>
-}
-
-named!(pub program<CompleteStr, Program>,
+named!(#[doc = "Parses a single source line: an optional label declaration followed by an optional instruction."],
+    pub source_line<CompleteStr, (Option<Token>, Option<AssemblerInstruction>)>,
     do_parse!(
-        instructions: many1!(instruction_one) >>
+        label: opt!(label_declaration) >>
+        instruction: opt!(instruction) >>
         (
-            Program {
-                instructions: instructions
-            }
+            (label, instruction)
         )
     )
 );
 
-impl Program {
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let mut program = vec![];
-        for instructions in &self.instructions {
-            program.append(&mut instructions.to_bytes());
-        }
-
-        program
-    }
-}
-#[cfg(tests)]
+#[cfg(test)]
 mod tests {
-    
-    #[test]
-    fn test_parse_program() {
-        let result = program(CompleteStr("load $0 #100\n"));
-        assert_eq!(result.is_ok(), true);
-        let (leftover, p) = result.unwrap();
-        assert_eq!(leftover, CompleteStr(""));
-        assert_eq!(
-            1,
-            p.instructions.len()
-        );
-        // TODO: Figure out an ergonomic way to test the AssemblerInstruction returned
-    }
+    use super::*;
+
     #[test]
-    fn test_program_to_bytes() {
-        let result = program(CompleteStr("load $0 #100\n"));
-        assert_eq!(result.is_ok(), true);
-        let (_, program) = result.unwrap();
-        let bytecode = program.to_bytes();
-        assert_eq!(bytecode.len(), 4);
-        println!("{:?}", bytecode);
+    fn test_parse_source_line() {
+        let (rest, (label, parsed)) = source_line(CompleteStr("loop: jmp $3")).unwrap();
+        assert_eq!(rest, CompleteStr(""));
+        assert_eq!(label, Some(Token::LabelDeclaration{name: "loop".to_string()}));
+        assert!(parsed.is_some());
+
+        let (_, (label, parsed)) = source_line(CompleteStr("end:")).unwrap();
+        assert!(label.is_some());
+        assert!(parsed.is_none());
     }
-}
\ No newline at end of file
+}
@@ -1,32 +1,32 @@
-#[macro_use]
 use crate::assembler::Token;
-use nom::{named, ws, tag, digit, types::CompleteStr};
+use nom::{named, ws, tag, digit, map_res, types::CompleteStr};
 
-named!(pub register <CompleteStr, Token>,
+named!(#[doc = "Parses register operands of the form `$0`. Indexes too big for a byte don't parse; the assembler reports indexes past the last register."],
+    pub register<CompleteStr, Token>,
     ws!(
         do_parse!(
             tag!("$") >>
-            reg_num: digit >>
+            reg_num: map_res!(digit, |digits: CompleteStr| digits.parse::<u8>()) >>
             (
-                Token::Register {
-                    reg_num: reg_num.parse::<u8>().unwrap()
-                }
+                Token::Register { reg_num }
             )
-            
         )
     )
 );
 
+#[cfg(test)]
 mod tests {
     use super::register;
     use nom::types::CompleteStr;
     #[test]
     fn test_parse_register() {
         let result = register(CompleteStr("$0"));
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
         let result = register(CompleteStr("0"));
-        assert_eq!(result.is_ok(), false);
+        assert!(result.is_err());
         let result = register(CompleteStr("$a"));
-        assert_eq!(result.is_ok(), false);
+        assert!(result.is_err());
+        let result = register(CompleteStr("$256"));
+        assert!(result.is_err());
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,122 @@
+//! `source` tracks each line of assembly back to where it was written, so
+//! errors in included files and macro bodies can point at them.
+//!
+//! A `;` starts a comment that runs to the end of the line, unless it is in a
+//! character literal such as `';'` or a quoted file name.
+use std::fmt;
+
+use crate::assembler::label_parsers::is_label_char;
+use crate::assembler::AssembleError;
+
+/// A line in a source file.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Location {
+    /// The included file the line is in, or `None` for the source being
+    /// assembled.
+    pub file: Option<String>,
+    /// The 1-based line number.
+    pub line: usize,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{} line {}", file, self.line),
+            None => write!(f, "line {}", self.line),
+        }
+    }
+}
+
+/// A line of source after includes and macros have been expanded.
+#[derive(Debug, PartialEq, Clone)]
+pub struct SourceLine {
+    /// The 1-based line in the source being assembled. For lines from an
+    /// included file or a macro, this is the line of the `.include` or the
+    /// macro call.
+    pub line: usize,
+    /// The text to assemble.
+    pub text: String,
+    /// Where the text was written.
+    pub origin: Location,
+    /// For lines produced by a macro, the macro's name.
+    pub expanded_from: Option<String>,
+}
+
+impl SourceLine {
+    /// Returns a line of the source being assembled.
+    #[cfg(test)]
+    pub fn new(line: usize, text: &str) -> SourceLine {
+        SourceLine {
+            line,
+            text: text.to_string(),
+            origin: Location { file: None, line },
+            expanded_from: None,
+        }
+    }
+
+    /// Returns an error at this line, naming where the text was written as
+    /// well if it came from an included file or a macro.
+    pub fn error(&self, message: String) -> AssembleError {
+        let message = match (&self.expanded_from, &self.origin.file) {
+            (Some(name), _) => format!("{} (in macro `{}` at {})", message, name, self.origin),
+            (None, Some(_)) => format!("{} (at {})", message, self.origin),
+            (None, None) => message,
+        };
+        AssembleError { line: self.line, message }
+    }
+}
+
+/// Splits `text` into the code before its comment and the comment's text
+/// after the `;`, if it has one.
+pub fn split_comment(text: &str) -> (&str, Option<&str>) {
+    let mut quote = None;
+    let mut escaped = false;
+    for (index, c) in text.char_indices() {
+        match quote {
+            _ if escaped => escaped = false,
+            Some(_) if c == '\\' => escaped = true,
+            Some(open) if c == open => quote = None,
+            Some(_) => {}
+            None if c == '\'' || c == '"' => quote = Some(c),
+            None if c == ';' => return (&text[..index], Some(&text[index + 1..])),
+            None => {}
+        }
+    }
+    (text, None)
+}
+
+/// Splits a leading `name:` label declaration off `text`, if there is one.
+pub fn split_label(text: &str) -> (Option<&str>, &str) {
+    match text.find(':') {
+        Some(colon) if colon > 0 && text[..colon].chars().all(is_label_char) => {
+            (Some(&text[..colon]), text[colon + 1..].trim())
+        }
+        _ => (None, text),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_comment() {
+        assert_eq!(split_comment("hlt ; done"), ("hlt ", Some(" done")));
+        assert_eq!(split_comment(";;"), ("", Some(";")));
+        assert_eq!(split_comment("load $0 #';'"), ("load $0 #';'", None));
+        assert_eq!(split_comment("load $0 #'\\'' ; quote"), ("load $0 #'\\'' ", Some(" quote")));
+        assert_eq!(split_comment(".include \"a;b.iasm\""), (".include \"a;b.iasm\"", None));
+    }
+
+    #[test]
+    fn test_error_names_origin() {
+        let mut line = SourceLine::new(4, "frob");
+        assert_eq!(line.error("bad".to_string()), AssembleError { line: 4, message: "bad".to_string() });
+
+        line.origin = Location { file: Some("lib.iasm".to_string()), line: 2 };
+        assert_eq!(line.error("bad".to_string()).message, "bad (at lib.iasm line 2)");
+
+        line.expanded_from = Some("m".to_string());
+        assert_eq!(line.error("bad".to_string()).message, "bad (in macro `m` at lib.iasm line 2)");
+    }
+}
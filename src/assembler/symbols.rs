@@ -0,0 +1,69 @@
+use std::collections::HashMap;
+
+/// Maps label names to the byte offsets they were declared at.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct SymbolTable {
+    symbols: HashMap<String, usize>,
+}
+
+impl SymbolTable {
+    /// Returns an empty table.
+    pub fn new() -> SymbolTable {
+        SymbolTable {
+            symbols: HashMap::new(),
+        }
+    }
+
+    /// Adds a label at `offset`. Returns false, leaving the table unchanged,
+    /// if the label was already declared.
+    pub fn add_symbol(&mut self, name: &str, offset: usize) -> bool {
+        if self.symbols.contains_key(name) {
+            return false;
+        }
+        self.symbols.insert(name.to_string(), offset);
+        true
+    }
+
+    /// Returns the offset a label was declared at.
+    pub fn symbol_value(&self, name: &str) -> Option<usize> {
+        self.symbols.get(name).cloned()
+    }
+
+    /// Returns a copy of the table with every offset moved on by `distance`.
+    pub fn shifted(&self, distance: usize) -> SymbolTable {
+        SymbolTable {
+            symbols: self
+                .symbols
+                .iter()
+                .map(|(name, &offset)| (name.clone(), offset + distance))
+                .collect(),
+        }
+    }
+
+    /// Returns every label with its offset, ordered by offset then name.
+    pub fn symbols(&self) -> Vec<(String, usize)> {
+        let mut symbols: Vec<(String, usize)> = self
+            .symbols
+            .iter()
+            .map(|(name, &offset)| (name.clone(), offset))
+            .collect();
+        symbols.sort_by(|a, b| a.1.cmp(&b.1).then(a.0.cmp(&b.0)));
+        symbols
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_symbol_table() {
+        let mut table = SymbolTable::new();
+        assert!(table.add_symbol("start", 0));
+        assert!(table.add_symbol("loop", 12));
+        assert!(!table.add_symbol("loop", 20));
+        assert_eq!(table.symbol_value("loop"), Some(12));
+        assert_eq!(table.symbol_value("end"), None);
+        assert_eq!(table.symbols(), vec![("start".to_string(), 0), ("loop".to_string(), 12)]);
+    }
+}
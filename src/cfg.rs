@@ -0,0 +1,344 @@
+//! `cfg` splits bytecode into basic blocks and finds where control can flow
+//! between them, for tools that reason about whole programs.
+//!
+//! A block starts at the start of the program, at every label in the debug
+//! info, at every constant jump target, and after every jump or HLT. Jump
+//! targets are constant when the verifier can follow them, as in
+//! `load $3 @loop` followed by `jmp $3`. A jump whose target isn't constant is
+//! assumed to be able to reach any label, or any block at all when there is
+//! no debug info.
+//!
+//! The graph can be written as Graphviz DOT, for `dot -Tsvg`, or as JSON. In
+//! both, indirect jumps are drawn as edges to every block they may reach.
+use std::collections::HashSet;
+
+use crate::debug_info::DebugInfo;
+use crate::instruction::Opcode;
+use crate::json::Json;
+use crate::verifier::{decode, is_control_flow, Decoded, KnownValues};
+
+/// A run of instructions that is only ever entered at its first instruction.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Block {
+    /// The offset of the first instruction.
+    pub start: usize,
+    /// The instructions, in order. Never empty.
+    pub instructions: Vec<Decoded>,
+    /// The starts of the blocks control may flow to next, not counting
+    /// `indirect` jumps.
+    pub successors: Vec<usize>,
+    /// True if the block ends in a jump whose target isn't constant.
+    pub indirect: bool,
+}
+
+impl Block {
+    /// Returns the offset just past the block's last instruction.
+    pub fn end(&self) -> usize {
+        self.last().next()
+    }
+
+    /// Returns the block's last instruction.
+    pub fn last(&self) -> &Decoded {
+        &self.instructions[self.instructions.len() - 1]
+    }
+}
+
+/// The control flow graph of a program.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Cfg {
+    blocks: Vec<Block>,
+    /// The starts of the blocks an indirect jump may reach.
+    indirect_targets: Vec<usize>,
+}
+
+impl Cfg {
+    /// Builds the control flow graph of `program`, splitting blocks at the
+    /// labels in `debug_info` as well. Bytes that don't decode are left out.
+    pub fn build(program: &[u8], debug_info: Option<&DebugInfo>) -> Cfg {
+        let (decoded, _) = decode(program);
+        let boundaries: HashSet<usize> = decoded.iter().map(|instruction| instruction.offset).collect();
+
+        // Resolve jump targets the way the verifier does, then start a block
+        // at each one that lands on an instruction.
+        let mut targets = vec![None; decoded.len()];
+        let mut known = KnownValues::unknown();
+        for (index, instruction) in decoded.iter().enumerate() {
+            targets[index] = known.jump_target(instruction);
+            known.apply(instruction);
+            if is_control_flow(instruction.opcode) {
+                known = KnownValues::unknown();
+            }
+        }
+        let labels: Vec<usize> = debug_info
+            .map(|debug_info| debug_info.labels().iter().map(|(_, offset)| *offset).collect())
+            .unwrap_or_default();
+        let mut leaders: HashSet<usize> = labels.iter().cloned().filter(|offset| boundaries.contains(offset)).collect();
+        leaders.extend(decoded.first().map(|instruction| instruction.offset));
+        for (instruction, target) in decoded.iter().zip(&targets) {
+            if let Some(target) = target.filter(|&target| target >= 0 && boundaries.contains(&(target as usize))) {
+                leaders.insert(target as usize);
+            }
+            if is_control_flow(instruction.opcode) {
+                leaders.insert(instruction.next());
+            }
+        }
+
+        let mut blocks: Vec<Block> = vec![];
+        for (instruction, target) in decoded.into_iter().zip(targets) {
+            let offset = instruction.offset;
+            if leaders.contains(&offset) || blocks.is_empty() {
+                blocks.push(Block { start: offset, instructions: vec![], successors: vec![], indirect: false });
+            }
+            let block = blocks.last_mut().expect("a block was just pushed");
+            block.instructions.push(instruction);
+            let instruction = block.last();
+            if instruction.opcode.is_jump() {
+                match target {
+                    Some(target) if target >= 0 && boundaries.contains(&(target as usize)) => {
+                        block.successors.push(target as usize)
+                    }
+                    // Jumping to the end of the program stops it.
+                    Some(target) if target == program.len() as i64 => {}
+                    // Jumping anywhere else faults.
+                    Some(_) => {}
+                    None => block.indirect = true,
+                }
+            }
+        }
+        for index in 0..blocks.len() {
+            let falls_through = !matches!(blocks[index].last().opcode, Opcode::HLT | Opcode::JMP | Opcode::JMPF | Opcode::JMPB);
+            let next = blocks.get(index + 1).map(|block| block.start);
+            let block = &mut blocks[index];
+            if let Some(next) = next.filter(|&next| falls_through && next == block.end()) {
+                if !block.successors.contains(&next) {
+                    block.successors.push(next);
+                }
+            }
+        }
+
+        let indirect_targets = match debug_info {
+            Some(_) => blocks.iter().map(|block| block.start).filter(|start| labels.contains(start)).collect(),
+            None => blocks.iter().map(|block| block.start).collect(),
+        };
+        Cfg { blocks, indirect_targets }
+    }
+
+    /// Returns the blocks in the order they appear in the program.
+    pub fn blocks(&self) -> &[Block] {
+        &self.blocks
+    }
+
+    /// Returns the block starting at `start`.
+    pub fn block(&self, start: usize) -> Option<&Block> {
+        self.blocks.iter().find(|block| block.start == start)
+    }
+
+    /// Returns the starts of every block control may flow to after `block`,
+    /// including the blocks an indirect jump may reach.
+    pub fn successors(&self, block: &Block) -> Vec<usize> {
+        let mut successors = block.successors.clone();
+        if block.indirect {
+            for &target in &self.indirect_targets {
+                if !successors.contains(&target) {
+                    successors.push(target);
+                }
+            }
+        }
+        successors
+    }
+
+    /// Returns the starts of the blocks `block` may be entered from.
+    pub fn predecessors(&self, block: &Block) -> Vec<usize> {
+        self.blocks
+            .iter()
+            .filter(|other| self.successors(other).contains(&block.start))
+            .map(|other| other.start)
+            .collect()
+    }
+
+    /// Returns the starts of the blocks an indirect jump may reach.
+    pub fn indirect_targets(&self) -> &[usize] {
+        &self.indirect_targets
+    }
+
+    /// Writes the graph in Graphviz DOT. Blocks are named with the labels and
+    /// source lines in `debug_info` when it is given, and unreachable blocks
+    /// are greyed out. Edges for indirect jumps are dashed.
+    pub fn to_dot(&self, debug_info: Option<&DebugInfo>) -> String {
+        let reachable = self.reachable();
+        let mut dot = String::from("digraph cfg {\n    node [shape=box, fontname=\"monospace\"];\n");
+        for block in &self.blocks {
+            let mut label = format!("{}\\l", escape_dot(&block_name(block.start, debug_info)));
+            for instruction in &block.instructions {
+                label.push_str(&escape_dot(&format!("{:>5}: {}", instruction.offset, instruction)));
+                label.push_str("\\l");
+            }
+            let style = if reachable.contains(&block.start) { "" } else { ", style=filled, fillcolor=lightgrey" };
+            dot.push_str(&format!("    b{} [label=\"{}\"{}];\n", block.start, label, style));
+        }
+        for block in &self.blocks {
+            for successor in &block.successors {
+                dot.push_str(&format!("    b{} -> b{};\n", block.start, successor));
+            }
+            if block.indirect {
+                for target in self.indirect_targets.iter().filter(|target| !block.successors.contains(target)) {
+                    dot.push_str(&format!("    b{} -> b{} [style=dashed];\n", block.start, target));
+                }
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Returns the graph as JSON: an object with a `blocks` array, where each
+    /// block has its `start`, `end`, `name`, whether it is `reachable`, its
+    /// `instructions` with their `offset`, `text`, source `file` and `line`,
+    /// the `successors` it may flow to directly, and whether it also jumps
+    /// `indirect`ly to any of the top level `indirect_targets`.
+    pub fn to_json(&self, debug_info: Option<&DebugInfo>) -> Json {
+        let reachable = self.reachable();
+        let offsets = |offsets: &[usize]| Json::Array(offsets.iter().map(|&offset| Json::from(offset)).collect());
+        let blocks = self
+            .blocks
+            .iter()
+            .map(|block| {
+                let instructions = block
+                    .instructions
+                    .iter()
+                    .map(|instruction| {
+                        let location = debug_info.and_then(|debug_info| debug_info.location(instruction.offset));
+                        Json::object(vec![
+                            ("offset", Json::from(instruction.offset)),
+                            ("text", Json::from(instruction.to_string())),
+                            ("file", location.map_or(Json::Null, |(file, _)| Json::from(file))),
+                            ("line", location.map_or(Json::Null, |(_, line)| Json::from(line))),
+                        ])
+                    })
+                    .collect();
+                Json::object(vec![
+                    ("start", Json::from(block.start)),
+                    ("end", Json::from(block.end())),
+                    ("name", Json::from(block_name(block.start, debug_info))),
+                    ("reachable", Json::from(reachable.contains(&block.start))),
+                    ("instructions", Json::Array(instructions)),
+                    ("successors", offsets(&block.successors)),
+                    ("indirect", Json::from(block.indirect)),
+                ])
+            })
+            .collect();
+        Json::object(vec![("blocks", Json::Array(blocks)), ("indirect_targets", offsets(&self.indirect_targets))])
+    }
+
+    /// Returns the starts of the blocks reachable from the start of the
+    /// program.
+    pub fn reachable(&self) -> HashSet<usize> {
+        let mut reachable = HashSet::new();
+        let mut pending: Vec<usize> = self.blocks.first().map(|block| block.start).into_iter().collect();
+        while let Some(start) = pending.pop() {
+            if !reachable.insert(start) {
+                continue;
+            }
+            if let Some(block) = self.block(start) {
+                pending.extend(self.successors(block));
+            }
+        }
+        reachable
+    }
+}
+
+/// Names the block starting at `start` after its label and source line, or
+/// its offset without debug info.
+fn block_name(start: usize, debug_info: Option<&DebugInfo>) -> String {
+    match debug_info {
+        Some(debug_info) => debug_info.describe(start),
+        None => start.to_string(),
+    }
+}
+
+fn escape_dot(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble_with_debug_info;
+
+    fn starts(cfg: &Cfg) -> Vec<usize> {
+        cfg.blocks().iter().map(|block| block.start).collect()
+    }
+
+    #[test]
+    fn test_build_blocks() {
+        let source = "load $0 #3\nload $1 @loop\nloop: dec $0\nload $2 #0\neq $0 $2 $0\njneq $1\nhlt\n";
+        let (program, debug_info) = assemble_with_debug_info(source, "").unwrap();
+        let cfg = Cfg::build(&program, Some(&debug_info));
+        assert_eq!(starts(&cfg), vec![0, 8, 20]);
+        assert_eq!(cfg.blocks()[0].successors, vec![8]);
+        assert_eq!(cfg.blocks()[1].successors, vec![8, 20]);
+        assert!(cfg.blocks()[2].successors.is_empty());
+        assert_eq!(cfg.predecessors(&cfg.blocks()[1]), vec![0, 8]);
+        assert_eq!(cfg.reachable().len(), 3);
+    }
+
+    #[test]
+    fn test_unreachable_blocks() {
+        // load $0 @end; jmp $0; inc $1; end: hlt
+        let (program, debug_info) = assemble_with_debug_info("load $0 @end\njmp $0\ninc $1\nend: hlt\n", "").unwrap();
+        let cfg = Cfg::build(&program, Some(&debug_info));
+        assert_eq!(starts(&cfg), vec![0, 6, 8]);
+        assert_eq!(cfg.reachable(), [0, 8].into_iter().collect());
+    }
+
+    #[test]
+    fn test_to_dot() {
+        let (program, debug_info) = assemble_with_debug_info("load $0 @end\njmp $0\ninc $1\nend: hlt\n", "prog.iasm").unwrap();
+        let dot = Cfg::build(&program, Some(&debug_info)).to_dot(Some(&debug_info));
+        assert_eq!(
+            dot,
+            r#"digraph cfg {
+    node [shape=box, fontname="monospace"];
+    b0 [label="0 (prog.iasm:1)\l    0: load $0 #8\l    4: jmp $0\l"];
+    b6 [label="6 (prog.iasm:3)\l    6: inc $1\l", style=filled, fillcolor=lightgrey];
+    b8 [label="end (prog.iasm:4)\l    8: hlt\l"];
+    b0 -> b8;
+    b6 -> b8;
+}
+"#
+        );
+    }
+
+    #[test]
+    fn test_to_json() {
+        // load $0 #4; add $0 $0 $0; jmp $0; hlt; hlt
+        let cfg = Cfg::build(&[0, 0, 0, 4, 1, 0, 0, 0, 6, 0, 5, 5], None);
+        let json = Json::parse(&cfg.to_json(None).to_string()).unwrap();
+        let Some(Json::Array(blocks)) = json.get("blocks") else { panic!("no blocks") };
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(blocks[0].get("end"), Some(&Json::from(10)));
+        assert_eq!(blocks[0].get("indirect"), Some(&Json::Bool(true)));
+        let Some(Json::Array(instructions)) = blocks[0].get("instructions") else { panic!("no instructions") };
+        assert_eq!(instructions[1].get("text").and_then(Json::as_str), Some("add $0 $0 $0"));
+        assert_eq!(instructions[1].get("file"), Some(&Json::Null));
+        assert_eq!(instructions[1].get("line"), Some(&Json::Null));
+        assert_eq!(
+            json.get("indirect_targets"),
+            Some(&Json::Array(vec![Json::from(0), Json::from(10), Json::from(11)]))
+        );
+    }
+
+    #[test]
+    fn test_indirect_jumps() {
+        // load $0 #4; add $0 $0 $0; jmp $0; hlt; hlt
+        let program = vec![0, 0, 0, 4, 1, 0, 0, 0, 6, 0, 5, 5];
+        let cfg = Cfg::build(&program, None);
+        assert_eq!(starts(&cfg), vec![0, 10, 11]);
+        assert!(cfg.blocks()[0].indirect);
+        assert_eq!(cfg.successors(&cfg.blocks()[0]), vec![0, 10, 11]);
+
+        let source = "load $0 #4\nadd $0 $0 $0\njmp $0\nhlt\nend: hlt\n";
+        let (program, debug_info) = assemble_with_debug_info(source, "").unwrap();
+        let cfg = Cfg::build(&program, Some(&debug_info));
+        assert_eq!(cfg.reachable(), [0, 11].into_iter().collect());
+    }
+}
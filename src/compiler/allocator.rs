@@ -0,0 +1,277 @@
+//! `allocator` maps each function's virtual registers onto the VM's
+//! registers.
+//!
+//! Within a function, virtual registers are given slots by colouring an
+//! interference graph: two can share a slot unless one is written while the
+//! other still holds a value that will be read. Registers joined by a move
+//! are given the same slot where they can be, so the move disappears.
+//!
+//! With no stack to save registers on, a function's registers must not
+//! overlap those of any function partway through a call to it. Each function
+//! is placed just above the highest of its callers, so functions that are
+//! never active at once share registers. Recursion would need fresh registers
+//! for every call, and is rejected.
+use std::collections::{HashMap, HashSet};
+
+use crate::compiler::ir::{FunctionIr, Op, Reg, VReg};
+use crate::compiler::CompileError;
+use crate::syscall::ARGUMENT_REGISTERS;
+use crate::vm::REGISTER_COUNT;
+
+/// The first register a function's slots can use. The ones below are fixed,
+/// as described in `ir`.
+pub const FIRST_REGISTER: usize = ARGUMENT_REGISTERS.end;
+
+/// Where a function's virtual registers live, relative to the function's
+/// first register.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Allocation {
+    /// The slot of each virtual register.
+    pub slots: Vec<usize>,
+    /// How many slots the function uses.
+    pub size: usize,
+}
+
+/// Returns the operations that can run after each operation of `ops`.
+fn successors(ops: &[(Op, usize)]) -> Vec<Vec<usize>> {
+    let labels: HashMap<&str, usize> = ops
+        .iter()
+        .enumerate()
+        .filter_map(|(index, (op, _))| match op {
+            Op::Label(label) => Some((label.as_str(), index)),
+            _ => None,
+        })
+        .collect();
+    ops.iter()
+        .enumerate()
+        .map(|(index, (op, _))| {
+            let next = Some(index + 1).filter(|&next| next < ops.len());
+            match op {
+                Op::Jump { label } => vec![labels[label.as_str()]],
+                Op::Branch { label, .. } => next.into_iter().chain(Some(labels[label.as_str()])).collect(),
+                Op::Return { .. } | Op::Halt => vec![],
+                _ => next.into_iter().collect(),
+            }
+        })
+        .collect()
+}
+
+/// Returns the virtual registers holding a value that will be read, just
+/// after each operation of `function`.
+pub fn live_out(function: &FunctionIr) -> Vec<HashSet<VReg>> {
+    let successors = successors(&function.ops);
+    let mut live_in = vec![HashSet::new(); function.ops.len()];
+    let mut live_out = vec![HashSet::new(); function.ops.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for index in (0..function.ops.len()).rev() {
+            let out: HashSet<VReg> = successors[index].iter().flat_map(|&next| live_in[next].iter().cloned()).collect();
+            let op = &function.ops[index].0;
+            let mut live = out.clone();
+            for def in op.defs() {
+                live.remove(&def);
+            }
+            live.extend(op.uses());
+            if live != live_in[index] {
+                live_in[index] = live;
+                changed = true;
+            }
+            live_out[index] = out;
+        }
+    }
+    live_out
+}
+
+/// Gives every virtual register of `function` a slot.
+pub fn allocate(function: &FunctionIr) -> Allocation {
+    let mut neighbours = vec![HashSet::new(); function.vregs];
+    let mut partners = vec![vec![]; function.vregs];
+    for ((op, _), live) in function.ops.iter().zip(live_out(function)) {
+        let defs = op.defs();
+        // A move's destination can share with its source, since they hold
+        // the same value.
+        let moved = match op {
+            Op::Move { src: Reg::Virtual(src), dst: Reg::Virtual(dst) } => {
+                partners[*src].push(*dst);
+                partners[*dst].push(*src);
+                Some(*src)
+            }
+            _ => None,
+        };
+        // Everything an operation writes interferes with everything live
+        // after it, and with the other registers it writes.
+        for &def in &defs {
+            for &other in live.iter().chain(&defs) {
+                if other != def && Some(other) != moved {
+                    neighbours[def].insert(other);
+                    neighbours[other].insert(def);
+                }
+            }
+        }
+    }
+
+    let mut slots: Vec<Option<usize>> = vec![None; function.vregs];
+    for vreg in 0..function.vregs {
+        let taken: HashSet<usize> = neighbours[vreg].iter().filter_map(|&other| slots[other]).collect();
+        let partner = partners[vreg].iter().filter_map(|&other| slots[other]).find(|slot| !taken.contains(slot));
+        slots[vreg] = Some(partner.unwrap_or_else(|| (0..).find(|slot| !taken.contains(slot)).expect("slots are unbounded")));
+    }
+    let slots: Vec<usize> = slots.into_iter().map(|slot| slot.expect("every register has a slot")).collect();
+    let size = slots.iter().max().map_or(0, |&slot| slot + 1);
+    Allocation { slots, size }
+}
+
+/// Describes `function` for an error message.
+fn describe(function: &FunctionIr, index: usize) -> String {
+    if index == 0 {
+        "the top level".to_string()
+    } else {
+        format!("`{}`", function.name)
+    }
+}
+
+/// Returns the index of every function, with each before everything it
+/// calls, or an error if a function can call itself.
+fn callers_first(functions: &[FunctionIr]) -> Result<Vec<usize>, CompileError> {
+    /// Adds `index` to `order` after everything it calls.
+    fn visit(
+        functions: &[FunctionIr],
+        index: usize,
+        visiting: &mut Vec<bool>,
+        done: &mut Vec<bool>,
+        order: &mut Vec<usize>,
+    ) -> Result<(), CompileError> {
+        if done[index] {
+            return Ok(());
+        }
+        visiting[index] = true;
+        for &(callee, line) in &functions[index].calls {
+            if visiting[callee] {
+                return Err(CompileError::new(
+                    line,
+                    format!(
+                        "`{}` is called recursively, which needs a call stack the VM doesn't have",
+                        functions[callee].name
+                    ),
+                ));
+            }
+            visit(functions, callee, visiting, done, order)?;
+        }
+        visiting[index] = false;
+        done[index] = true;
+        order.push(index);
+        Ok(())
+    }
+
+    let mut visiting = vec![false; functions.len()];
+    let mut done = vec![false; functions.len()];
+    let mut order = vec![];
+    for index in 0..functions.len() {
+        visit(functions, index, &mut visiting, &mut done, &mut order)?;
+    }
+    order.reverse();
+    Ok(order)
+}
+
+/// Returns the first register of each function, placing each above all of
+/// its callers. Fails if there is recursion or the registers run out.
+pub fn layout(functions: &[FunctionIr], allocations: &[Allocation]) -> Result<Vec<usize>, CompileError> {
+    let mut bases = vec![FIRST_REGISTER; functions.len()];
+    for caller in callers_first(functions)? {
+        let top = bases[caller] + allocations[caller].size;
+        for &(callee, _) in &functions[caller].calls {
+            bases[callee] = bases[callee].max(top);
+        }
+    }
+    for (index, function) in functions.iter().enumerate() {
+        let size = allocations[index].size;
+        if bases[index] + size > REGISTER_COUNT {
+            return Err(CompileError::new(
+                function.line,
+                format!(
+                    "{} needs {} registers on top of the {} in use when it runs, but the VM only has {}",
+                    describe(function, index),
+                    size,
+                    bases[index],
+                    REGISTER_COUNT
+                ),
+            ));
+        }
+    }
+    Ok(bases)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::ir::lower;
+    use crate::compiler::parser::parse;
+
+    fn lower_source(source: &str) -> Vec<FunctionIr> {
+        lower(&parse(source).unwrap()).unwrap()
+    }
+
+    /// Allocates and lays out `source`, returning each function's
+    /// allocation and first register.
+    fn layout_source(source: &str) -> Result<(Vec<Allocation>, Vec<usize>), CompileError> {
+        let functions = lower(&parse(source)?)?;
+        let allocations: Vec<Allocation> = functions.iter().map(allocate).collect();
+        let bases = layout(&functions, &allocations)?;
+        Ok((allocations, bases))
+    }
+
+    #[test]
+    fn test_allocate_shares_slots() {
+        let functions = lower_source("let a = 1;\nlet b = a + 1;\nlet c = b + 1;\nreturn c;\n");
+        let allocations: Vec<Allocation> = functions.iter().map(allocate).collect();
+        assert_eq!(functions[0].vregs, 8);
+        assert_eq!(allocations[0].size, 2);
+
+        // Nothing live at the same time shares a slot.
+        for ((op, _), live) in functions[0].ops.iter().zip(live_out(&functions[0])) {
+            for def in op.defs() {
+                for other in live.iter().filter(|&&other| other != def) {
+                    assert_ne!(allocations[0].slots[def], allocations[0].slots[*other]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_allocate_parameters() {
+        // Unused parameters still need their own registers, since the caller
+        // writes all of them.
+        let functions = lower_source("fn f(a, b) {\n    return 0;\n}\n");
+        let slots = allocate(&functions[1]).slots;
+        assert!(slots[0] != slots[1] && slots[1] != slots[2] && slots[0] != slots[2]);
+    }
+
+    #[test]
+    fn test_layout() {
+        let source = "fn g(x) { return x; }\nfn f(x) { return g(x) + 1; }\nfn h(x) { return x * 2; }\nreturn f(1) + h(2);\n";
+        let (allocations, bases) = layout_source(source).unwrap();
+        assert_eq!(bases[0], FIRST_REGISTER);
+        let above_main = FIRST_REGISTER + allocations[0].size;
+        assert_eq!(bases[2], above_main);
+        assert_eq!(bases[1], above_main + allocations[2].size);
+        assert_eq!(bases[3], above_main);
+    }
+
+    #[test]
+    fn test_layout_errors() {
+        let error = |source| layout_source(source).unwrap_err().to_string();
+        assert_eq!(
+            error("fn f(n) {\n    return g(n);\n}\nfn g(n) {\n    return f(n);\n}\nf(1);\n"),
+            "line 5: `f` is called recursively, which needs a call stack the VM doesn't have"
+        );
+
+        let names: Vec<String> = (0..30).map(|n| format!("v{}", n)).collect();
+        let mut source: String = names.iter().enumerate().map(|(n, name)| format!("let {} = {};\n", name, n)).collect();
+        source.push_str(&format!("return {};\n", names.join(" + ")));
+        assert_eq!(
+            error(&source),
+            "line 1: the top level needs 30 registers on top of the 5 in use when it runs, but the VM only has 32"
+        );
+    }
+}
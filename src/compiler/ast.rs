@@ -0,0 +1,115 @@
+//! `ast` holds the syntax tree the parser builds from source.
+
+/// An operator taking two operands.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum BinaryOp {
+    /// `+`
+    Add,
+    /// `-`
+    Sub,
+    /// `*`
+    Mul,
+    /// `/`, rounding towards zero.
+    Div,
+    /// `%`, with the sign of the left operand.
+    Rem,
+    /// `==`
+    Eq,
+    /// `!=`
+    Ne,
+    /// `<`
+    Lt,
+    /// `<=`
+    Le,
+    /// `>`
+    Gt,
+    /// `>=`
+    Ge,
+    /// `&&`, which only evaluates its right operand if the left is true.
+    And,
+    /// `||`, which only evaluates its right operand if the left is false.
+    Or,
+}
+
+impl BinaryOp {
+    /// Returns true for the operators that compare their operands.
+    pub fn is_comparison(self) -> bool {
+        matches!(self, BinaryOp::Eq | BinaryOp::Ne | BinaryOp::Lt | BinaryOp::Le | BinaryOp::Gt | BinaryOp::Ge)
+    }
+}
+
+/// An operator taking one operand.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum UnaryOp {
+    /// `-`
+    Neg,
+    /// `!`, giving 1 for 0 and 0 for anything else.
+    Not,
+}
+
+/// An expression. Every value is a 32 bit signed integer; conditions treat
+/// 0 as false and anything else as true, and comparisons give 0 or 1.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Expr {
+    /// An integer literal such as `42` or `'a'`.
+    Number(i64),
+    /// The value of a variable.
+    Variable(String),
+    /// An operator applied to one operand.
+    Unary(UnaryOp, Box<Expr>),
+    /// An operator applied to two operands.
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    /// A call of a function, or of the `syscall` builtin, with arguments.
+    Call(String, Vec<Expr>),
+}
+
+/// A statement, with the line it starts on.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Stmt {
+    /// The 1-based line the statement starts on.
+    pub line: usize,
+    /// What the statement does.
+    pub kind: StmtKind,
+}
+
+/// The kinds of statement.
+#[derive(Debug, PartialEq, Clone)]
+pub enum StmtKind {
+    /// `let name = value;` declares a variable for the rest of the block.
+    Let(String, Expr),
+    /// `name = value;` changes a variable.
+    Assign(String, Expr),
+    /// `if condition { ... } else { ... }`. `else if` is an `If` alone in the
+    /// else block.
+    If(Expr, Vec<Stmt>, Vec<Stmt>),
+    /// `while condition { ... }`
+    While(Expr, Vec<Stmt>),
+    /// `return value;` or `return;`, which returns 0. At the top level it
+    /// stops the program, leaving the value in `$0`.
+    Return(Option<Expr>),
+    /// An expression evaluated for its effects, such as a call.
+    Expr(Expr),
+}
+
+/// A function declared with `fn name(params) { ... }`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Function {
+    /// The 1-based line of the declaration.
+    pub line: usize,
+    /// The function's name.
+    pub name: String,
+    /// The names of the parameters.
+    pub params: Vec<String>,
+    /// The statements in the body.
+    pub body: Vec<Stmt>,
+}
+
+/// A whole source file: its functions, and the statements outside them,
+/// which are run in order as the program.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct Program {
+    /// The functions, in the order they were declared.
+    pub functions: Vec<Function>,
+    /// The top level statements.
+    pub body: Vec<Stmt>,
+}
@@ -0,0 +1,116 @@
+//! `codegen` writes out allocated operations as assembly source, one
+//! instruction per line, for the assembler to turn into bytecode.
+use crate::compiler::allocator::Allocation;
+use crate::compiler::ir::{FunctionIr, Op, Reg};
+use crate::syscall::RESULT_REGISTER;
+
+/// The register jump targets are loaded into.
+const JUMP_REGISTER: usize = RESULT_REGISTER;
+
+/// Generates assembly for `functions`, given each one's `allocations` and
+/// first register. Returns the lines of assembly, each with the source line
+/// it came from.
+pub fn generate(functions: &[FunctionIr], allocations: &[Allocation], bases: &[usize]) -> Vec<(String, usize)> {
+    let mut lines = vec![];
+    for (index, function) in functions.iter().enumerate() {
+        let register = |reg: &Reg| match *reg {
+            Reg::Virtual(vreg) => bases[index] + allocations[index].slots[vreg],
+            Reg::Fixed(register) => register,
+            Reg::Callee { function, vreg } => bases[function] + allocations[function].slots[vreg],
+        };
+        lines.push((format!("{}:", function.label), function.line));
+        for (op, line) in &function.ops {
+            for text in instructions(op, &register) {
+                lines.push((text, *line));
+            }
+        }
+    }
+    lines
+}
+
+/// Returns the assembly for `op`, with `register` giving the VM register of
+/// each register operand.
+fn instructions(op: &Op, register: &dyn Fn(&Reg) -> usize) -> Vec<String> {
+    match op {
+        Op::Entry { .. } => vec![],
+        Op::Load { dst, value } => load(register(dst), *value),
+        Op::Binary { opcode, lhs, rhs, dst } => {
+            vec![format!("{} ${} ${} ${}", opcode.mnemonic(), register(lhs), register(rhs), register(dst))]
+        }
+        Op::Move { src, dst } if register(src) == register(dst) => vec![],
+        Op::Move { src, dst } => vec![format!("mov ${} ${}", register(src), register(dst))],
+        Op::Branch { opcode, lhs, rhs, label } => vec![
+            format!("load ${} @{}", JUMP_REGISTER, label),
+            format!("{} ${} ${} ${}", opcode.mnemonic(), register(lhs), register(rhs), JUMP_REGISTER),
+        ],
+        Op::Jump { label } => vec![format!("load ${} @{}", JUMP_REGISTER, label), format!("jmp ${}", JUMP_REGISTER)],
+        Op::Label(label) => vec![format!("{}:", label)],
+        Op::Call { label, return_address, return_label } => vec![
+            format!("load ${} @{}", register(return_address), return_label),
+            format!("load ${} @{}", JUMP_REGISTER, label),
+            format!("jmp ${}", JUMP_REGISTER),
+            format!("{}:", return_label),
+        ],
+        Op::Return { return_address } => vec![format!("jmp ${}", register(return_address))],
+        Op::Syscall { number } => vec![format!("syscall ${}", register(number))],
+        Op::Halt => vec!["hlt".to_string()],
+    }
+}
+
+/// Returns instructions setting `dst` to `value`. LOAD only takes 16 bits,
+/// so bigger or negative values are built up 16 bits at a time with the
+/// immediate opcodes, which wrap.
+fn load(dst: usize, value: i64) -> Vec<String> {
+    if (0..=u16::MAX as i64).contains(&value) {
+        return vec![format!("load ${} #{}", dst, value)];
+    }
+    let (magnitude, step) = if value < 0 { (-value, "subi") } else { (value, "addi") };
+    let (high, low) = (magnitude >> 16, magnitude & 0xffff);
+    let mut instructions = vec![format!("load ${} #0", dst)];
+    if high > 0 {
+        instructions.push(format!("{} ${} #{} ${}", step, dst, high, dst));
+        instructions.push(format!("muli ${} #256 ${}", dst, dst));
+        instructions.push(format!("muli ${} #256 ${}", dst, dst));
+    }
+    if low > 0 {
+        instructions.push(format!("{} ${} #{} ${}", step, dst, low, dst));
+    }
+    instructions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::vm::VM;
+
+    #[test]
+    fn test_load() {
+        assert_eq!(load(6, 500), vec!["load $6 #500"]);
+        assert_eq!(load(6, -3), vec!["load $6 #0", "subi $6 #3 $6"]);
+        for value in [65536, 100_000, -65536, -1_234_567, i32::MAX as i64, i32::MIN as i64] {
+            let source = format!("{}\nhlt", load(6, value).join("\n"));
+            let mut vm = VM::new();
+            vm.load_program(assemble(&source).unwrap());
+            vm.run().unwrap();
+            assert_eq!(vm.register(6) as i64, value);
+        }
+    }
+
+    #[test]
+    fn test_instructions() {
+        let register = |reg: &Reg| match reg {
+            Reg::Virtual(vreg) => 10 + vreg,
+            Reg::Fixed(register) => *register,
+            Reg::Callee { vreg, .. } => 20 + vreg,
+        };
+        let call = Op::Call {
+            label: "fn_f".to_string(),
+            return_address: Reg::Callee { function: 1, vreg: 2 },
+            return_label: "L1".to_string(),
+        };
+        assert_eq!(instructions(&call, &register), vec!["load $22 @L1", "load $0 @fn_f", "jmp $0", "L1:"]);
+        let same = Op::Move { src: Reg::Virtual(1), dst: Reg::Virtual(1) };
+        assert_eq!(instructions(&same, &register), Vec::<String>::new());
+    }
+}
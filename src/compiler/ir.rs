@@ -0,0 +1,575 @@
+//! `ir` lowers the syntax tree to a list of operations per function over an
+//! unlimited supply of virtual registers, which `allocator` then maps onto
+//! the VM's registers.
+//!
+//! The VM has no memory, so there is no stack: each function keeps its
+//! variables in registers of its own, and a caller passes arguments by
+//! writing them straight into the callee's parameter registers. The fixed
+//! registers are used as follows:
+//!
+//! - `$0` holds a function's return value on the way back to its caller, and
+//!   the target of each jump.
+//! - `$1` to `$4` hold the arguments of a `syscall`, whose result is left in
+//!   `$0`.
+use std::collections::HashMap;
+
+use crate::compiler::ast::{BinaryOp, Expr, Program, Stmt, StmtKind, UnaryOp};
+use crate::compiler::CompileError;
+use crate::instruction::Opcode;
+use crate::syscall::{ARGUMENT_REGISTERS, RESULT_REGISTER};
+
+/// The name of the builtin that makes a SYSCALL.
+pub const SYSCALL: &str = "syscall";
+
+/// A virtual register, numbered from 0 within its function.
+pub type VReg = usize;
+
+/// A register an operation reads or writes.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Reg {
+    /// A virtual register of the function the operation is in.
+    Virtual(VReg),
+    /// One of the VM's registers, used the same way by every function.
+    Fixed(usize),
+    /// A virtual register of another function: a parameter or the return
+    /// address a caller sets before jumping to it.
+    Callee {
+        /// The index of the function called.
+        function: usize,
+        /// The register in the called function.
+        vreg: VReg,
+    },
+}
+
+/// An operation, which becomes one or a few instructions.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Op {
+    /// The start of a function, where the caller has set the parameters and
+    /// the return address.
+    Entry {
+        /// The parameters, followed by the return address.
+        defs: Vec<VReg>,
+    },
+    /// Sets `dst` to any 32 bit value.
+    Load {
+        /// The register set.
+        dst: Reg,
+        /// The value.
+        value: i64,
+    },
+    /// ADD, SUB, MUL, DIV or REM.
+    Binary {
+        /// The opcode.
+        opcode: Opcode,
+        /// The first operand.
+        lhs: Reg,
+        /// The second operand.
+        rhs: Reg,
+        /// The register the result goes in.
+        dst: Reg,
+    },
+    /// Copies `src` into `dst`.
+    Move {
+        /// The register copied.
+        src: Reg,
+        /// The register set.
+        dst: Reg,
+    },
+    /// Jumps to `label` if comparing `lhs` and `rhs` with one of the BEQ
+    /// family of opcodes succeeds.
+    Branch {
+        /// The opcode.
+        opcode: Opcode,
+        /// The first operand.
+        lhs: Reg,
+        /// The second operand.
+        rhs: Reg,
+        /// Where to jump.
+        label: String,
+    },
+    /// Jumps to `label`.
+    Jump {
+        /// Where to jump.
+        label: String,
+    },
+    /// Marks the place `label` names.
+    Label(String),
+    /// Calls the function at `label`, which returns to `return_label`. The
+    /// arguments are already in place.
+    Call {
+        /// The function's label.
+        label: String,
+        /// The callee's return address register.
+        return_address: Reg,
+        /// The label just after the call.
+        return_label: String,
+    },
+    /// Jumps back to the caller; the return value is already in `$0`.
+    Return {
+        /// The register holding the return address.
+        return_address: Reg,
+    },
+    /// Calls the host service numbered `number`.
+    Syscall {
+        /// The register holding the syscall number.
+        number: Reg,
+    },
+    /// Stops the program.
+    Halt,
+}
+
+impl Op {
+    /// Returns the virtual registers the operation reads.
+    pub fn uses(&self) -> Vec<VReg> {
+        let regs = match self {
+            Op::Binary { lhs, rhs, .. } | Op::Branch { lhs, rhs, .. } => vec![*lhs, *rhs],
+            Op::Move { src, .. } => vec![*src],
+            Op::Return { return_address } => vec![*return_address],
+            Op::Syscall { number } => vec![*number],
+            _ => vec![],
+        };
+        virtual_only(regs)
+    }
+
+    /// Returns the virtual registers the operation writes.
+    pub fn defs(&self) -> Vec<VReg> {
+        match self {
+            Op::Entry { defs } => defs.clone(),
+            Op::Load { dst, .. } | Op::Binary { dst, .. } | Op::Move { dst, .. } => virtual_only(vec![*dst]),
+            _ => vec![],
+        }
+    }
+}
+
+fn virtual_only(regs: Vec<Reg>) -> Vec<VReg> {
+    regs.into_iter()
+        .filter_map(|reg| match reg {
+            Reg::Virtual(vreg) => Some(vreg),
+            _ => None,
+        })
+        .collect()
+}
+
+/// A function lowered to operations. The program's top level statements are
+/// lowered as a function too, which halts instead of returning.
+#[derive(Debug, PartialEq, Clone)]
+pub struct FunctionIr {
+    /// The function's name.
+    pub name: String,
+    /// The line the function is declared on.
+    pub line: usize,
+    /// The label the function starts at.
+    pub label: String,
+    /// The operations, each with the source line it came from.
+    pub ops: Vec<(Op, usize)>,
+    /// How many virtual registers the operations use.
+    pub vregs: usize,
+    /// The functions called, by index, with the line of each call.
+    pub calls: Vec<(usize, usize)>,
+}
+
+/// What the label of each function starts with, so they can't clash with
+/// the labels made inside functions.
+pub const FUNCTION_LABEL_PREFIX: &str = "fn_";
+
+/// Returns the label of the function called `name`.
+pub fn function_label(name: &str) -> String {
+    format!("{}{}", FUNCTION_LABEL_PREFIX, name)
+}
+
+/// Lowers `program`. The top level statements come first, as the function
+/// named `main` at index 0, followed by the declared functions in order.
+pub fn lower(program: &Program) -> Result<Vec<FunctionIr>, CompileError> {
+    let mut signatures = HashMap::new();
+    for (index, function) in program.functions.iter().enumerate() {
+        if function.name == SYSCALL {
+            return Err(CompileError::new(function.line, format!("`{}` is a builtin and can't be redeclared", SYSCALL)));
+        }
+        if signatures.insert(function.name.as_str(), (index + 1, function.params.len())).is_some() {
+            return Err(CompileError::new(function.line, format!("function `{}` is declared more than once", function.name)));
+        }
+        for (position, param) in function.params.iter().enumerate() {
+            if function.params[..position].contains(param) {
+                return Err(CompileError::new(function.line, format!("parameter `{}` is declared more than once", param)));
+            }
+        }
+    }
+
+    let mut labels = 0;
+    let mut lowered = vec![];
+    let mut main = Lowerer::new(&signatures, &mut labels, None);
+    main.block(&program.body)?;
+    lowered.push(main.finish("main", 1, "main".to_string()));
+    for function in &program.functions {
+        let mut lowerer = Lowerer::new(&signatures, &mut labels, Some(&function.params));
+        lowerer.line = function.line;
+        lowerer.block(&function.body)?;
+        lowered.push(lowerer.finish(&function.name, function.line, function_label(&function.name)));
+    }
+    Ok(lowered)
+}
+
+/// Lowers the statements of one function.
+struct Lowerer<'a> {
+    /// The index and parameter count of every declared function.
+    signatures: &'a HashMap<&'a str, (usize, usize)>,
+    /// How many local labels have been made, across all functions.
+    labels: &'a mut usize,
+    ops: Vec<(Op, usize)>,
+    vregs: usize,
+    scopes: Vec<HashMap<String, VReg>>,
+    /// The return address, or `None` at the top level.
+    return_address: Option<VReg>,
+    calls: Vec<(usize, usize)>,
+    /// The line of the statement being lowered.
+    line: usize,
+}
+
+impl<'a> Lowerer<'a> {
+    /// Starts lowering a function with `params`, or the top level when there
+    /// are none. Parameters take the first virtual registers, followed by
+    /// the return address, so callers know where to put them.
+    fn new(
+        signatures: &'a HashMap<&'a str, (usize, usize)>,
+        labels: &'a mut usize,
+        params: Option<&[String]>,
+    ) -> Lowerer<'a> {
+        let mut lowerer = Lowerer {
+            signatures,
+            labels,
+            ops: vec![],
+            vregs: 0,
+            scopes: vec![HashMap::new()],
+            return_address: None,
+            calls: vec![],
+            line: 1,
+        };
+        if let Some(params) = params {
+            for param in params {
+                let vreg = lowerer.vreg();
+                lowerer.scopes[0].insert(param.clone(), vreg);
+            }
+            let return_address = lowerer.vreg();
+            lowerer.return_address = Some(return_address);
+            lowerer.push(Op::Entry { defs: (0..=params.len()).collect() });
+        }
+        lowerer
+    }
+
+    /// Ends the function with an implicit `return 0;`, unless it already
+    /// ends with a return.
+    fn finish(mut self, name: &str, line: usize, label: String) -> FunctionIr {
+        if !matches!(self.ops.last(), Some((Op::Return { .. } | Op::Halt, _))) {
+            self.return_value(None);
+        }
+        FunctionIr { name: name.to_string(), line, label, ops: self.ops, vregs: self.vregs, calls: self.calls }
+    }
+
+    fn push(&mut self, op: Op) {
+        self.ops.push((op, self.line));
+    }
+
+    fn vreg(&mut self) -> VReg {
+        self.vregs += 1;
+        self.vregs - 1
+    }
+
+    fn label(&mut self) -> String {
+        *self.labels += 1;
+        format!("L{}", self.labels)
+    }
+
+    fn error(&self, message: String) -> CompileError {
+        CompileError::new(self.line, message)
+    }
+
+    fn variable(&self, name: &str) -> Result<VReg, CompileError> {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name).cloned())
+            .ok_or_else(|| self.error(format!("undefined variable `{}`", name)))
+    }
+
+    fn block(&mut self, statements: &[Stmt]) -> Result<(), CompileError> {
+        self.scopes.push(HashMap::new());
+        for statement in statements {
+            self.statement(statement)?;
+        }
+        self.scopes.pop();
+        Ok(())
+    }
+
+    fn statement(&mut self, statement: &Stmt) -> Result<(), CompileError> {
+        self.line = statement.line;
+        match &statement.kind {
+            StmtKind::Let(name, value) => {
+                let value = self.value(value)?;
+                let variable = self.vreg();
+                self.push(Op::Move { src: Reg::Virtual(value), dst: Reg::Virtual(variable) });
+                self.scopes.last_mut().expect("there is always a scope").insert(name.clone(), variable);
+            }
+            StmtKind::Assign(name, value) => {
+                let variable = self.variable(name)?;
+                let value = self.value(value)?;
+                self.push(Op::Move { src: Reg::Virtual(value), dst: Reg::Virtual(variable) });
+            }
+            StmtKind::If(condition, then, otherwise) => {
+                let else_label = self.label();
+                self.branch(condition, &else_label, false)?;
+                self.block(then)?;
+                if otherwise.is_empty() {
+                    self.push(Op::Label(else_label));
+                } else {
+                    let end = self.label();
+                    self.push(Op::Jump { label: end.clone() });
+                    self.push(Op::Label(else_label));
+                    self.block(otherwise)?;
+                    self.push(Op::Label(end));
+                }
+            }
+            StmtKind::While(condition, body) => {
+                let top = self.label();
+                let end = self.label();
+                self.push(Op::Label(top.clone()));
+                self.branch(condition, &end, false)?;
+                self.block(body)?;
+                self.line = statement.line;
+                self.push(Op::Jump { label: top });
+                self.push(Op::Label(end));
+            }
+            StmtKind::Return(value) => {
+                let value = value.as_ref().map(|value| self.value(value)).transpose()?;
+                self.return_value(value);
+            }
+            StmtKind::Expr(value) => {
+                self.value(value)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Puts `value`, or 0, in `$0` and returns, or halts at the top level.
+    fn return_value(&mut self, value: Option<VReg>) {
+        let result = Reg::Fixed(RESULT_REGISTER);
+        match value {
+            Some(value) => self.push(Op::Move { src: Reg::Virtual(value), dst: result }),
+            None => self.push(Op::Load { dst: result, value: 0 }),
+        }
+        match self.return_address {
+            Some(return_address) => self.push(Op::Return { return_address: Reg::Virtual(return_address) }),
+            None => self.push(Op::Halt),
+        }
+    }
+
+    /// Evaluates `expr` into a register, which is the variable's own register
+    /// for a variable.
+    fn value(&mut self, expr: &Expr) -> Result<VReg, CompileError> {
+        match expr {
+            Expr::Number(value) => Ok(self.load(*value)),
+            Expr::Variable(name) => self.variable(name),
+            Expr::Unary(UnaryOp::Neg, operand) => {
+                if let Expr::Number(value) = **operand {
+                    return Ok(self.load(-value));
+                }
+                let operand = self.value(operand)?;
+                let zero = self.load(0);
+                Ok(self.binary(Opcode::SUB, zero, operand))
+            }
+            Expr::Call(name, args) => self.call(name, args),
+            Expr::Binary(op @ (BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div | BinaryOp::Rem), lhs, rhs) => {
+                let opcode = match op {
+                    BinaryOp::Add => Opcode::ADD,
+                    BinaryOp::Sub => Opcode::SUB,
+                    BinaryOp::Mul => Opcode::MUL,
+                    BinaryOp::Div => Opcode::DIV,
+                    _ => Opcode::REM,
+                };
+                let lhs = self.value(lhs)?;
+                let rhs = self.value(rhs)?;
+                Ok(self.binary(opcode, lhs, rhs))
+            }
+            _ => self.truth_value(expr),
+        }
+    }
+
+    fn load(&mut self, value: i64) -> VReg {
+        let dst = self.vreg();
+        self.push(Op::Load { dst: Reg::Virtual(dst), value });
+        dst
+    }
+
+    fn binary(&mut self, opcode: Opcode, lhs: VReg, rhs: VReg) -> VReg {
+        let dst = self.vreg();
+        self.push(Op::Binary { opcode, lhs: Reg::Virtual(lhs), rhs: Reg::Virtual(rhs), dst: Reg::Virtual(dst) });
+        dst
+    }
+
+    /// Evaluates a condition to 1 if it holds and 0 if not.
+    fn truth_value(&mut self, expr: &Expr) -> Result<VReg, CompileError> {
+        let dst = self.load(1);
+        let end = self.label();
+        self.branch(expr, &end, true)?;
+        self.push(Op::Load { dst: Reg::Virtual(dst), value: 0 });
+        self.push(Op::Label(end));
+        Ok(dst)
+    }
+
+    /// Jumps to `label` if `expr`'s truth is `when`, and falls through if
+    /// not.
+    fn branch(&mut self, expr: &Expr, label: &str, when: bool) -> Result<(), CompileError> {
+        match expr {
+            Expr::Number(value) => {
+                if (*value != 0) == when {
+                    self.push(Op::Jump { label: label.to_string() });
+                }
+            }
+            Expr::Unary(UnaryOp::Not, operand) => self.branch(operand, label, !when)?,
+            Expr::Binary(op @ (BinaryOp::And | BinaryOp::Or), lhs, rhs) => {
+                // Jumping when `a && b` is false, or `a || b` is true, only
+                // takes one of the operands; the other way round needs a label
+                // to skip the right operand.
+                if (*op == BinaryOp::And) != when {
+                    self.branch(lhs, label, when)?;
+                    self.branch(rhs, label, when)?;
+                } else {
+                    let skip = self.label();
+                    self.branch(lhs, &skip, !when)?;
+                    self.branch(rhs, label, when)?;
+                    self.push(Op::Label(skip));
+                }
+            }
+            Expr::Binary(op, lhs, rhs) if op.is_comparison() => {
+                let lhs = self.value(lhs)?;
+                let rhs = self.value(rhs)?;
+                let opcode = match (op, when) {
+                    (BinaryOp::Eq, true) | (BinaryOp::Ne, false) => Opcode::BEQ,
+                    (BinaryOp::Ne, true) | (BinaryOp::Eq, false) => Opcode::BNE,
+                    (BinaryOp::Lt, true) | (BinaryOp::Ge, false) => Opcode::BLT,
+                    (BinaryOp::Ge, true) | (BinaryOp::Lt, false) => Opcode::BGE,
+                    (BinaryOp::Gt, true) | (BinaryOp::Le, false) => Opcode::BGT,
+                    _ => Opcode::BLE,
+                };
+                self.push(Op::Branch {
+                    opcode,
+                    lhs: Reg::Virtual(lhs),
+                    rhs: Reg::Virtual(rhs),
+                    label: label.to_string(),
+                });
+            }
+            _ => {
+                let value = self.value(expr)?;
+                let zero = self.load(0);
+                let opcode = if when { Opcode::BNE } else { Opcode::BEQ };
+                self.push(Op::Branch {
+                    opcode,
+                    lhs: Reg::Virtual(value),
+                    rhs: Reg::Virtual(zero),
+                    label: label.to_string(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Calls the function `name`, or makes a SYSCALL, and returns the
+    /// register the result is copied into.
+    fn call(&mut self, name: &str, args: &[Expr]) -> Result<VReg, CompileError> {
+        let values = args.iter().map(|arg| self.value(arg)).collect::<Result<Vec<_>, _>>()?;
+        if name == SYSCALL {
+            if values.is_empty() || values.len() > ARGUMENT_REGISTERS.len() + 1 {
+                return Err(self.error(format!(
+                    "`{}` takes a syscall number and up to {} arguments",
+                    SYSCALL,
+                    ARGUMENT_REGISTERS.len()
+                )));
+            }
+            for (value, register) in values[1..].iter().zip(ARGUMENT_REGISTERS) {
+                self.push(Op::Move { src: Reg::Virtual(*value), dst: Reg::Fixed(register) });
+            }
+            self.push(Op::Syscall { number: Reg::Virtual(values[0]) });
+        } else {
+            let &(function, arity) = self
+                .signatures
+                .get(name)
+                .ok_or_else(|| self.error(format!("undefined function `{}`", name)))?;
+            if arity != values.len() {
+                return Err(self.error(format!("`{}` takes {} arguments but is given {}", name, arity, values.len())));
+            }
+            for (vreg, value) in values.iter().enumerate() {
+                self.push(Op::Move { src: Reg::Virtual(*value), dst: Reg::Callee { function, vreg } });
+            }
+            let return_label = self.label();
+            self.push(Op::Call {
+                label: function_label(name),
+                return_address: Reg::Callee { function, vreg: arity },
+                return_label,
+            });
+            self.calls.push((function, self.line));
+        }
+        let dst = self.vreg();
+        self.push(Op::Move { src: Reg::Fixed(RESULT_REGISTER), dst: Reg::Virtual(dst) });
+        Ok(dst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::parser::parse;
+
+    fn lower_source(source: &str) -> Result<Vec<FunctionIr>, CompileError> {
+        lower(&parse(source)?)
+    }
+
+    #[test]
+    fn test_lower_function() {
+        let functions = lower_source("fn add(a, b) {\n    return a + b;\n}\nlet x = add(1, 2);\n").unwrap();
+        assert_eq!(functions.len(), 2);
+        assert_eq!(functions[0].calls, vec![(1, 4)]);
+        let add = &functions[1];
+        assert_eq!((add.name.as_str(), add.label.as_str(), add.vregs), ("add", "fn_add", 4));
+        let ops: Vec<&Op> = add.ops.iter().map(|(op, _)| op).collect();
+        assert_eq!(
+            ops[..4],
+            [
+                &Op::Entry { defs: vec![0, 1, 2] },
+                &Op::Binary { opcode: Opcode::ADD, lhs: Reg::Virtual(0), rhs: Reg::Virtual(1), dst: Reg::Virtual(3) },
+                &Op::Move { src: Reg::Virtual(3), dst: Reg::Fixed(0) },
+                &Op::Return { return_address: Reg::Virtual(2) },
+            ]
+        );
+        assert_eq!(add.ops[1].1, 2);
+
+        let call = functions[0].ops.iter().find(|(op, _)| matches!(op, Op::Call { .. })).unwrap();
+        assert_eq!(
+            call.0,
+            Op::Call {
+                label: "fn_add".to_string(),
+                return_address: Reg::Callee { function: 1, vreg: 2 },
+                return_label: "L1".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_lower_conditions() {
+        // `a || b` jumps to the else branch only when both are false.
+        let functions = lower_source("let a = 1;\nlet b = 2;\nif a < b || b == 3 { a = 0; }\n").unwrap();
+        let branches: Vec<&Op> = functions[0].ops.iter().map(|(op, _)| op).filter(|op| matches!(op, Op::Branch { .. })).collect();
+        assert!(matches!(branches[0], Op::Branch { opcode: Opcode::BLT, label, .. } if label == "L2"));
+        assert!(matches!(branches[1], Op::Branch { opcode: Opcode::BNE, label, .. } if label == "L1"));
+    }
+
+    #[test]
+    fn test_lower_errors() {
+        let error = |source| lower_source(source).unwrap_err().to_string();
+        assert_eq!(error("let x = 1;\ny = x;"), "line 2: undefined variable `y`");
+        assert_eq!(error("if 1 { let x = 1; }\nx = 2;"), "line 2: undefined variable `x`");
+        assert_eq!(error("f(1);"), "line 1: undefined function `f`");
+        assert_eq!(error("fn f(a) { return a; }\nf(1, 2);"), "line 2: `f` takes 1 arguments but is given 2");
+        assert_eq!(error("fn f() {}\nfn f() {}"), "line 2: function `f` is declared more than once");
+        assert_eq!(error("fn f(a, a) {}"), "line 1: parameter `a` is declared more than once");
+        assert_eq!(error("syscall(1, 2, 3, 4, 5, 6);"), "line 1: `syscall` takes a syscall number and up to 4 arguments");
+    }
+}
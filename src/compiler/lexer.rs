@@ -0,0 +1,125 @@
+//! `lexer` splits source into tokens. `//` starts a comment that runs to the
+//! end of the line. Character literals take the same escapes as the
+//! assembler's, such as `'\n'`.
+use crate::assembler::expressions::unescape;
+use crate::compiler::CompileError;
+
+/// The words that can't be used as names.
+pub const KEYWORDS: &[&str] = &["fn", "let", "if", "else", "while", "return"];
+
+/// The operators and punctuation, longest first so `==` is found before `=`.
+const SYMBOLS: &[&str] = &[
+    "==", "!=", "<=", ">=", "&&", "||", "+", "-", "*", "/", "%", "<", ">", "=", "!", "(", ")", "{", "}", ",", ";",
+];
+
+/// A token of source.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Token {
+    /// An integer literal, or a character literal such as `'a'`.
+    Number(i64),
+    /// A name or keyword.
+    Name(String),
+    /// An operator or punctuation.
+    Symbol(&'static str),
+}
+
+/// Splits `source` into tokens, each with the 1-based line it is on.
+pub fn tokenize(source: &str) -> Result<Vec<(Token, usize)>, CompileError> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = vec![];
+    let mut line = 1;
+    let mut pos = 0;
+    while pos < chars.len() {
+        let c = chars[pos];
+        let rest: String = chars[pos..chars.len().min(pos + 2)].iter().collect();
+        if c == '\n' {
+            line += 1;
+            pos += 1;
+        } else if c.is_whitespace() {
+            pos += 1;
+        } else if rest == "//" {
+            while pos < chars.len() && chars[pos] != '\n' {
+                pos += 1;
+            }
+        } else if c.is_ascii_digit() {
+            let start = pos;
+            while pos < chars.len() && chars[pos].is_ascii_alphanumeric() {
+                pos += 1;
+            }
+            let text: String = chars[start..pos].iter().collect();
+            // One past `i32::MAX` is let through, for the parser to accept
+            // after a `-`.
+            let value = text
+                .parse::<i64>()
+                .ok()
+                .filter(|&value| value <= -(i32::MIN as i64))
+                .ok_or_else(|| CompileError::new(line, format!("`{}` isn't an integer that fits in 32 bits", text)))?;
+            tokens.push((Token::Number(value), line));
+        } else if c == '_' || c.is_alphabetic() {
+            let start = pos;
+            while pos < chars.len() && (chars[pos] == '_' || chars[pos].is_alphanumeric()) {
+                pos += 1;
+            }
+            tokens.push((Token::Name(chars[start..pos].iter().collect()), line));
+        } else if c == '\'' {
+            let (value, len) = match chars.get(pos + 1) {
+                Some('\\') => match chars.get(pos + 2).copied().and_then(unescape) {
+                    Some(value) => (value, 4),
+                    None => return Err(CompileError::new(line, "unknown escape in character literal".to_string())),
+                },
+                Some(&value) if value != '\n' => (value, 3),
+                _ => ('\n', 0),
+            };
+            if len == 0 || chars.get(pos + len - 1) != Some(&'\'') {
+                return Err(CompileError::new(line, "character literals hold one character, like `'a'`".to_string()));
+            }
+            tokens.push((Token::Number(value as i64), line));
+            pos += len;
+        } else if let Some(symbol) = SYMBOLS.iter().find(|symbol| rest.starts_with(**symbol)) {
+            tokens.push((Token::Symbol(symbol), line));
+            pos += symbol.len();
+        } else {
+            return Err(CompileError::new(line, format!("unexpected character `{}`", c)));
+        }
+    }
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokenize() {
+        let tokens = tokenize("let x = 10; // ten\nx == 'a'").unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                (Token::Name("let".to_string()), 1),
+                (Token::Name("x".to_string()), 1),
+                (Token::Symbol("="), 1),
+                (Token::Number(10), 1),
+                (Token::Symbol(";"), 1),
+                (Token::Name("x".to_string()), 2),
+                (Token::Symbol("=="), 2),
+                (Token::Number(97), 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_escapes() {
+        let tokens: Vec<Token> = tokenize(r"'\n' '\t' '\0' '\\' '\''").unwrap().into_iter().map(|(token, _)| token).collect();
+        assert_eq!(tokens, [10, 9, 0, 92, 39].map(Token::Number));
+    }
+
+    #[test]
+    fn test_tokenize_errors() {
+        assert_eq!(tokenize("1\n2147483649").unwrap_err().to_string(), "line 2: `2147483649` isn't an integer that fits in 32 bits");
+        assert_eq!(tokenize("x = 1 $").unwrap_err().to_string(), "line 1: unexpected character `$`");
+        assert!(tokenize("'ab'").is_err());
+        assert!(tokenize(r"'\n").is_err());
+        assert_eq!(tokenize(r"'\q'").unwrap_err().to_string(), "line 1: unknown escape in character literal");
+        assert!(tokenize("12ab").is_err());
+    }
+}
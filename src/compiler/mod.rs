@@ -0,0 +1,200 @@
+//! `compiler` compiles a small language to bytecode for `iridescent
+//! compile`, so programs don't have to be written in assembly.
+//!
+//! ```text
+//! // Sums the squares below 10.
+//! fn square(x) {
+//!     return x * x;
+//! }
+//!
+//! let total = 0;
+//! let i = 0;
+//! while i < 10 {
+//!     total = total + square(i);
+//!     i = i + 1;
+//! }
+//! return total;
+//! ```
+//!
+//! Every value is a 32 bit integer. The statements outside functions run in
+//! order as the program, and a `return` there halts it with the value in
+//! `$0`. `syscall(number, args...)` calls a host service with up to four
+//! arguments and gives its result. Functions can call functions declared
+//! anywhere in the file, but not themselves: the VM has no memory for a call
+//! stack, so each function keeps its variables in registers of its own.
+//!
+//! Source is parsed into an `ast`, lowered to the `ir` over virtual
+//! registers, mapped onto the VM's registers by the `allocator`, written out
+//! as assembly by `codegen` and assembled.
+use std::error::Error;
+use std::fmt;
+
+use crate::assembler::{assemble_with_debug_info, formatter::format_source, AssembleError};
+use crate::debug_info::DebugInfo;
+use crate::verifier::decode;
+
+/// The syntax tree.
+pub mod ast;
+
+/// Splits source into tokens.
+pub mod lexer;
+
+/// Parses tokens into a syntax tree.
+pub mod parser;
+
+/// Lowers the syntax tree to operations on virtual registers.
+pub mod ir;
+
+/// Maps virtual registers onto the VM's registers.
+pub mod allocator;
+
+/// Writes out operations as assembly.
+pub mod codegen;
+
+/// An error produced while compiling source.
+#[derive(Debug, PartialEq, Clone)]
+pub struct CompileError {
+    /// The 1-based source line the error was found on.
+    pub line: usize,
+    /// A description of what went wrong.
+    pub message: String,
+}
+
+impl CompileError {
+    /// Returns an error on `line`.
+    pub fn new(line: usize, message: String) -> CompileError {
+        CompileError { line, message }
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for CompileError {}
+
+/// Compiles `source` to lines of assembly, each with the source line it came
+/// from.
+fn assembly(source: &str) -> Result<Vec<(String, usize)>, CompileError> {
+    let program = parser::parse(source)?;
+    let functions = ir::lower(&program)?;
+    let allocations: Vec<allocator::Allocation> = functions.iter().map(allocator::allocate).collect();
+    let bases = allocator::layout(&functions, &allocations)?;
+    Ok(codegen::generate(&functions, &allocations, &bases))
+}
+
+/// Returns an error for a problem the assembler found in generated assembly,
+/// at the source line the assembly came from.
+fn assemble_error(lines: &[(String, usize)], error: AssembleError) -> CompileError {
+    let line = lines.get(error.line.wrapping_sub(1)).map_or(0, |&(_, line)| line);
+    CompileError::new(line, error.message)
+}
+
+/// Compiles `source` to assembly source, laid out like `iridescent fmt`
+/// would.
+pub fn compile_to_assembly(source: &str) -> Result<String, CompileError> {
+    let lines = assembly(source)?;
+    let text: String = lines.iter().map(|(text, _)| format!("{}\n", text)).collect();
+    format_source(&text).map_err(|error| assemble_error(&lines, error))
+}
+
+/// Compiles `source` to bytecode, along with debug info mapping the bytecode
+/// back to lines of `file` and naming where each function starts. The top
+/// level statements start at offset 0, under the label `main`.
+pub fn compile(source: &str, file: &str) -> Result<(Vec<u8>, DebugInfo), CompileError> {
+    let lines = assembly(source)?;
+    let text: String = lines.iter().map(|(text, _)| format!("{}\n", text)).collect();
+    let (program, assembled) = assemble_with_debug_info(&text, "").map_err(|error| assemble_error(&lines, error))?;
+
+    let mut debug_info = DebugInfo::new(file);
+    for instruction in decode(&program).0 {
+        if let Some(&(_, line)) = assembled.line(instruction.offset).and_then(|line| lines.get(line - 1)) {
+            debug_info.add_line(instruction.offset, line);
+        }
+    }
+    for (label, offset) in assembled.labels() {
+        if label == "main" {
+            debug_info.add_label(label, *offset);
+        } else if let Some(name) = label.strip_prefix(ir::FUNCTION_LABEL_PREFIX) {
+            debug_info.add_label(name, *offset);
+        }
+    }
+    Ok((program, debug_info))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::VM;
+
+    /// Compiles and runs `source`, returning `$0` when it halts.
+    fn run(source: &str) -> i32 {
+        let (program, _) = compile(source, "prog.iri").unwrap();
+        let mut vm = VM::builder().program(program).build_verified().unwrap();
+        vm.run().unwrap();
+        vm.register(0)
+    }
+
+    #[test]
+    fn test_compile_arithmetic() {
+        assert_eq!(run("return 1 + 2 * 3 - 4;"), 3);
+        assert_eq!(run("return (1 + 2) * 3;"), 9);
+        assert_eq!(run("return -7 / 2 + -7 % 2;"), -4);
+        assert_eq!(run("let x = 5;\nreturn -x * 100000;"), -500_000);
+        assert_eq!(run("return 'a' + 1;"), 98);
+        assert_eq!(run("return 2147483647 + 1;"), i32::MIN);
+        assert_eq!(run("return -2147483648;"), i32::MIN);
+        assert_eq!(run("return '\\n' + '\\'';"), 49);
+    }
+
+    #[test]
+    fn test_compile_conditions() {
+        assert_eq!(run("let a = 3;\nlet b = 4;\nreturn (a < b) + (a == b) * 10 + (a != b) * 100;"), 101);
+        assert_eq!(run("let a = 0;\nreturn !a + !(a >= 0 && a <= 0) * 10 + (a > 1 || a < -1) * 100;"), 1);
+        let source = "let x = 15;\nif x > 10 && x < 20 {\n    return 1;\n} else if x == 5 {\n    return 2;\n}\nreturn 3;\n";
+        assert_eq!(run(source), 1);
+        assert_eq!(run(&source.replace("15", "5")), 2);
+        assert_eq!(run(&source.replace("15", "30")), 3);
+    }
+
+    #[test]
+    fn test_compile_loops_and_functions() {
+        let source = "// Sums the squares below 10.\nfn square(x) {\n    return x * x;\n}\n\nlet total = 0;\nlet i = 0;\nwhile i < 10 {\n    total = total + square(i);\n    i = i + 1;\n}\nreturn total;\n";
+        assert_eq!(run(source), 285);
+
+        let source = "fn gcd(a, b) {\n    while b != 0 {\n        let t = a % b;\n        a = b;\n        b = t;\n    }\n    return a;\n}\nfn lcm(a, b) {\n    return a / gcd(a, b) * b;\n}\nreturn lcm(gcd(84, 36), 10) + gcd(7, 5);\n";
+        assert_eq!(run(source), 61);
+
+        let source = "fn fib(n) {\n    let a = 0;\n    let b = 1;\n    while n > 0 {\n        let next = a + b;\n        a = b;\n        b = next;\n        n = n - 1;\n    }\n    return a;\n}\nfn nothing() {}\nnothing();\nreturn fib(20) + nothing();\n";
+        assert_eq!(run(source), 6765);
+    }
+
+    #[test]
+    fn test_compile_syscalls() {
+        let (program, _) = compile("let n = 40;\nreturn syscall(7, n, 2) + 1;", "prog.iri").unwrap();
+        let mut vm = VM::builder().program(program).syscall(7, |args| Ok(args[0] + args[1])).build();
+        vm.run().unwrap();
+        assert_eq!(vm.register(0), 43);
+    }
+
+    #[test]
+    fn test_compile_debug_info() {
+        let source = "fn f(x) {\n    return x + 1;\n}\nlet y = 1;\nreturn f(y);\n";
+        let (_, debug_info) = compile(source, "prog.iri").unwrap();
+        let f = debug_info.label_offset("f").unwrap();
+        assert_eq!(debug_info.label_offset("main"), Some(0));
+        assert_eq!(debug_info.describe(0), "main (prog.iri:4)");
+        assert_eq!(debug_info.describe(f), "f (prog.iri:2)");
+    }
+
+    #[test]
+    fn test_compile_to_assembly() {
+        let assembly = compile_to_assembly("fn f(x) {\n    return x + 1;\n}\nreturn f(2);\n").unwrap();
+        assert!(assembly.starts_with("main:"));
+        assert!(assembly.contains("\nfn_f:"));
+        assert_eq!(format_source(&assembly).unwrap(), assembly);
+        assert_eq!(compile_to_assembly("return y;").unwrap_err().to_string(), "line 1: undefined variable `y`");
+    }
+}
@@ -0,0 +1,430 @@
+//! `parser` builds a `Program` from tokens by recursive descent.
+//!
+//! Operators bind, from loosest to tightest: `||`, `&&`, the comparisons,
+//! `+ -`, `* / %`, then the unary `- !`. Comparisons can't be chained, so
+//! `a < b < c` is an error rather than a surprise.
+//!
+//! The parser and the passes after it recurse over the tree, so how deeply
+//! expressions and blocks can nest is limited to `MAX_DEPTH`, and deeper
+//! source is an error rather than a stack overflow.
+use crate::compiler::ast::{BinaryOp, Expr, Function, Program, Stmt, StmtKind, UnaryOp};
+use crate::compiler::lexer::{tokenize, Token, KEYWORDS};
+use crate::compiler::CompileError;
+
+/// How deeply expressions and blocks can nest. Each operator of a chain like
+/// `1 + 2 + 3` nests one deeper than the last.
+const MAX_DEPTH: usize = 256;
+
+/// Parses `source` into a `Program`.
+pub fn parse(source: &str) -> Result<Program, CompileError> {
+    let tokens = tokenize(source)?;
+    let mut parser = Parser { tokens, pos: 0, depth: 0 };
+    let mut program = Program::default();
+    while !parser.at_end() {
+        if parser.peek_name("fn") {
+            program.functions.push(parser.function()?);
+        } else {
+            program.body.push(parser.statement()?);
+        }
+    }
+    Ok(program)
+}
+
+/// A recursive descent parser over a list of tokens.
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn at_end(&self) -> bool {
+        self.pos >= self.tokens.len()
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(token, _)| token)
+    }
+
+    /// Returns the line of the next token, or of the last one at the end.
+    fn line(&self) -> usize {
+        self.tokens.get(self.pos).or_else(|| self.tokens.last()).map_or(1, |&(_, line)| line)
+    }
+
+    fn error(&self, message: String) -> CompileError {
+        CompileError::new(self.line(), message)
+    }
+
+    /// Describes the next token for an error message.
+    fn found(&self) -> String {
+        match self.peek() {
+            Some(Token::Number(value)) => format!("`{}`", value),
+            Some(Token::Name(name)) => format!("`{}`", name),
+            Some(Token::Symbol(symbol)) => format!("`{}`", symbol),
+            None => "the end of the file".to_string(),
+        }
+    }
+
+    /// Goes one level deeper into the tree, failing past `MAX_DEPTH`. The
+    /// caller restores `depth` once it is done.
+    fn nest(&mut self) -> Result<(), CompileError> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(self.error(format!("code is nested more than {} levels deep", MAX_DEPTH)));
+        }
+        Ok(())
+    }
+
+    fn peek_name(&self, name: &str) -> bool {
+        matches!(self.peek(), Some(Token::Name(n)) if n == name)
+    }
+
+    fn peek_symbol(&self, symbol: &str) -> bool {
+        matches!(self.peek(), Some(Token::Symbol(s)) if *s == symbol)
+    }
+
+    /// Consumes `symbol` if it is next.
+    fn eat(&mut self, symbol: &str) -> bool {
+        let found = self.peek_symbol(symbol);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<(), CompileError> {
+        if self.eat(symbol) {
+            Ok(())
+        } else {
+            Err(self.error(format!("expected `{}` but found {}", symbol, self.found())))
+        }
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), CompileError> {
+        if self.peek_name(keyword) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(self.error(format!("expected `{}` but found {}", keyword, self.found())))
+        }
+    }
+
+    /// Consumes a name that isn't a keyword.
+    fn name(&mut self) -> Result<String, CompileError> {
+        match self.peek() {
+            Some(Token::Name(name)) if !KEYWORDS.contains(&name.as_str()) => {
+                let name = name.clone();
+                self.pos += 1;
+                Ok(name)
+            }
+            _ => Err(self.error(format!("expected a name but found {}", self.found()))),
+        }
+    }
+
+    fn function(&mut self) -> Result<Function, CompileError> {
+        let line = self.line();
+        self.expect_keyword("fn")?;
+        let name = self.name()?;
+        self.expect("(")?;
+        let mut params = vec![];
+        if !self.eat(")") {
+            loop {
+                params.push(self.name()?);
+                if self.eat(")") {
+                    break;
+                }
+                self.expect(",")?;
+            }
+        }
+        let body = self.block()?;
+        Ok(Function { line, name, params, body })
+    }
+
+    fn block(&mut self) -> Result<Vec<Stmt>, CompileError> {
+        self.expect("{")?;
+        self.nest()?;
+        let mut statements = vec![];
+        while !self.eat("}") {
+            if self.at_end() {
+                return Err(self.error("expected `}` but found the end of the file".to_string()));
+            }
+            statements.push(self.statement()?);
+        }
+        self.depth -= 1;
+        Ok(statements)
+    }
+
+    fn statement(&mut self) -> Result<Stmt, CompileError> {
+        let line = self.line();
+        let kind = if self.peek_name("fn") {
+            return Err(self.error("functions can only be declared at the top level".to_string()));
+        } else if self.peek_name("let") {
+            self.pos += 1;
+            let name = self.name()?;
+            self.expect("=")?;
+            let value = self.expression()?;
+            self.expect(";")?;
+            StmtKind::Let(name, value)
+        } else if self.peek_name("if") {
+            self.if_statement()?
+        } else if self.peek_name("while") {
+            self.pos += 1;
+            let condition = self.expression()?;
+            StmtKind::While(condition, self.block()?)
+        } else if self.peek_name("return") {
+            self.pos += 1;
+            let value = if self.peek_symbol(";") { None } else { Some(self.expression()?) };
+            self.expect(";")?;
+            StmtKind::Return(value)
+        } else if matches!(self.tokens.get(self.pos + 1), Some((Token::Symbol("="), _))) {
+            let name = self.name()?;
+            self.pos += 1;
+            let value = self.expression()?;
+            self.expect(";")?;
+            StmtKind::Assign(name, value)
+        } else {
+            let value = self.expression()?;
+            self.expect(";")?;
+            StmtKind::Expr(value)
+        };
+        Ok(Stmt { line, kind })
+    }
+
+    fn if_statement(&mut self) -> Result<StmtKind, CompileError> {
+        self.expect_keyword("if")?;
+        let condition = self.expression()?;
+        let then = self.block()?;
+        let otherwise = if !self.peek_name("else") {
+            vec![]
+        } else {
+            self.pos += 1;
+            if self.peek_name("if") {
+                let line = self.line();
+                self.nest()?;
+                let kind = self.if_statement()?;
+                self.depth -= 1;
+                vec![Stmt { line, kind }]
+            } else {
+                self.block()?
+            }
+        };
+        Ok(StmtKind::If(condition, then, otherwise))
+    }
+
+    fn expression(&mut self) -> Result<Expr, CompileError> {
+        self.nest()?;
+        let value = self.or()?;
+        self.depth -= 1;
+        Ok(value)
+    }
+
+    fn or(&mut self) -> Result<Expr, CompileError> {
+        let depth = self.depth;
+        let mut left = self.and()?;
+        while self.eat("||") {
+            self.nest()?;
+            left = Expr::Binary(BinaryOp::Or, Box::new(left), Box::new(self.and()?));
+        }
+        self.depth = depth;
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<Expr, CompileError> {
+        let depth = self.depth;
+        let mut left = self.comparison()?;
+        while self.eat("&&") {
+            self.nest()?;
+            left = Expr::Binary(BinaryOp::And, Box::new(left), Box::new(self.comparison()?));
+        }
+        self.depth = depth;
+        Ok(left)
+    }
+
+    fn comparison(&mut self) -> Result<Expr, CompileError> {
+        let left = self.sum()?;
+        let op = match self.peek() {
+            Some(Token::Symbol("==")) => BinaryOp::Eq,
+            Some(Token::Symbol("!=")) => BinaryOp::Ne,
+            Some(Token::Symbol("<")) => BinaryOp::Lt,
+            Some(Token::Symbol("<=")) => BinaryOp::Le,
+            Some(Token::Symbol(">")) => BinaryOp::Gt,
+            Some(Token::Symbol(">=")) => BinaryOp::Ge,
+            _ => return Ok(left),
+        };
+        self.pos += 1;
+        let right = self.sum()?;
+        if matches!(self.peek(), Some(Token::Symbol("==" | "!=" | "<" | "<=" | ">" | ">="))) {
+            return Err(self.error("comparisons can't be chained; join them with `&&`".to_string()));
+        }
+        Ok(Expr::Binary(op, Box::new(left), Box::new(right)))
+    }
+
+    fn sum(&mut self) -> Result<Expr, CompileError> {
+        let depth = self.depth;
+        let mut left = self.product()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Symbol("+")) => BinaryOp::Add,
+                Some(Token::Symbol("-")) => BinaryOp::Sub,
+                _ => break,
+            };
+            self.pos += 1;
+            self.nest()?;
+            left = Expr::Binary(op, Box::new(left), Box::new(self.product()?));
+        }
+        self.depth = depth;
+        Ok(left)
+    }
+
+    fn product(&mut self) -> Result<Expr, CompileError> {
+        let depth = self.depth;
+        let mut left = self.unary()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Symbol("*")) => BinaryOp::Mul,
+                Some(Token::Symbol("/")) => BinaryOp::Div,
+                Some(Token::Symbol("%")) => BinaryOp::Rem,
+                _ => break,
+            };
+            self.pos += 1;
+            self.nest()?;
+            left = Expr::Binary(op, Box::new(left), Box::new(self.unary()?));
+        }
+        self.depth = depth;
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expr, CompileError> {
+        let op = if self.eat("-") {
+            // `i32::MIN` has no positive literal to negate, so it is read
+            // whole.
+            if matches!(self.peek(), Some(&Token::Number(value)) if value == -(i32::MIN as i64)) {
+                self.pos += 1;
+                return Ok(Expr::Number(i32::MIN as i64));
+            }
+            UnaryOp::Neg
+        } else if self.eat("!") {
+            UnaryOp::Not
+        } else {
+            return self.primary();
+        };
+        self.nest()?;
+        let value = self.unary()?;
+        self.depth -= 1;
+        Ok(Expr::Unary(op, Box::new(value)))
+    }
+
+    fn primary(&mut self) -> Result<Expr, CompileError> {
+        match self.peek() {
+            Some(Token::Number(value)) if *value > i32::MAX as i64 => {
+                Err(self.error(format!("`{}` isn't an integer that fits in 32 bits", value)))
+            }
+            Some(Token::Number(value)) => {
+                let value = *value;
+                self.pos += 1;
+                Ok(Expr::Number(value))
+            }
+            Some(Token::Symbol("(")) => {
+                self.pos += 1;
+                let value = self.expression()?;
+                self.expect(")")?;
+                Ok(value)
+            }
+            Some(Token::Name(_)) => {
+                let name = self.name()?;
+                if !self.eat("(") {
+                    return Ok(Expr::Variable(name));
+                }
+                let mut args = vec![];
+                if !self.eat(")") {
+                    loop {
+                        args.push(self.expression()?);
+                        if self.eat(")") {
+                            break;
+                        }
+                        self.expect(",")?;
+                    }
+                }
+                Ok(Expr::Call(name, args))
+            }
+            _ => Err(self.error(format!("expected an expression but found {}", self.found()))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn number(value: i64) -> Box<Expr> {
+        Box::new(Expr::Number(value))
+    }
+
+    fn variable(name: &str) -> Box<Expr> {
+        Box::new(Expr::Variable(name.to_string()))
+    }
+
+    #[test]
+    fn test_parse_precedence() {
+        let program = parse("x = 1 + 2 * -y < 3 || !z && f(4, 5);").unwrap();
+        let sum = Expr::Binary(
+            BinaryOp::Add,
+            number(1),
+            Box::new(Expr::Binary(BinaryOp::Mul, number(2), Box::new(Expr::Unary(UnaryOp::Neg, variable("y"))))),
+        );
+        let comparison = Expr::Binary(BinaryOp::Lt, Box::new(sum), number(3));
+        let call = Expr::Call("f".to_string(), vec![Expr::Number(4), Expr::Number(5)]);
+        let and = Expr::Binary(BinaryOp::And, Box::new(Expr::Unary(UnaryOp::Not, variable("z"))), Box::new(call));
+        let value = Expr::Binary(BinaryOp::Or, Box::new(comparison), Box::new(and));
+        assert_eq!(program.body, vec![Stmt { line: 1, kind: StmtKind::Assign("x".to_string(), value) }]);
+    }
+
+    #[test]
+    fn test_parse_statements() {
+        let source = "fn double(n) {\n    return n * 2;\n}\nlet i = 0;\nwhile i < 3 {\n    if i == 1 { i = 5; } else if i == 2 { return; } else { double(i); }\n}\n";
+        let program = parse(source).unwrap();
+        assert_eq!(program.functions.len(), 1);
+        let function = &program.functions[0];
+        assert_eq!((function.line, function.name.as_str(), &function.params[..]), (1, "double", &["n".to_string()][..]));
+        assert_eq!(program.body.len(), 2);
+        assert_eq!(program.body[1].line, 5);
+        let body = match &program.body[1].kind {
+            StmtKind::While(_, body) => body,
+            kind => panic!("expected a while loop, found {:?}", kind),
+        };
+        match &body[0].kind {
+            StmtKind::If(_, then, otherwise) => {
+                assert_eq!(then.len(), 1);
+                assert_eq!(otherwise.len(), 1);
+                assert!(matches!(&otherwise[0].kind, StmtKind::If(_, _, last) if last.len() == 1));
+            }
+            kind => panic!("expected an if statement, found {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = |source| parse(source).unwrap_err().to_string();
+        assert_eq!(error("let x = 1\nlet y = 2;"), "line 2: expected `;` but found `let`");
+        assert_eq!(error("let while = 1;"), "line 1: expected a name but found `while`");
+        assert_eq!(error("if x { fn f() {} }"), "line 1: functions can only be declared at the top level");
+        assert_eq!(error("x = a < b < c;"), "line 1: comparisons can't be chained; join them with `&&`");
+        assert_eq!(error("while 1 {\n"), "line 1: expected `}` but found the end of the file");
+        assert_eq!(error("x = (1 + ;"), "line 1: expected an expression but found `;`");
+        assert_eq!(error("return 2147483648;"), "line 1: `2147483648` isn't an integer that fits in 32 bits");
+    }
+
+    #[test]
+    fn test_parse_depth_limit() {
+        let error = |source: String| parse(&source).unwrap_err().to_string();
+        let too_deep = "line 1: code is nested more than 256 levels deep";
+        assert_eq!(error(format!("return {}1{};", "(".repeat(5000), ")".repeat(5000))), too_deep);
+        assert_eq!(error(format!("return {}1;", "-".repeat(5000))), too_deep);
+        assert_eq!(error(format!("return 1{};", " + 1".repeat(5000))), too_deep);
+        assert_eq!(error(format!("{}{}", "while 1 { ".repeat(5000), "}".repeat(5000))), too_deep);
+
+        let nested = format!("return {}1{} + {}1;", "(".repeat(100), ")".repeat(100), "-".repeat(100));
+        assert!(parse(&nested).is_ok());
+        assert_eq!(parse("return -2147483648;").unwrap().body[0].kind, StmtKind::Return(Some(Expr::Number(-2147483648))));
+    }
+}
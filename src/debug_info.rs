@@ -0,0 +1,288 @@
+//! `debug_info` maps bytecode offsets back to the source they were assembled
+//! from, so tools can print `loop+4 (prog.iasm:12)` instead of a raw offset.
+//!
+//! Debug info can be written next to a bytecode file as a text sidecar:
+//!
+//! ```text
+//! iridescent-debug 1
+//! file prog.iasm
+//! file lib/io.iasm
+//! label loop 16
+//! line 16 12
+//! line 20 3 1
+//! ```
+//!
+//! The first `file` line names the file that was assembled, and any more
+//! name files it included, numbered from 1. Each `label` line gives a label
+//! name and its offset, and each `line` line gives the offset of an
+//! instruction, the source line it came from and, for an included file, that
+//! file's number.
+use std::error::Error;
+use std::fmt;
+
+/// The first line of every debug info sidecar.
+const SIDECAR_HEADER: &str = "iridescent-debug 1";
+
+/// Source locations and label names for a piece of bytecode.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct DebugInfo {
+    file: String,
+    /// Files other than `file` that lines came from, such as included ones.
+    included: Vec<String>,
+    /// The offset of each instruction, the file it came from, as 0 for
+    /// `file` or 1 plus an index into `included`, and its line there.
+    lines: Vec<(usize, usize, usize)>,
+    labels: Vec<(String, usize)>,
+}
+
+/// An error produced while reading a debug info sidecar.
+#[derive(Debug, PartialEq, Clone)]
+pub struct DebugInfoError {
+    /// The 1-based sidecar line the error was found on.
+    pub line: usize,
+    /// A description of what went wrong.
+    pub message: String,
+}
+
+impl fmt::Display for DebugInfoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for DebugInfoError {}
+
+impl DebugInfo {
+    /// Returns empty debug info for bytecode assembled from `file`.
+    pub fn new(file: &str) -> DebugInfo {
+        DebugInfo {
+            file: file.to_string(),
+            included: vec![],
+            lines: vec![],
+            labels: vec![],
+        }
+    }
+
+    /// Records that the instruction at `offset` came from source line `line`.
+    pub fn add_line(&mut self, offset: usize, line: usize) {
+        self.insert_line(offset, 0, line);
+    }
+
+    /// Records that the instruction at `offset` came from line `line` of
+    /// `file`, which may be a file other than the one assembled.
+    pub fn add_line_in(&mut self, offset: usize, file: &str, line: usize) {
+        let index = if file == self.file {
+            0
+        } else {
+            match self.included.iter().position(|included| included == file) {
+                Some(index) => index + 1,
+                None => {
+                    self.included.push(file.to_string());
+                    self.included.len()
+                }
+            }
+        };
+        self.insert_line(offset, index, line);
+    }
+
+    fn insert_line(&mut self, offset: usize, file: usize, line: usize) {
+        let index = self.lines.partition_point(|&(o, _, _)| o <= offset);
+        self.lines.insert(index, (offset, file, line));
+    }
+
+    /// Records that `name` labels `offset`.
+    pub fn add_label(&mut self, name: &str, offset: usize) {
+        let index = self.labels.partition_point(|(_, o)| *o <= offset);
+        self.labels.insert(index, (name.to_string(), offset));
+    }
+
+    /// Returns the name of the source file.
+    pub fn file(&self) -> &str {
+        &self.file
+    }
+
+    /// Returns every label with its offset, ordered by offset.
+    pub fn labels(&self) -> &[(String, usize)] {
+        &self.labels
+    }
+
+    /// Returns the offset `name` labels.
+    pub fn label_offset(&self, name: &str) -> Option<usize> {
+        self.labels.iter().find(|(label, _)| label == name).map(|&(_, offset)| offset)
+    }
+
+    /// Returns the source line of the instruction containing `offset`.
+    pub fn line(&self, offset: usize) -> Option<usize> {
+        self.location(offset).map(|(_, line)| line)
+    }
+
+    /// Returns the file and line the instruction containing `offset` came
+    /// from.
+    pub fn location(&self, offset: usize) -> Option<(&str, usize)> {
+        let index = self.lines.partition_point(|&(o, _, _)| o <= offset);
+        index.checked_sub(1).map(|i| {
+            let (_, file, line) = self.lines[i];
+            let file = match file {
+                0 => &self.file,
+                n => &self.included[n - 1],
+            };
+            (file.as_str(), line)
+        })
+    }
+
+    /// Returns the nearest label at or before `offset`, and how far past it
+    /// `offset` is.
+    pub fn label(&self, offset: usize) -> Option<(&str, usize)> {
+        let index = self.labels.partition_point(|(_, o)| *o <= offset);
+        index
+            .checked_sub(1)
+            .map(|i| (self.labels[i].0.as_str(), offset - self.labels[i].1))
+    }
+
+    /// Describes `offset` as `label+delta (file:line)`, leaving out whichever
+    /// parts are unknown.
+    pub fn describe(&self, offset: usize) -> String {
+        let mut description = match self.label(offset) {
+            Some((label, 0)) => label.to_string(),
+            Some((label, delta)) => format!("{}+{}", label, delta),
+            None => offset.to_string(),
+        };
+        if let Some((file, line)) = self.location(offset) {
+            description.push_str(&format!(" ({}:{})", file, line));
+        }
+        description
+    }
+
+    /// Writes the debug info in the sidecar format described in the module docs.
+    pub fn to_sidecar(&self) -> String {
+        let mut sidecar = format!("{}\nfile {}\n", SIDECAR_HEADER, self.file);
+        for file in &self.included {
+            sidecar.push_str(&format!("file {}\n", file));
+        }
+        for (name, offset) in &self.labels {
+            sidecar.push_str(&format!("label {} {}\n", name, offset));
+        }
+        for (offset, file, line) in &self.lines {
+            match file {
+                0 => sidecar.push_str(&format!("line {} {}\n", offset, line)),
+                _ => sidecar.push_str(&format!("line {} {} {}\n", offset, line, file)),
+            }
+        }
+        sidecar
+    }
+
+    /// Reads debug info written by `to_sidecar`.
+    pub fn from_sidecar(sidecar: &str) -> Result<DebugInfo, DebugInfoError> {
+        let mut lines = sidecar.lines().enumerate();
+        match lines.next() {
+            Some((_, SIDECAR_HEADER)) => {}
+            _ => {
+                return Err(DebugInfoError {
+                    line: 1,
+                    message: "not an iridescent debug info sidecar".to_string(),
+                })
+            }
+        }
+
+        let mut info = DebugInfo::default();
+        let mut files = 0;
+        for (index, text) in lines {
+            let error = |message: &str| DebugInfoError {
+                line: index + 1,
+                message: message.to_string(),
+            };
+            let number = |field: Option<&str>| {
+                field
+                    .and_then(|field| field.parse::<usize>().ok())
+                    .ok_or_else(|| error("expected a number"))
+            };
+            let mut fields = text.split_whitespace();
+            match fields.next() {
+                Some("file") => {
+                    let file = text["file".len()..].trim().to_string();
+                    match files {
+                        0 => info.file = file,
+                        _ => info.included.push(file),
+                    }
+                    files += 1;
+                }
+                Some("label") => {
+                    let name = fields.next().ok_or_else(|| error("expected a label name"))?;
+                    let offset = number(fields.next())?;
+                    info.add_label(name, offset);
+                }
+                Some("line") => {
+                    let offset = number(fields.next())?;
+                    let line = number(fields.next())?;
+                    let file = match fields.next() {
+                        Some(field) => number(Some(field))?,
+                        None => 0,
+                    };
+                    if file > info.included.len() {
+                        return Err(error("unknown file number"));
+                    }
+                    info.insert_line(offset, file, line);
+                }
+                None => {}
+                Some(other) => return Err(error(&format!("unknown entry `{}`", other))),
+            }
+        }
+        Ok(info)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn debug_info() -> DebugInfo {
+        let mut info = DebugInfo::new("prog.iasm");
+        info.add_label("start", 0);
+        info.add_label("loop", 8);
+        info.add_line(0, 1);
+        info.add_line(4, 2);
+        info.add_line(8, 4);
+        info.add_line(12, 5);
+        info
+    }
+
+    #[test]
+    fn test_describe() {
+        let info = debug_info();
+        assert_eq!(info.describe(0), "start (prog.iasm:1)");
+        assert_eq!(info.describe(12), "loop+4 (prog.iasm:5)");
+        assert_eq!(info.line(13), Some(5));
+        assert_eq!(DebugInfo::new("x").describe(7), "7");
+    }
+
+    #[test]
+    fn test_sidecar_round_trip() {
+        let info = debug_info();
+        let sidecar = info.to_sidecar();
+        assert_eq!(DebugInfo::from_sidecar(&sidecar), Ok(info));
+    }
+
+    #[test]
+    fn test_included_files() {
+        let mut info = debug_info();
+        info.add_line_in(16, "lib/io.iasm", 3);
+        info.add_line_in(20, "prog.iasm", 7);
+        assert_eq!(info.describe(16), "loop+8 (lib/io.iasm:3)");
+        assert_eq!(info.location(20), Some(("prog.iasm", 7)));
+
+        let sidecar = info.to_sidecar();
+        assert!(sidecar.contains("file lib/io.iasm\n"));
+        assert!(sidecar.contains("line 16 3 1\n"));
+        assert_eq!(DebugInfo::from_sidecar(&sidecar), Ok(info));
+
+        let error = DebugInfo::from_sidecar("iridescent-debug 1\nfile a.iasm\nline 0 4 1\n").unwrap_err();
+        assert_eq!(error, DebugInfoError { line: 3, message: "unknown file number".to_string() });
+    }
+
+    #[test]
+    fn test_sidecar_errors() {
+        assert_eq!(DebugInfo::from_sidecar("hello").unwrap_err().line, 1);
+        let error = DebugInfo::from_sidecar("iridescent-debug 1\nline x 1\n").unwrap_err();
+        assert_eq!(error, DebugInfoError { line: 2, message: "expected a number".to_string() });
+    }
+}
@@ -1,67 +1,179 @@
-/// `instruction` provides the 
-#[derive(PartialEq, Debug, Clone, Copy)]
+//! `instruction` provides the opcodes understood by the VM, and the
+//! `Instruction` type wrapping them.
+/// The operations understood by the VM. An opcode's position in this enum is
+/// the byte it is encoded as.
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
 pub enum Opcode {
-    
-    /// LOAD $0 $1: Loads the value of b into register $0.
-    LOAD, 
-    
+
+    /// LOAD $0 #1: Loads the integer 1 into register $0.
+    LOAD,
+
     /// ADD $0 $1 $2: Stores the sum of $0 and $1 into register $2.
-    ADD, 
-    
+    ADD,
+
     /// SUB $0 $1 $2: Stores the difference of $0 and $1 into register $2.
-    SUB, 
-    
+    SUB,
+
     /// MUL $0 $1 $2: Stores the product of $0 and $1 into register $2.
-    MUL, 
-    
-    /// DIV $0 $1 $2: Stores the product of $0 and $1 into register $2.
-    DIV, 
-    
-    /// Stops execution of current instruction.
-    HLT, 
-    
+    MUL,
+
+    /// DIV $0 $1 $2: Stores the quotient of $0 and $1 into register $2, keeping
+    /// the remainder for MFR.
+    DIV,
+
+    /// HLT: Stops the program.
+    HLT,
+
     /// JMP $0: Sets the program counter to $0, continuing execution from there.
-    JMP, 
-    
+    JMP,
+
     /// JMPF $0: Sets the program counter to pc + $0, continuing execution from there.
     JMPF,
-    
-    /// JMPB $0: JMPF $0: Sets the program counter to pc - $0, continuing execution from there.
-    JMPB, 
-    
-    /// EQ $0 $1 $2: Compares the values of $0 and $2, setting `VM.equal_flag` to the results of the comparison.
-    EQ, 
-    
-    /// NEQ $0 $1 $2: Compares the values of $0 and $2, setting `VM.equal_flag` to the results of the comparison.
-    NEQ, 
-    
+
+    /// JMPB $0: Sets the program counter to pc - $0, continuing execution from there.
+    JMPB,
+
+    /// EQ $0 $1 $2: Sets `VM.equal_flag` if $0 equals $1.
+    EQ,
+
+    /// NEQ $0 $1 $2: Sets `VM.equal_flag` if $0 does not equal $1.
+    NEQ,
+
+    /// GT $0 $1 $2: Sets `VM.equal_flag` if $0 is greater than $1.
     GT,
-    
+
+    /// LT $0 $1 $2: Sets `VM.equal_flag` if $0 is less than $1.
     LT,
-    
+
+    /// GTQ $0 $1 $2: Sets `VM.equal_flag` if $0 is greater than or equal to $1.
     GTQ,
-    
+
+    /// LTQ $0 $1 $2: Sets `VM.equal_flag` if $0 is less than or equal to $1.
     LTQ,
-    
+
+    /// JEQ $0: Sets the program counter to $0 if `VM.equal_flag` is set.
     JEQ,
-    
+
+    /// JNEQ $0: Sets the program counter to $0 if `VM.equal_flag` is not set.
     JNEQ,
-    
-    /// Sends a request for an interrupt to the processor.
-    IGL, 
+
+    /// SYSCALL $0: Calls the host service numbered $0, passing $1-$4 as
+    /// arguments and storing its result in $0.
+    SYSCALL,
+
+    /// SEND $0 $1: Queues the value of $1 as a message to the VM whose id is in $0.
+    SEND,
+
+    /// RECV $0: Moves the oldest message in the mailbox into $0, blocking
+    /// while the mailbox is empty.
+    RECV,
+
+    /// GTU $0 $1 $2: Sets `VM.equal_flag` if $0 is greater than $1, comparing them as unsigned.
+    GTU,
+
+    /// LTU $0 $1 $2: Sets `VM.equal_flag` if $0 is less than $1, comparing them as unsigned.
+    LTU,
+
+    /// GEU $0 $1 $2: Sets `VM.equal_flag` if $0 is greater than or equal to $1, comparing them as unsigned.
+    GEU,
+
+    /// LEU $0 $1 $2: Sets `VM.equal_flag` if $0 is less than or equal to $1, comparing them as unsigned.
+    LEU,
+
+    /// BEQ $0 $1 $2: Sets the program counter to $2 if $0 equals $1.
+    BEQ,
+
+    /// BNE $0 $1 $2: Sets the program counter to $2 if $0 does not equal $1.
+    BNE,
+
+    /// BGT $0 $1 $2: Sets the program counter to $2 if $0 is greater than $1.
+    BGT,
+
+    /// BLT $0 $1 $2: Sets the program counter to $2 if $0 is less than $1.
+    BLT,
+
+    /// BGE $0 $1 $2: Sets the program counter to $2 if $0 is greater than or equal to $1.
+    BGE,
+
+    /// BLE $0 $1 $2: Sets the program counter to $2 if $0 is less than or equal to $1.
+    BLE,
+
+    /// BGTU $0 $1 $2: Sets the program counter to $2 if $0 is greater than $1, comparing them as unsigned.
+    BGTU,
+
+    /// BLTU $0 $1 $2: Sets the program counter to $2 if $0 is less than $1, comparing them as unsigned.
+    BLTU,
+
+    /// BGEU $0 $1 $2: Sets the program counter to $2 if $0 is greater than or equal to $1, comparing them as unsigned.
+    BGEU,
+
+    /// BLEU $0 $1 $2: Sets the program counter to $2 if $0 is less than or equal to $1, comparing them as unsigned.
+    BLEU,
+
+    /// JEQF $0: Sets the program counter to pc + $0 if `VM.equal_flag` is set.
+    JEQF,
+
+    /// JEQB $0: Sets the program counter to pc - $0 if `VM.equal_flag` is set.
+    JEQB,
+
+    /// JNEQF $0: Sets the program counter to pc + $0 if `VM.equal_flag` is not set.
+    JNEQF,
+
+    /// JNEQB $0: Sets the program counter to pc - $0 if `VM.equal_flag` is not set.
+    JNEQB,
+
+    /// REM $0 $1 $2: Stores the remainder of $0 divided by $1 into register $2.
+    /// The remainder has the sign of $0.
+    REM,
+
+    /// MFR $0: Moves the remainder left by the most recent DIV into $0.
+    MFR,
+
+    /// MOV $0 $1: Copies the value of $0 into register $1.
+    MOV,
+
+    /// SWAP $0 $1: Exchanges the values of $0 and $1.
+    SWAP,
+
+    /// LOADX $0 $1: Copies the value of the register whose index is in $0 into register $1.
+    LOADX,
+
+    /// INC $0: Adds one to $0.
+    INC,
+
+    /// DEC $0: Subtracts one from $0.
+    DEC,
+
+    /// ADDI $0 #1 $2: Stores the sum of $0 and the integer 1 into register $2.
+    ADDI,
+
+    /// SUBI $0 #1 $2: Stores the difference of $0 and the integer 1 into register $2.
+    SUBI,
+
+    /// MULI $0 #1 $2: Stores the product of $0 and the integer 1 into register $2.
+    MULI,
+
+    /// IGL: Stands for any byte that isn't an opcode. The VM stops if it reaches one.
+    IGL,
 }
 
-pub struct Instruction {
-    /// An instruction is a group of 32 bits, the first 8 of which, will be
-    /// an opcode, and the remaining ones will be up to three operands.
-    pub opcode: Opcode
+/// The kinds of operand encoded after an opcode.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Operand {
+    /// A single byte naming one of the 32 registers.
+    Register,
+    /// A 16 bit big-endian integer.
+    Integer,
+    /// A byte the VM reads past without using.
+    Padding,
 }
 
-impl Instruction {
-    /// Returns a new instance of an opcode.
-    pub fn new(opcode: Opcode) -> Instruction {
-        Instruction {
-            opcode: opcode
+impl Operand {
+    /// Returns how many bytes the operand takes up.
+    pub fn width(self) -> usize {
+        match self {
+            Operand::Integer => 2,
+            Operand::Register | Operand::Padding => 1,
         }
     }
 }
@@ -70,32 +182,321 @@ impl From<u8> for Opcode {
     /// Allows for Opcode to be understood by the parser as an integer.
     fn from(v: u8) -> Self {
         match v {
-            0 => return Opcode::LOAD,
-            1 => return Opcode::ADD,
-            2 => return Opcode::SUB,
-            3 => return Opcode::MUL,
-            4 => return Opcode::DIV,
-            5 => return Opcode::HLT,
-            6 => return Opcode::JMP,
-            7 => return Opcode::JMPF,
-            8 => return Opcode::JMPB,
-            9 => return Opcode::EQ,
-            10 => return Opcode::NEQ,
-            11 => return Opcode::GT,
-            12 => return Opcode::LT,
-            13 => return Opcode::GTQ,
-            14 => return Opcode::LTQ,
-            15 => return Opcode::JEQ,
-            16 => return Opcode::JNEQ,
-            _ => return Opcode::IGL,
+            0 => Opcode::LOAD,
+            1 => Opcode::ADD,
+            2 => Opcode::SUB,
+            3 => Opcode::MUL,
+            4 => Opcode::DIV,
+            5 => Opcode::HLT,
+            6 => Opcode::JMP,
+            7 => Opcode::JMPF,
+            8 => Opcode::JMPB,
+            9 => Opcode::EQ,
+            10 => Opcode::NEQ,
+            11 => Opcode::GT,
+            12 => Opcode::LT,
+            13 => Opcode::GTQ,
+            14 => Opcode::LTQ,
+            15 => Opcode::JEQ,
+            16 => Opcode::JNEQ,
+            17 => Opcode::SYSCALL,
+            18 => Opcode::SEND,
+            19 => Opcode::RECV,
+            20 => Opcode::GTU,
+            21 => Opcode::LTU,
+            22 => Opcode::GEU,
+            23 => Opcode::LEU,
+            24 => Opcode::BEQ,
+            25 => Opcode::BNE,
+            26 => Opcode::BGT,
+            27 => Opcode::BLT,
+            28 => Opcode::BGE,
+            29 => Opcode::BLE,
+            30 => Opcode::BGTU,
+            31 => Opcode::BLTU,
+            32 => Opcode::BGEU,
+            33 => Opcode::BLEU,
+            34 => Opcode::JEQF,
+            35 => Opcode::JEQB,
+            36 => Opcode::JNEQF,
+            37 => Opcode::JNEQB,
+            38 => Opcode::REM,
+            39 => Opcode::MFR,
+            40 => Opcode::MOV,
+            41 => Opcode::SWAP,
+            42 => Opcode::LOADX,
+            43 => Opcode::INC,
+            44 => Opcode::DEC,
+            45 => Opcode::ADDI,
+            46 => Opcode::SUBI,
+            47 => Opcode::MULI,
+            _ => Opcode::IGL,
         }
     }
 }
 
+impl Opcode {
+    /// Returns the lowercase mnemonic used for this opcode in assembly.
+    pub fn mnemonic(self) -> &'static str {
+        match self {
+            Opcode::LOAD => "load",
+            Opcode::ADD => "add",
+            Opcode::SUB => "sub",
+            Opcode::MUL => "mul",
+            Opcode::DIV => "div",
+            Opcode::HLT => "hlt",
+            Opcode::JMP => "jmp",
+            Opcode::JMPF => "jmpf",
+            Opcode::JMPB => "jmpb",
+            Opcode::EQ => "eq",
+            Opcode::NEQ => "neq",
+            Opcode::GT => "gt",
+            Opcode::LT => "lt",
+            Opcode::GTQ => "gtq",
+            Opcode::LTQ => "ltq",
+            Opcode::JEQ => "jeq",
+            Opcode::JNEQ => "jneq",
+            Opcode::SYSCALL => "syscall",
+            Opcode::SEND => "send",
+            Opcode::RECV => "recv",
+            Opcode::GTU => "gtu",
+            Opcode::LTU => "ltu",
+            Opcode::GEU => "geu",
+            Opcode::LEU => "leu",
+            Opcode::BEQ => "beq",
+            Opcode::BNE => "bne",
+            Opcode::BGT => "bgt",
+            Opcode::BLT => "blt",
+            Opcode::BGE => "bge",
+            Opcode::BLE => "ble",
+            Opcode::BGTU => "bgtu",
+            Opcode::BLTU => "bltu",
+            Opcode::BGEU => "bgeu",
+            Opcode::BLEU => "bleu",
+            Opcode::JEQF => "jeqf",
+            Opcode::JEQB => "jeqb",
+            Opcode::JNEQF => "jneqf",
+            Opcode::JNEQB => "jneqb",
+            Opcode::REM => "rem",
+            Opcode::MFR => "mfr",
+            Opcode::MOV => "mov",
+            Opcode::SWAP => "swap",
+            Opcode::LOADX => "loadx",
+            Opcode::INC => "inc",
+            Opcode::DEC => "dec",
+            Opcode::ADDI => "addi",
+            Opcode::SUBI => "subi",
+            Opcode::MULI => "muli",
+            Opcode::IGL => "igl",
+        }
+    }
+
+    /// Describes what an instruction with this opcode does, as shown when
+    /// hovering over it in an editor.
+    pub fn doc(self) -> &'static str {
+        match self {
+            Opcode::LOAD => "LOAD $0 #1: Loads the integer 1 into register $0.",
+            Opcode::ADD => "ADD $0 $1 $2: Stores the sum of $0 and $1 into register $2.",
+            Opcode::SUB => "SUB $0 $1 $2: Stores the difference of $0 and $1 into register $2.",
+            Opcode::MUL => "MUL $0 $1 $2: Stores the product of $0 and $1 into register $2.",
+            Opcode::DIV => {
+                "DIV $0 $1 $2: Stores the quotient of $0 and $1 into register $2, keeping the remainder for MFR."
+            }
+            Opcode::HLT => "HLT: Stops the program.",
+            Opcode::JMP => "JMP $0: Sets the program counter to $0, continuing execution from there.",
+            Opcode::JMPF => "JMPF $0: Sets the program counter to pc + $0, continuing execution from there.",
+            Opcode::JMPB => "JMPB $0: Sets the program counter to pc - $0, continuing execution from there.",
+            Opcode::EQ => "EQ $0 $1 $2: Sets `VM.equal_flag` if $0 equals $1.",
+            Opcode::NEQ => "NEQ $0 $1 $2: Sets `VM.equal_flag` if $0 does not equal $1.",
+            Opcode::GT => "GT $0 $1 $2: Sets `VM.equal_flag` if $0 is greater than $1.",
+            Opcode::LT => "LT $0 $1 $2: Sets `VM.equal_flag` if $0 is less than $1.",
+            Opcode::GTQ => "GTQ $0 $1 $2: Sets `VM.equal_flag` if $0 is greater than or equal to $1.",
+            Opcode::LTQ => "LTQ $0 $1 $2: Sets `VM.equal_flag` if $0 is less than or equal to $1.",
+            Opcode::JEQ => "JEQ $0: Sets the program counter to $0 if `VM.equal_flag` is set.",
+            Opcode::JNEQ => "JNEQ $0: Sets the program counter to $0 if `VM.equal_flag` is not set.",
+            Opcode::SYSCALL => {
+                "SYSCALL $0: Calls the host service numbered $0, passing $1-$4 as arguments and storing its result in $0."
+            }
+            Opcode::SEND => "SEND $0 $1: Queues the value of $1 as a message to the VM whose id is in $0.",
+            Opcode::RECV => {
+                "RECV $0: Moves the oldest message in the mailbox into $0, blocking while the mailbox is empty."
+            }
+            Opcode::GTU => "GTU $0 $1 $2: Sets `VM.equal_flag` if $0 is greater than $1, comparing them as unsigned.",
+            Opcode::LTU => "LTU $0 $1 $2: Sets `VM.equal_flag` if $0 is less than $1, comparing them as unsigned.",
+            Opcode::GEU => {
+                "GEU $0 $1 $2: Sets `VM.equal_flag` if $0 is greater than or equal to $1, comparing them as unsigned."
+            }
+            Opcode::LEU => {
+                "LEU $0 $1 $2: Sets `VM.equal_flag` if $0 is less than or equal to $1, comparing them as unsigned."
+            }
+            Opcode::BEQ => "BEQ $0 $1 $2: Sets the program counter to $2 if $0 equals $1.",
+            Opcode::BNE => "BNE $0 $1 $2: Sets the program counter to $2 if $0 does not equal $1.",
+            Opcode::BGT => "BGT $0 $1 $2: Sets the program counter to $2 if $0 is greater than $1.",
+            Opcode::BLT => "BLT $0 $1 $2: Sets the program counter to $2 if $0 is less than $1.",
+            Opcode::BGE => "BGE $0 $1 $2: Sets the program counter to $2 if $0 is greater than or equal to $1.",
+            Opcode::BLE => "BLE $0 $1 $2: Sets the program counter to $2 if $0 is less than or equal to $1.",
+            Opcode::BGTU => {
+                "BGTU $0 $1 $2: Sets the program counter to $2 if $0 is greater than $1, comparing them as unsigned."
+            }
+            Opcode::BLTU => {
+                "BLTU $0 $1 $2: Sets the program counter to $2 if $0 is less than $1, comparing them as unsigned."
+            }
+            Opcode::BGEU => {
+                "BGEU $0 $1 $2: Sets the program counter to $2 if $0 is greater than or equal to $1, comparing them as unsigned."
+            }
+            Opcode::BLEU => {
+                "BLEU $0 $1 $2: Sets the program counter to $2 if $0 is less than or equal to $1, comparing them as unsigned."
+            }
+            Opcode::JEQF => "JEQF $0: Sets the program counter to pc + $0 if `VM.equal_flag` is set.",
+            Opcode::JEQB => "JEQB $0: Sets the program counter to pc - $0 if `VM.equal_flag` is set.",
+            Opcode::JNEQF => "JNEQF $0: Sets the program counter to pc + $0 if `VM.equal_flag` is not set.",
+            Opcode::JNEQB => "JNEQB $0: Sets the program counter to pc - $0 if `VM.equal_flag` is not set.",
+            Opcode::REM => {
+                "REM $0 $1 $2: Stores the remainder of $0 divided by $1 into register $2. The remainder has the sign of $0."
+            }
+            Opcode::MFR => "MFR $0: Moves the remainder left by the most recent DIV into $0.",
+            Opcode::MOV => "MOV $0 $1: Copies the value of $0 into register $1.",
+            Opcode::SWAP => "SWAP $0 $1: Exchanges the values of $0 and $1.",
+            Opcode::LOADX => "LOADX $0 $1: Copies the value of the register whose index is in $0 into register $1.",
+            Opcode::INC => "INC $0: Adds one to $0.",
+            Opcode::DEC => "DEC $0: Subtracts one from $0.",
+            Opcode::ADDI => "ADDI $0 #1 $2: Stores the sum of $0 and the integer 1 into register $2.",
+            Opcode::SUBI => "SUBI $0 #1 $2: Stores the difference of $0 and the integer 1 into register $2.",
+            Opcode::MULI => "MULI $0 #1 $2: Stores the product of $0 and the integer 1 into register $2.",
+            Opcode::IGL => "IGL: Stands for any byte that isn't an opcode. The VM stops if it reaches one.",
+        }
+    }
+
+    /// Returns the operands the VM reads after this opcode, in order.
+    pub fn operands(self) -> &'static [Operand] {
+        use self::Operand::*;
+        match self {
+            Opcode::LOAD => &[Register, Integer],
+            Opcode::ADD | Opcode::SUB | Opcode::MUL | Opcode::DIV | Opcode::REM => &[Register, Register, Register],
+            Opcode::EQ | Opcode::NEQ | Opcode::GT | Opcode::LT | Opcode::GTQ | Opcode::LTQ => {
+                &[Register, Register, Padding]
+            }
+            Opcode::GTU | Opcode::LTU | Opcode::GEU | Opcode::LEU => &[Register, Register, Padding],
+            Opcode::BEQ | Opcode::BNE | Opcode::BGT | Opcode::BLT | Opcode::BGE | Opcode::BLE => {
+                &[Register, Register, Register]
+            }
+            Opcode::BGTU | Opcode::BLTU | Opcode::BGEU | Opcode::BLEU => &[Register, Register, Register],
+            Opcode::JMP | Opcode::JMPF | Opcode::JMPB | Opcode::JEQ | Opcode::JNEQ => &[Register],
+            Opcode::JEQF | Opcode::JEQB | Opcode::JNEQF | Opcode::JNEQB => &[Register],
+            Opcode::SYSCALL | Opcode::RECV | Opcode::MFR | Opcode::INC | Opcode::DEC => &[Register],
+            Opcode::ADDI | Opcode::SUBI | Opcode::MULI => &[Register, Integer, Register],
+            Opcode::SEND | Opcode::MOV | Opcode::SWAP | Opcode::LOADX => &[Register, Register],
+            Opcode::HLT | Opcode::IGL => &[],
+        }
+    }
+
+    /// Returns how many bytes an instruction with this opcode takes up,
+    /// including the opcode itself.
+    pub fn instruction_len(self) -> usize {
+        1 + self.operands().iter().map(|operand| operand.width()).sum::<usize>()
+    }
+
+    /// Returns true for opcodes that may set the program counter somewhere
+    /// other than the next instruction.
+    pub fn is_jump(self) -> bool {
+        matches!(
+            self,
+            Opcode::JMP
+                | Opcode::JMPF
+                | Opcode::JMPB
+                | Opcode::JEQ
+                | Opcode::JNEQ
+                | Opcode::BEQ
+                | Opcode::BNE
+                | Opcode::BGT
+                | Opcode::BLT
+                | Opcode::BGE
+                | Opcode::BLE
+                | Opcode::BGTU
+                | Opcode::BLTU
+                | Opcode::BGEU
+                | Opcode::BLEU
+                | Opcode::JEQF
+                | Opcode::JEQB
+                | Opcode::JNEQF
+                | Opcode::JNEQB
+        )
+    }
+
+    /// Returns true for the comparisons, which set `VM.equal_flag`.
+    pub fn sets_equal_flag(self) -> bool {
+        matches!(
+            self,
+            Opcode::EQ
+                | Opcode::NEQ
+                | Opcode::GT
+                | Opcode::LT
+                | Opcode::GTQ
+                | Opcode::LTQ
+                | Opcode::GTU
+                | Opcode::LTU
+                | Opcode::GEU
+                | Opcode::LEU
+        )
+    }
+
+    /// Returns true for the jumps that depend on `VM.equal_flag`.
+    pub fn reads_equal_flag(self) -> bool {
+        matches!(
+            self,
+            Opcode::JEQ | Opcode::JNEQ | Opcode::JEQF | Opcode::JEQB | Opcode::JNEQF | Opcode::JNEQB
+        )
+    }
+
+    /// Returns which of its register operands an instruction with this opcode
+    /// reads, counting from 0. Registers SYSCALL passes as arguments and the
+    /// register LOADX reads through its index aren't included.
+    pub fn register_reads(self) -> &'static [usize] {
+        match self {
+            Opcode::LOAD | Opcode::RECV | Opcode::MFR | Opcode::HLT | Opcode::IGL => &[],
+            Opcode::BEQ
+            | Opcode::BNE
+            | Opcode::BGT
+            | Opcode::BLT
+            | Opcode::BGE
+            | Opcode::BLE
+            | Opcode::BGTU
+            | Opcode::BLTU
+            | Opcode::BGEU
+            | Opcode::BLEU => &[0, 1, 2],
+            opcode if opcode.is_jump() => &[0],
+            Opcode::SYSCALL | Opcode::MOV | Opcode::LOADX | Opcode::INC | Opcode::DEC => &[0],
+            Opcode::ADDI | Opcode::SUBI | Opcode::MULI => &[0],
+            _ => &[0, 1],
+        }
+    }
+
+    /// Returns which of its register operands an instruction with this opcode
+    /// writes, counting from 0. The result SYSCALL writes isn't included.
+    pub fn register_writes(self) -> &'static [usize] {
+        match self {
+            Opcode::LOAD | Opcode::RECV | Opcode::MFR | Opcode::INC | Opcode::DEC => &[0],
+            Opcode::ADD | Opcode::SUB | Opcode::MUL | Opcode::DIV | Opcode::REM => &[2],
+            Opcode::MOV | Opcode::LOADX | Opcode::ADDI | Opcode::SUBI | Opcode::MULI => &[1],
+            Opcode::SWAP => &[0, 1],
+            _ => &[],
+        }
+    }
+
+    /// Returns the opcode named by `mnemonic`, ignoring case. IGL stands
+    /// for bytes that aren't an opcode, so `igl` doesn't name one.
+    pub fn from_mnemonic(mnemonic: &str) -> Option<Opcode> {
+        (0..=u8::MAX)
+            .map(Opcode::from)
+            .filter(|&opcode| opcode != Opcode::IGL)
+            .find(|opcode| opcode.mnemonic().eq_ignore_ascii_case(mnemonic))
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::*; 
-    
+    use super::*;
+
     #[test]
 
     fn test_create_hlt() {
@@ -105,9 +506,107 @@ mod tests {
     }
 
     #[test]
+    fn test_from_mnemonic() {
+        assert_eq!(Opcode::from_mnemonic("load"), Some(Opcode::LOAD));
+        assert_eq!(Opcode::from_mnemonic("JMPF"), Some(Opcode::JMPF));
+        assert_eq!(Opcode::from_mnemonic("bogus"), None);
+        assert_eq!(Opcode::from_mnemonic("igl"), None);
+    }
 
-    fn test_create_instruction() {
-        let instruction = Instruction::new(Opcode::HLT);
-        assert_eq!(instruction.opcode, Opcode::HLT)
+    #[test]
+    fn test_doc_describes_operands() {
+        for opcode in (0..=Opcode::IGL as u8).map(Opcode::from) {
+            // Each doc starts with how the instruction is written, such as
+            // `LOAD $0 #1:`.
+            let (usage, _) = opcode.doc().split_once(':').unwrap();
+            let mut words = usage.split(' ');
+            assert_eq!(words.next(), Some(opcode.mnemonic().to_uppercase().as_str()));
+            let operands: Vec<char> = words.map(|word| word.chars().next().unwrap()).collect();
+            let expected: Vec<char> = opcode
+                .operands()
+                .iter()
+                .map(|operand| if *operand == Operand::Integer { '#' } else { '$' })
+                .collect();
+            assert_eq!(operands, expected, "{:?}", opcode);
+        }
+    }
+
+    #[test]
+    fn test_opcode_table() {
+        // Each opcode with its byte, mnemonic, length, whether it jumps, and
+        // whether it sets or reads the equal flag.
+        use self::Opcode::*;
+        let table = [
+            (LOAD, 0, "load", 4, false, false, false),
+            (ADD, 1, "add", 4, false, false, false),
+            (SUB, 2, "sub", 4, false, false, false),
+            (MUL, 3, "mul", 4, false, false, false),
+            (DIV, 4, "div", 4, false, false, false),
+            (HLT, 5, "hlt", 1, false, false, false),
+            (JMP, 6, "jmp", 2, true, false, false),
+            (JMPF, 7, "jmpf", 2, true, false, false),
+            (JMPB, 8, "jmpb", 2, true, false, false),
+            (EQ, 9, "eq", 4, false, true, false),
+            (NEQ, 10, "neq", 4, false, true, false),
+            (GT, 11, "gt", 4, false, true, false),
+            (LT, 12, "lt", 4, false, true, false),
+            (GTQ, 13, "gtq", 4, false, true, false),
+            (LTQ, 14, "ltq", 4, false, true, false),
+            (JEQ, 15, "jeq", 2, true, false, true),
+            (JNEQ, 16, "jneq", 2, true, false, true),
+            (SYSCALL, 17, "syscall", 2, false, false, false),
+            (SEND, 18, "send", 3, false, false, false),
+            (RECV, 19, "recv", 2, false, false, false),
+            (GTU, 20, "gtu", 4, false, true, false),
+            (LTU, 21, "ltu", 4, false, true, false),
+            (GEU, 22, "geu", 4, false, true, false),
+            (LEU, 23, "leu", 4, false, true, false),
+            (BEQ, 24, "beq", 4, true, false, false),
+            (BNE, 25, "bne", 4, true, false, false),
+            (BGT, 26, "bgt", 4, true, false, false),
+            (BLT, 27, "blt", 4, true, false, false),
+            (BGE, 28, "bge", 4, true, false, false),
+            (BLE, 29, "ble", 4, true, false, false),
+            (BGTU, 30, "bgtu", 4, true, false, false),
+            (BLTU, 31, "bltu", 4, true, false, false),
+            (BGEU, 32, "bgeu", 4, true, false, false),
+            (BLEU, 33, "bleu", 4, true, false, false),
+            (JEQF, 34, "jeqf", 2, true, false, true),
+            (JEQB, 35, "jeqb", 2, true, false, true),
+            (JNEQF, 36, "jneqf", 2, true, false, true),
+            (JNEQB, 37, "jneqb", 2, true, false, true),
+            (REM, 38, "rem", 4, false, false, false),
+            (MFR, 39, "mfr", 2, false, false, false),
+            (MOV, 40, "mov", 3, false, false, false),
+            (SWAP, 41, "swap", 3, false, false, false),
+            (LOADX, 42, "loadx", 3, false, false, false),
+            (INC, 43, "inc", 2, false, false, false),
+            (DEC, 44, "dec", 2, false, false, false),
+            (ADDI, 45, "addi", 5, false, false, false),
+            (SUBI, 46, "subi", 5, false, false, false),
+            (MULI, 47, "muli", 5, false, false, false),
+        ];
+        assert_eq!(table.len(), IGL as usize);
+        for (opcode, byte, mnemonic, len, jumps, sets_flag, reads_flag) in table {
+            assert_eq!(opcode as u8, byte);
+            assert_eq!(Opcode::from(byte), opcode);
+            assert_eq!(Opcode::from_mnemonic(mnemonic), Some(opcode));
+            assert_eq!(opcode.instruction_len(), len, "{:?}", opcode);
+            assert_eq!(opcode.is_jump(), jumps, "{:?}", opcode);
+            assert_eq!(opcode.sets_equal_flag(), sets_flag, "{:?}", opcode);
+            assert_eq!(opcode.reads_equal_flag(), reads_flag, "{:?}", opcode);
+        }
+        assert_eq!(Opcode::from(IGL as u8), IGL);
+        assert_eq!(IGL.instruction_len(), 1);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_register_reads_and_writes() {
+        assert_eq!(Opcode::ADDI.register_reads(), &[0]);
+        assert_eq!(Opcode::ADDI.register_writes(), &[1]);
+        assert_eq!(Opcode::BLTU.register_reads(), &[0, 1, 2]);
+        assert_eq!(Opcode::JNEQF.register_reads(), &[0]);
+        assert_eq!(Opcode::SWAP.register_writes(), &[0, 1]);
+        assert!(Opcode::HLT.register_reads().is_empty());
+    }
+}
@@ -0,0 +1,47 @@
+//! Iridescent is a small register-based virtual machine, with an assembler
+//! for producing its bytecode.
+//!
+//! ```
+//! let bytecode = iridescent::assemble("load $0 #500\nhlt").unwrap();
+//! let mut vm = iridescent::VM::builder().program(bytecode).build();
+//! vm.run().unwrap();
+//! assert_eq!(vm.register(0), 500);
+//! ```
+#![warn(missing_docs)]
+mod vm;
+mod instruction;
+mod assembler;
+mod syscall;
+mod debug_info;
+mod runtime;
+mod verifier;
+mod cfg;
+mod lint;
+mod object;
+mod linker;
+mod json;
+mod lsp;
+mod compiler;
+
+pub use crate::assembler::{
+    assemble, assemble_object, assemble_optimized, assemble_with_debug_info, assemble_with_includes, lint_source,
+    AssembleError,
+};
+pub use crate::assembler::formatter::format_source;
+pub use crate::assembler::optimizer::Change;
+pub use crate::cfg::{Block, Cfg};
+pub use crate::compiler::{compile, compile_to_assembly, CompileError};
+pub use crate::debug_info::{DebugInfo, DebugInfoError};
+pub use crate::instruction::{Opcode, Operand};
+pub use crate::json::{Json, JsonError};
+pub use crate::linker::{LinkError, Linker};
+pub use crate::lint::{lint, Warning};
+pub use crate::lsp::serve as serve_lsp;
+pub use crate::object::{Object, ObjectError, Relocation, Target, OBJECT_VERSION};
+pub use crate::runtime::{Runtime, Status, VmId, DEFAULT_QUANTUM};
+pub use crate::syscall::{SyscallHandler, ARGUMENT_REGISTERS, RESULT_REGISTER};
+pub use crate::verifier::{decode, verify, Decoded, Violation};
+pub use crate::vm::{
+    Fault, Journal, JournalEntry, Profile, SnapshotError, VMBuilder, DEFAULT_MAILBOX_CAPACITY, REGISTER_COUNT,
+    SNAPSHOT_VERSION, VM,
+};
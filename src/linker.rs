@@ -0,0 +1,213 @@
+//! `linker` combines object files into a single program the VM can run.
+//!
+//! Objects are laid out one after another in the order they were added, so
+//! the first object's code is where execution starts. Each relocation is then
+//! patched with the final address of what it refers to.
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+use crate::object::{Object, Target};
+
+/// A problem that stops objects from being linked.
+#[derive(Debug, PartialEq, Clone)]
+pub enum LinkError {
+    /// An object refers to a symbol no object exports.
+    UndefinedSymbol {
+        /// The object referring to the symbol.
+        object: String,
+        /// The symbol's name.
+        name: String,
+    },
+    /// Two objects export the same symbol.
+    DuplicateSymbol {
+        /// The symbol's name.
+        name: String,
+        /// The object that exported it first.
+        first: String,
+        /// The object that exported it again.
+        second: String,
+    },
+    /// A relocated operand ends up past the 16 bit operand range.
+    OutOfRange {
+        /// The object holding the operand.
+        object: String,
+        /// Where the operand starts in that object's code.
+        offset: usize,
+        /// The value it would have been patched with.
+        value: u64,
+    },
+    /// A relocation's operand doesn't lie within its object's code.
+    BadRelocation {
+        /// The object holding the relocation.
+        object: String,
+        /// The offset the relocation gives.
+        offset: usize,
+    },
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LinkError::UndefinedSymbol { object, name } => {
+                write!(f, "{}: undefined symbol `{}`", object, name)
+            }
+            LinkError::DuplicateSymbol { name, first, second } => {
+                write!(f, "{}: symbol `{}` is already defined in {}", second, name, first)
+            }
+            LinkError::OutOfRange { object, offset, value } => {
+                write!(f, "{}: operand at {} would be {}, past the 16 bit operand range", object, offset, value)
+            }
+            LinkError::BadRelocation { object, offset } => {
+                write!(f, "{}: relocation at {} is past the end of the code", object, offset)
+            }
+        }
+    }
+}
+
+impl Error for LinkError {}
+
+/// Collects named objects and links them into a program.
+#[derive(Debug, Default)]
+pub struct Linker {
+    objects: Vec<(String, Object)>,
+}
+
+impl Linker {
+    /// Returns a linker with no objects.
+    pub fn new() -> Linker {
+        Linker { objects: vec![] }
+    }
+
+    /// Adds `object`, named `name` in error messages, after the objects
+    /// already added.
+    pub fn add(mut self, name: &str, object: Object) -> Linker {
+        self.objects.push((name.to_string(), object));
+        self
+    }
+
+    /// Lays the objects out in order and resolves every relocation, returning
+    /// the program or every problem found.
+    pub fn link(self) -> Result<Vec<u8>, Vec<LinkError>> {
+        let mut errors = vec![];
+        let mut bases = vec![];
+        let mut program = vec![];
+        for (_, object) in &self.objects {
+            bases.push(program.len());
+            program.extend_from_slice(&object.code);
+        }
+
+        let mut symbols: HashMap<&str, (usize, &str)> = HashMap::new();
+        for ((object_name, object), &base) in self.objects.iter().zip(&bases) {
+            for (name, offset) in &object.exports {
+                match symbols.get(name.as_str()) {
+                    Some(&(_, first)) => errors.push(LinkError::DuplicateSymbol {
+                        name: name.clone(),
+                        first: first.to_string(),
+                        second: object_name.clone(),
+                    }),
+                    None => {
+                        symbols.insert(name, (base + offset, object_name));
+                    }
+                }
+            }
+        }
+
+        for ((object_name, object), &base) in self.objects.iter().zip(&bases) {
+            let mut undefined: Vec<&str> = vec![];
+            for relocation in &object.relocations {
+                // `Object::from_bytes` checks this, but an object built by
+                // hand may not have been read from bytes.
+                if object.code.len() < 2 || relocation.offset > object.code.len() - 2 {
+                    errors.push(LinkError::BadRelocation { object: object_name.clone(), offset: relocation.offset });
+                    continue;
+                }
+                let address = match &relocation.target {
+                    Target::Local => base,
+                    Target::Symbol(name) => match symbols.get(name.as_str()) {
+                        Some(&(address, _)) => address,
+                        None => {
+                            if !undefined.contains(&name.as_str()) {
+                                undefined.push(name);
+                                errors.push(LinkError::UndefinedSymbol {
+                                    object: object_name.clone(),
+                                    name: name.clone(),
+                                });
+                            }
+                            continue;
+                        }
+                    },
+                };
+                let at = base + relocation.offset;
+                let addend = u16::from_be_bytes([program[at], program[at + 1]]);
+                let value = address as u64 + addend as u64;
+                if value > u16::MAX as u64 {
+                    errors.push(LinkError::OutOfRange { object: object_name.clone(), offset: relocation.offset, value });
+                    continue;
+                }
+                program[at..at + 2].copy_from_slice(&(value as u16).to_be_bytes());
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(program)
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::Relocation;
+
+    // load $0 <addend>; jmp $0
+    fn jumper(target: Target, addend: u8) -> Object {
+        Object {
+            code: vec![0, 0, 0, addend, 6, 0],
+            exports: vec![],
+            relocations: vec![Relocation { offset: 2, target }],
+        }
+    }
+
+    #[test]
+    fn test_link_resolves_relocations() {
+        let main = jumper(Target::Symbol("end".to_string()), 0);
+        let local = jumper(Target::Local, 4);
+        let end = Object { code: vec![5], exports: vec![("end".to_string(), 0)], relocations: vec![] };
+
+        let program = Linker::new().add("main", main).add("local", local).add("end", end).link().unwrap();
+        assert_eq!(program, vec![0, 0, 0, 12, 6, 0, 0, 0, 0, 10, 6, 0, 5]);
+    }
+
+    #[test]
+    fn test_link_errors() {
+        let exporter = || Object { code: vec![5], exports: vec![("f".to_string(), 0)], relocations: vec![] };
+        let errors = Linker::new()
+            .add("a", jumper(Target::Symbol("g".to_string()), 0))
+            .add("b", exporter())
+            .add("c", exporter())
+            .link()
+            .unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                LinkError::DuplicateSymbol { name: "f".to_string(), first: "b".to_string(), second: "c".to_string() },
+                LinkError::UndefinedSymbol { object: "a".to_string(), name: "g".to_string() },
+            ]
+        );
+        assert_eq!(errors[0].to_string(), "c: symbol `f` is already defined in b");
+
+        // The second object's code must be left alone, not patched by the
+        // first's relocation.
+        let mut overhanging = jumper(Target::Local, 0);
+        overhanging.relocations[0].offset = 5;
+        let errors = Linker::new().add("a", overhanging).add("b", jumper(Target::Local, 0)).link().unwrap_err();
+        assert_eq!(errors, vec![LinkError::BadRelocation { object: "a".to_string(), offset: 5 }]);
+        assert_eq!(errors[0].to_string(), "a: relocation at 5 is past the end of the code");
+        let mut far = jumper(Target::Local, 0);
+        far.relocations[0].offset = usize::MAX;
+        assert!(Linker::new().add("a", far).link().is_err());
+    }
+}
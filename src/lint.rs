@@ -0,0 +1,235 @@
+//! `lint` looks for likely mistakes in programs that assemble and verify but
+//! probably don't do what their author meant, for `iridescent lint`.
+//!
+//! The checks work on the control flow graph, so they see the program the VM
+//! will run, after macros and includes. Unused labels need the source as
+//! well, and are found by `assembler::lint_source`.
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::cfg::{Block, Cfg};
+use crate::debug_info::DebugInfo;
+use crate::instruction::Opcode;
+use crate::syscall::RESULT_REGISTER;
+use crate::verifier::{Decoded, KnownValues};
+use crate::vm::REGISTER_COUNT;
+
+/// A likely mistake found by `lint`.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Warning {
+    /// Code that can never run, such as code straight after a HLT or JMP
+    /// that nothing jumps to.
+    Unreachable {
+        /// Where the unreachable code starts.
+        offset: usize,
+    },
+    /// A register is read, but no instruction ever writes it.
+    NeverWritten {
+        /// Where the first read is.
+        offset: usize,
+        /// The register read.
+        register: u8,
+    },
+    /// A jump on `equal_flag` may run before any comparison has set it.
+    FlagNotSet {
+        /// Where the jump is.
+        offset: usize,
+        /// The jump's opcode.
+        opcode: Opcode,
+    },
+    /// A DIV or REM divides by a register that always holds zero there.
+    DivisionByZero {
+        /// Where the division is.
+        offset: usize,
+        /// The register holding the divisor.
+        register: u8,
+    },
+    /// A label is declared but never used.
+    UnusedLabel {
+        /// The label's offset.
+        offset: usize,
+        /// The label's name.
+        name: String,
+    },
+}
+
+impl Warning {
+    /// Returns the offset the warning is about.
+    pub fn offset(&self) -> usize {
+        match self {
+            Warning::Unreachable { offset }
+            | Warning::NeverWritten { offset, .. }
+            | Warning::FlagNotSet { offset, .. }
+            | Warning::DivisionByZero { offset, .. }
+            | Warning::UnusedLabel { offset, .. } => *offset,
+        }
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Warning::Unreachable { .. } => write!(f, "unreachable code"),
+            Warning::NeverWritten { register, .. } => {
+                write!(f, "${} is read but never written, so it is always 0", register)
+            }
+            Warning::FlagNotSet { opcode, .. } => {
+                write!(f, "{} may run before a comparison sets the equal flag", opcode.mnemonic())
+            }
+            Warning::DivisionByZero { register, .. } => write!(f, "division by ${}, which is always 0", register),
+            Warning::UnusedLabel { name, .. } => write!(f, "label `{}` is never used", name),
+        }
+    }
+}
+
+/// Returns the registers `instruction` reads. The syscall argument registers
+/// aren't counted, since most syscalls only use some of them.
+fn reads(instruction: &Decoded) -> Vec<usize> {
+    instruction.opcode.register_reads().iter().filter_map(|&n| instruction.register(n)).collect()
+}
+
+/// Returns the registers `instruction` writes.
+fn writes(instruction: &Decoded) -> Vec<usize> {
+    let mut writes: Vec<usize> =
+        instruction.opcode.register_writes().iter().filter_map(|&n| instruction.register(n)).collect();
+    if instruction.opcode == Opcode::SYSCALL {
+        writes.push(RESULT_REGISTER);
+    }
+    writes
+}
+
+/// Checks `program` for unreachable code, registers that are read but never
+/// written, jumps on `equal_flag` that no comparison sets, and divisions by a
+/// register that is always zero. Labels in `debug_info` are taken as the
+/// places a jump to a computed target may land. Warnings are in program
+/// order.
+pub fn lint(program: &[u8], debug_info: Option<&DebugInfo>) -> Vec<Warning> {
+    let cfg = Cfg::build(program, debug_info);
+    let reachable = cfg.reachable();
+    let mut warnings = vec![];
+
+    let mut previous_reachable = true;
+    for block in cfg.blocks() {
+        let is_reachable = reachable.contains(&block.start);
+        if !is_reachable && previous_reachable {
+            warnings.push(Warning::Unreachable { offset: block.start });
+        }
+        previous_reachable = is_reachable;
+    }
+    let live: Vec<_> = cfg.blocks().iter().filter(|block| reachable.contains(&block.start)).collect();
+
+    let written: HashSet<usize> = cfg.blocks().iter().flat_map(|block| &block.instructions).flat_map(writes).collect();
+    let mut reported = HashSet::new();
+    for instruction in live.iter().flat_map(|block| &block.instructions) {
+        for register in reads(instruction) {
+            if !written.contains(&register) && reported.insert(register) {
+                warnings.push(Warning::NeverWritten { offset: instruction.offset, register: register as u8 });
+            }
+        }
+    }
+
+    // Find the blocks entered with the flag set on every path: assume all of
+    // them are, then take it back from any with a predecessor that doesn't
+    // set it, until nothing changes. The entry block starts with it unset.
+    let entry = live.first().map(|block| block.start);
+    let sets_flag = |block: &Block| block.instructions.iter().any(|i| i.opcode.sets_equal_flag());
+    let mut flag_set: HashSet<usize> = live.iter().map(|block| block.start).filter(|&start| Some(start) != entry).collect();
+    loop {
+        let unset: Vec<usize> = live
+            .iter()
+            .filter(|block| flag_set.contains(&block.start))
+            .filter(|block| {
+                cfg.predecessors(block).iter().any(|predecessor| {
+                    let predecessor = cfg.block(*predecessor).expect("predecessors are blocks");
+                    reachable.contains(&predecessor.start)
+                        && !flag_set.contains(&predecessor.start)
+                        && !sets_flag(predecessor)
+                })
+            })
+            .map(|block| block.start)
+            .collect();
+        if unset.is_empty() {
+            break;
+        }
+        for start in unset {
+            flag_set.remove(&start);
+        }
+    }
+
+    for block in &live {
+        let mut flag = flag_set.contains(&block.start);
+        let mut known = KnownValues::unknown();
+        for register in (0..REGISTER_COUNT).filter(|register| !written.contains(register)) {
+            known.set(register, Some(0));
+        }
+        for instruction in &block.instructions {
+            if instruction.opcode.reads_equal_flag() && !flag {
+                warnings.push(Warning::FlagNotSet { offset: instruction.offset, opcode: instruction.opcode });
+            }
+            flag |= instruction.opcode.sets_equal_flag();
+            if let (Opcode::DIV | Opcode::REM, Some(divisor)) = (instruction.opcode, instruction.register(1)) {
+                if known.get(divisor) == Some(0) {
+                    warnings.push(Warning::DivisionByZero { offset: instruction.offset, register: divisor as u8 });
+                }
+            }
+            known.apply(instruction);
+        }
+    }
+
+    warnings.sort_by_key(Warning::offset);
+    warnings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble_with_debug_info;
+
+    fn lint_source(source: &str) -> Vec<Warning> {
+        let (program, debug_info) = assemble_with_debug_info(source, "").unwrap();
+        lint(&program, Some(&debug_info))
+    }
+
+    #[test]
+    fn test_lint_clean_program() {
+        let source = "load $0 #3\nload $1 @loop\nload $2 #0\nloop: dec $0\neq $0 $2 $0\njneq $1\nhlt\n";
+        assert_eq!(lint_source(source), vec![]);
+    }
+
+    #[test]
+    fn test_lint_unreachable() {
+        let warnings = lint_source("load $0 @end\njmp $0\ninc $0\ninc $0\nend: hlt\ninc $0\n");
+        assert_eq!(warnings, vec![Warning::Unreachable { offset: 6 }, Warning::Unreachable { offset: 11 }]);
+        assert_eq!(warnings[0].to_string(), "unreachable code");
+    }
+
+    #[test]
+    fn test_lint_never_written() {
+        let warnings = lint_source("load $0 #1\nadd $0 $5 $1\nadd $5 $5 $2\nhlt\n");
+        assert_eq!(warnings, vec![Warning::NeverWritten { offset: 4, register: 5 }]);
+        assert_eq!(warnings[0].to_string(), "$5 is read but never written, so it is always 0");
+    }
+
+    #[test]
+    fn test_lint_flag_not_set() {
+        // The jneq in the loop is fine, since both ways in have compared.
+        let source = "load $0 @end\njeq $0\nload $1 #1\nload $2 @loop\nloop: eq $1 $1 $0\nbeq $1 $1 $2\njneq $2\nend: hlt\n";
+        assert_eq!(lint_source(source), vec![Warning::FlagNotSet { offset: 4, opcode: Opcode::JEQ }]);
+
+        let source = "load $0 @skip\nload $1 #0\ngt $0 $1 $0\njeq $0\nload $2 @skip\njmp $2\nskip: jneq $0\nhlt\n";
+        assert_eq!(lint_source(source), vec![]);
+    }
+
+    #[test]
+    fn test_lint_division_by_zero() {
+        let source = "load $0 #8\nload $1 #0\ndiv $0 $1 $2\nrem $0 $3 $2\nload $1 #2\ndiv $0 $1 $2\nhlt\n";
+        assert_eq!(
+            lint_source(source),
+            vec![
+                Warning::DivisionByZero { offset: 8, register: 1 },
+                Warning::NeverWritten { offset: 12, register: 3 },
+                Warning::DivisionByZero { offset: 12, register: 3 },
+            ]
+        );
+    }
+}
@@ -0,0 +1,501 @@
+//! `lsp` is a language server for iridescent assembly, run over stdio by
+//! `iridescent lsp`. It gives editors:
+//!
+//! - diagnostics from assembling each open document as an object file, so
+//!   `.extern` labels are allowed;
+//! - completion of opcodes at the start of a statement, registers after `$`,
+//!   labels after `@` and constants after `#`;
+//! - go to definition for labels, constants and macros;
+//! - hover text for opcodes, taken from `Opcode::doc`.
+//!
+//! Documents are synced in full on every change. Positions count characters
+//! rather than UTF-16 code units, which only differs outside the BMP.
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+use crate::assembler::assemble_object;
+use crate::assembler::label_parsers::is_label_char;
+use crate::assembler::source::{split_comment, split_label};
+use crate::instruction::{Opcode, Operand};
+use crate::json::Json;
+use crate::vm::REGISTER_COUNT;
+
+/// The JSON-RPC error for messages that aren't valid JSON.
+const PARSE_ERROR: i32 = -32700;
+
+/// The JSON-RPC error for requests the server doesn't know.
+const METHOD_NOT_FOUND: i32 = -32601;
+
+/// The JSON-RPC error for requests made after `shutdown`.
+const INVALID_REQUEST: i32 = -32600;
+
+/// The LSP completion item kinds used for each kind of suggestion.
+const KEYWORD_KIND: usize = 14;
+const VARIABLE_KIND: usize = 6;
+const REFERENCE_KIND: usize = 18;
+const CONSTANT_KIND: usize = 21;
+const MODULE_KIND: usize = 9;
+
+/// The state of one language server session.
+#[derive(Debug, Default)]
+pub struct Server {
+    /// The text of each open document, by URI.
+    documents: HashMap<String, String>,
+    shut_down: bool,
+    exited: bool,
+}
+
+/// What a name is declared as.
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Kind {
+    Label,
+    Constant,
+    Macro,
+}
+
+/// A name declared in a document, and where.
+#[derive(Debug, PartialEq)]
+struct Declaration {
+    name: String,
+    kind: Kind,
+    /// The 0-based line and character the name starts at.
+    line: usize,
+    character: usize,
+}
+
+impl Server {
+    /// Returns a server with no open documents.
+    pub fn new() -> Server {
+        Server::default()
+    }
+
+    /// Returns true once the client has sent `exit`.
+    pub fn exited(&self) -> bool {
+        self.exited
+    }
+
+    /// Returns true once the client has sent `shutdown`.
+    pub fn shut_down(&self) -> bool {
+        self.shut_down
+    }
+
+    /// Handles one message from the client, returning the responses and
+    /// notifications to send back.
+    pub fn handle(&mut self, message: &Json) -> Vec<Json> {
+        let method = message.get("method").and_then(Json::as_str).unwrap_or("");
+        let params = message.get("params").unwrap_or(&Json::Null);
+        let id = match message.get("id") {
+            Some(id) => id.clone(),
+            None => return self.notification(method, params),
+        };
+
+        let result = match method {
+            _ if self.shut_down => return vec![error_response(id, INVALID_REQUEST, "the server is shutting down")],
+            "initialize" => capabilities(),
+            "shutdown" => {
+                self.shut_down = true;
+                Json::Null
+            }
+            "textDocument/completion" => self.at_position(params, completions).unwrap_or(Json::Null),
+            "textDocument/definition" => self.at_position(params, definition).unwrap_or(Json::Null),
+            "textDocument/hover" => self.at_position(params, hover).unwrap_or(Json::Null),
+            _ => return vec![error_response(id, METHOD_NOT_FOUND, &format!("unknown method `{}`", method))],
+        };
+        vec![Json::object(vec![("jsonrpc", Json::from("2.0")), ("id", id), ("result", result)])]
+    }
+
+    fn notification(&mut self, method: &str, params: &Json) -> Vec<Json> {
+        let document = params.get("textDocument");
+        let uri = document.and_then(|document| document.get("uri")).and_then(Json::as_str);
+        match (method, uri) {
+            ("exit", _) => {
+                self.exited = true;
+                vec![]
+            }
+            ("textDocument/didOpen", Some(uri)) => {
+                let text = document.and_then(|document| document.get("text")).and_then(Json::as_str);
+                self.documents.insert(uri.to_string(), text.unwrap_or("").to_string());
+                vec![publish_diagnostics(uri, &self.documents[uri])]
+            }
+            ("textDocument/didChange", Some(uri)) => {
+                let text = match params.get("contentChanges") {
+                    Some(Json::Array(changes)) => changes.last().and_then(|change| change.get("text")),
+                    _ => None,
+                };
+                match text.and_then(Json::as_str) {
+                    Some(text) => {
+                        self.documents.insert(uri.to_string(), text.to_string());
+                        vec![publish_diagnostics(uri, text)]
+                    }
+                    None => vec![],
+                }
+            }
+            ("textDocument/didClose", Some(uri)) => {
+                self.documents.remove(uri);
+                vec![publish_diagnostics(uri, "")]
+            }
+            _ => vec![],
+        }
+    }
+
+    /// Calls `f` with the document, line and character named by the
+    /// request's `params`, if the document is open.
+    fn at_position(&self, params: &Json, f: fn(&str, &str, usize, usize) -> Json) -> Option<Json> {
+        let uri = params.get("textDocument")?.get("uri")?.as_str()?;
+        let text = self.documents.get(uri)?;
+        let position = params.get("position")?;
+        let line = position.get("line")?.as_usize()?;
+        let character = position.get("character")?.as_usize()?;
+        Some(f(uri, text, line, character))
+    }
+}
+
+fn capabilities() -> Json {
+    Json::object(vec![
+        (
+            "capabilities",
+            Json::object(vec![
+                ("textDocumentSync", Json::from(1)),
+                (
+                    "completionProvider",
+                    Json::object(vec![(
+                        "triggerCharacters",
+                        Json::Array(vec![Json::from("$"), Json::from("@"), Json::from("#")]),
+                    )]),
+                ),
+                ("definitionProvider", Json::from(true)),
+                ("hoverProvider", Json::from(true)),
+            ]),
+        ),
+        (
+            "serverInfo",
+            Json::object(vec![("name", Json::from("iridescent")), ("version", Json::from(env!("CARGO_PKG_VERSION")))]),
+        ),
+    ])
+}
+
+fn error_response(id: Json, code: i32, message: &str) -> Json {
+    Json::object(vec![
+        ("jsonrpc", Json::from("2.0")),
+        ("id", id),
+        ("error", Json::object(vec![("code", Json::Number(code as f64)), ("message", Json::from(message))])),
+    ])
+}
+
+/// Returns the notification publishing the diagnostics for `text`.
+fn publish_diagnostics(uri: &str, text: &str) -> Json {
+    let path = uri.strip_prefix("file://").unwrap_or(uri);
+    let diagnostics = match assemble_object(text, path, &[]) {
+        Ok(_) => vec![],
+        Err(error) => {
+            let line = error.line.saturating_sub(1);
+            let end = text.lines().nth(line).map_or(0, |text| text.chars().count());
+            vec![Json::object(vec![
+                ("range", range(line, 0, end)),
+                ("severity", Json::from(1)),
+                ("source", Json::from("iridescent")),
+                ("message", Json::from(error.message)),
+            ])]
+        }
+    };
+    Json::object(vec![
+        ("jsonrpc", Json::from("2.0")),
+        ("method", Json::from("textDocument/publishDiagnostics")),
+        ("params", Json::object(vec![("uri", Json::from(uri)), ("diagnostics", Json::Array(diagnostics))])),
+    ])
+}
+
+fn range(line: usize, start: usize, end: usize) -> Json {
+    let position = |character: usize| Json::object(vec![("line", Json::from(line)), ("character", Json::from(character))]);
+    Json::object(vec![("start", position(start)), ("end", position(end))])
+}
+
+/// Returns the labels, constants and macros declared in `text`.
+fn declarations(text: &str) -> Vec<Declaration> {
+    let mut declarations = vec![];
+    for (line, source) in text.lines().enumerate() {
+        let code = split_comment(source).0;
+        let trimmed = code.trim_start();
+        let indent = code.chars().count() - trimmed.chars().count();
+        let mut words = trimmed.split_whitespace();
+        let directive = words.next();
+        let kind = match directive {
+            Some(".equ") => Kind::Constant,
+            Some(".macro") => Kind::Macro,
+            _ => {
+                if let (Some(name), _) = split_label(trimmed) {
+                    declarations.push(Declaration { name: name.to_string(), kind: Kind::Label, line, character: indent });
+                }
+                continue;
+            }
+        };
+        if let Some(name) = words.next().map(|name| name.trim_end_matches(',')) {
+            // The name is looked for after the directive, which could
+            // contain it, as `.macro m` does.
+            let after = code.len() - trimmed.len() + directive.map_or(0, str::len);
+            let character = code[after..].find(name).map_or(indent, |start| code[..after + start].chars().count());
+            declarations.push(Declaration { name: name.to_string(), kind, line, character });
+        }
+    }
+    declarations
+}
+
+/// Returns the name under `character` on `line` and the character before it,
+/// such as `$` or `@`.
+fn word_at(text: &str, line: usize, character: usize) -> Option<(String, Option<char>)> {
+    let chars: Vec<char> = text.lines().nth(line)?.chars().collect();
+    let cursor = character.min(chars.len());
+    let start = (0..cursor).rev().take_while(|&i| is_label_char(chars[i])).last().unwrap_or(cursor);
+    let end = (cursor..chars.len()).take_while(|&i| is_label_char(chars[i])).last().map_or(cursor, |i| i + 1);
+    let before = start.checked_sub(1).map(|i| chars[i]);
+    Some((chars[start..end].iter().collect(), before))
+}
+
+fn completions(_: &str, text: &str, line: usize, character: usize) -> Json {
+    let item = |label: String, kind: usize, detail: String| {
+        Json::object(vec![("label", Json::from(label)), ("kind", Json::from(kind)), ("detail", Json::from(detail))])
+    };
+    let declared = |wanted: Kind, kind: usize, detail: &str| -> Vec<Json> {
+        declarations(text)
+            .into_iter()
+            .filter(|declaration| declaration.kind == wanted)
+            .map(|declaration| item(declaration.name, kind, detail.to_string()))
+            .collect()
+    };
+    let items = match word_at(text, line, character) {
+        Some((_, Some('$'))) => {
+            (0..REGISTER_COUNT).map(|n| item(n.to_string(), VARIABLE_KIND, format!("register ${}", n))).collect()
+        }
+        Some((_, Some('@'))) => declared(Kind::Label, REFERENCE_KIND, "label"),
+        Some((_, Some('#'))) => declared(Kind::Constant, CONSTANT_KIND, "constant"),
+        Some(_) if starts_statement(text, line, character) => (0..Opcode::IGL as u8)
+            .map(Opcode::from)
+            .map(|opcode| item(opcode.mnemonic().to_string(), KEYWORD_KIND, signature(opcode)))
+            .chain(declared(Kind::Macro, MODULE_KIND, "macro"))
+            .collect(),
+        _ => vec![],
+    };
+    Json::Array(items)
+}
+
+/// Returns true if the word at `character` is the first word of a statement,
+/// after any label declaration.
+fn starts_statement(text: &str, line: usize, character: usize) -> bool {
+    let before: String = text.lines().nth(line).unwrap_or("").chars().take(character).collect();
+    let (_, statement) = split_label(before.trim_start());
+    !statement.contains(char::is_whitespace) && !statement.starts_with('.')
+}
+
+fn definition(uri: &str, text: &str, line: usize, character: usize) -> Json {
+    let name = match word_at(text, line, character) {
+        Some((name, _)) if !name.is_empty() => name,
+        _ => return Json::Null,
+    };
+    match declarations(text).into_iter().find(|declaration| declaration.name == name) {
+        Some(declaration) => Json::object(vec![
+            ("uri", Json::from(uri)),
+            ("range", range(declaration.line, declaration.character, declaration.character + name.chars().count())),
+        ]),
+        None => Json::Null,
+    }
+}
+
+fn hover(_: &str, text: &str, line: usize, character: usize) -> Json {
+    let opcode = match word_at(text, line, character) {
+        Some((word, None)) | Some((word, Some(' ' | '\t' | ':'))) => Opcode::from_mnemonic(&word),
+        _ => None,
+    };
+    match opcode {
+        Some(opcode) => {
+            let value = format!("`{}`\n\n{}", signature(opcode), opcode.doc());
+            Json::object(vec![("contents", Json::object(vec![("kind", Json::from("markdown")), ("value", Json::from(value))]))])
+        }
+        None => Json::Null,
+    }
+}
+
+/// Returns how `opcode` is written, such as `load $reg #int`. Padding is
+/// written as a register.
+fn signature(opcode: Opcode) -> String {
+    let operands = opcode.operands().iter().map(|operand| match operand {
+        Operand::Register | Operand::Padding => "$reg",
+        Operand::Integer => "#int",
+    });
+    std::iter::once(opcode.mnemonic()).chain(operands).collect::<Vec<_>>().join(" ")
+}
+
+/// Reads one message framed with a `Content-Length` header, or returns `None`
+/// at the end of the input.
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let length = length.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "message has no Content-Length"))?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    String::from_utf8(body).map(Some).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn write_message(output: &mut impl Write, message: &Json) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+/// Serves one client over `input` and `output` until it sends `exit` or
+/// closes the input. Returns true if the client shut the server down first,
+/// as it should.
+pub fn serve(input: &mut impl BufRead, output: &mut impl Write) -> io::Result<bool> {
+    let mut server = Server::new();
+    while let Some(body) = read_message(input)? {
+        let replies = match Json::parse(&body) {
+            Ok(message) => server.handle(&message),
+            Err(e) => vec![error_response(Json::Null, PARSE_ERROR, &e.to_string())],
+        };
+        for reply in &replies {
+            write_message(output, reply)?;
+        }
+        if server.exited() {
+            break;
+        }
+    }
+    Ok(server.shut_down())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URI: &str = "file:///tmp/prog.iasm";
+    const SOURCE: &str = ".equ COUNT 3\nstart: load $0 #COUNT\nloop: dec $0\nload $1 @loop\njneq $1\nhlt\n";
+
+    fn request(id: usize, method: &str, params: Json) -> Json {
+        Json::object(vec![("jsonrpc", Json::from("2.0")), ("id", Json::from(id)), ("method", Json::from(method)), ("params", params)])
+    }
+
+    fn notification(method: &str, params: Json) -> Json {
+        Json::object(vec![("jsonrpc", Json::from("2.0")), ("method", Json::from(method)), ("params", params)])
+    }
+
+    fn open(server: &mut Server, text: &str) -> Vec<Json> {
+        let document = Json::object(vec![("uri", Json::from(URI)), ("languageId", Json::from("iasm")), ("text", Json::from(text))]);
+        server.handle(&notification("textDocument/didOpen", Json::object(vec![("textDocument", document)])))
+    }
+
+    /// Sends a request at a position in the open document and returns its result.
+    fn at(server: &mut Server, method: &str, line: usize, character: usize) -> Json {
+        let params = Json::object(vec![
+            ("textDocument", Json::object(vec![("uri", Json::from(URI))])),
+            ("position", Json::object(vec![("line", Json::from(line)), ("character", Json::from(character))])),
+        ]);
+        let replies = server.handle(&request(7, method, params));
+        replies[0].get("result").unwrap().clone()
+    }
+
+    fn labels(items: &Json) -> Vec<&str> {
+        match items {
+            Json::Array(items) => items.iter().filter_map(|item| item.get("label")?.as_str()).collect(),
+            _ => vec![],
+        }
+    }
+
+    #[test]
+    fn test_lifecycle() {
+        let mut server = Server::new();
+        let replies = server.handle(&request(1, "initialize", Json::object(vec![])));
+        let capabilities = replies[0].get("result").unwrap().get("capabilities").unwrap();
+        assert_eq!(capabilities.get("hoverProvider"), Some(&Json::Bool(true)));
+
+        let replies = server.handle(&request(2, "workspace/symbol", Json::Null));
+        assert_eq!(replies[0].get("error").unwrap().get("code"), Some(&Json::Number(METHOD_NOT_FOUND as f64)));
+
+        server.handle(&request(3, "shutdown", Json::Null));
+        assert!(server.shut_down());
+        assert!(!server.exited());
+        server.handle(&notification("exit", Json::Null));
+        assert!(server.exited());
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let mut server = Server::new();
+        let replies = open(&mut server, SOURCE);
+        assert_eq!(replies[0].get("params").unwrap().get("diagnostics"), Some(&Json::Array(vec![])));
+
+        let replies = open(&mut server, "hlt\nload $0 @nowhere ; oops\n");
+        let diagnostics = replies[0].get("params").unwrap().get("diagnostics").unwrap();
+        let Json::Array(diagnostics) = diagnostics else { panic!("diagnostics aren't an array") };
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].get("range"), Some(&range(1, 0, 23)));
+        assert_eq!(diagnostics[0].get("message").and_then(Json::as_str), Some("undefined label `nowhere`"));
+    }
+
+    #[test]
+    fn test_completion() {
+        let mut server = Server::new();
+        open(&mut server, &format!("{}load $2 @\nadd $0 $1 #\nj", SOURCE));
+        assert_eq!(labels(&at(&mut server, "textDocument/completion", 6, 9)), vec!["start", "loop"]);
+        assert_eq!(labels(&at(&mut server, "textDocument/completion", 7, 11)), vec!["COUNT"]);
+        assert_eq!(labels(&at(&mut server, "textDocument/completion", 6, 6)).len(), REGISTER_COUNT);
+        let opcodes = at(&mut server, "textDocument/completion", 8, 1);
+        assert!(labels(&opcodes).contains(&"jneq"));
+        assert!(!labels(&opcodes).contains(&"igl"));
+        assert_eq!(labels(&at(&mut server, "textDocument/completion", 7, 4)), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn test_definition() {
+        let mut server = Server::new();
+        open(&mut server, SOURCE);
+        let location = at(&mut server, "textDocument/definition", 3, 11);
+        assert_eq!(location.get("range"), Some(&range(2, 0, 4)));
+        assert_eq!(location.get("uri").and_then(Json::as_str), Some(URI));
+        let location = at(&mut server, "textDocument/definition", 1, 18);
+        assert_eq!(location.get("range"), Some(&range(0, 5, 10)));
+        assert_eq!(at(&mut server, "textDocument/definition", 5, 1), Json::Null);
+    }
+
+    #[test]
+    fn test_declarations_skip_the_directive() {
+        let columns: Vec<(String, usize)> = declarations(".macro m\n.endm\n  .equ e 1\n.equ qu 2\n")
+            .into_iter()
+            .map(|declaration| (declaration.name, declaration.character))
+            .collect();
+        assert_eq!(columns, [("m".to_string(), 7), ("e".to_string(), 7), ("qu".to_string(), 5)]);
+    }
+
+    #[test]
+    fn test_hover() {
+        let mut server = Server::new();
+        open(&mut server, SOURCE);
+        let hover = at(&mut server, "textDocument/hover", 4, 2);
+        let value = hover.get("contents").unwrap().get("value").and_then(Json::as_str).unwrap();
+        assert_eq!(value, "`jneq $reg`\n\nJNEQ $0: Sets the program counter to $0 if `VM.equal_flag` is not set.");
+        assert_eq!(at(&mut server, "textDocument/hover", 3, 10), Json::Null);
+    }
+
+    #[test]
+    fn test_serve() {
+        let mut input = vec![];
+        for message in [request(1, "shutdown", Json::Null), notification("exit", Json::Null)] {
+            write_message(&mut input, &message).unwrap();
+        }
+        let mut output = vec![];
+        assert!(serve(&mut input.as_slice(), &mut output).unwrap());
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(output, "Content-Length: 38\r\n\r\n{\"jsonrpc\":\"2.0\",\"id\":1,\"result\":null}");
+    }
+}
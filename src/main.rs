@@ -1,11 +1,360 @@
-#![warn(missing_docs)]
-#[macro_use]
-pub mod vm;
-pub mod instruction;
-pub mod repl;
-pub mod assembler;
+//! The `iridescent` binary. With no arguments it starts a REPL for feeding
+//! programs into the VM. `iridescent run` runs a program from a file,
+//! `iridescent asm` assembles one into bytecode or an object file, optionally
+//! optimizing it, `iridescent link` links object files into a program,
+//! `iridescent fmt` formats assembly source, `iridescent lint` warns about
+//! likely mistakes in it, `iridescent cfg` draws a program's control flow
+//! graph, `iridescent compile` compiles the high level language in
+//! `compiler`, and `iridescent lsp` runs a language server for editors over
+//! stdio.
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use iridescent::{
+    assemble_object, assemble_optimized, assemble_with_includes, compile, compile_to_assembly, format_source,
+    lint_source, Cfg, DebugInfo, Linker, Object, Opcode, VM,
+};
+
+mod repl;
+
+const USAGE: &str = "Usage:
+    iridescent
+    iridescent run <file> [-I <dir>]... [--profile] [--trace]
+    iridescent asm <file.iasm> [-I <dir>]... [-o <out>] [-O] [--debug | -c]
+    iridescent link <file.iro>... [-o <out>]
+    iridescent fmt [--check] <file.iasm>...
+    iridescent lint <file.iasm> [-I <dir>]...
+    iridescent cfg <file> [-I <dir>]... [--json] [-o <out>]
+    iridescent compile <file.iri> [-o <out>] [-S | --debug]
+    iridescent lsp";
+
+/// The extension of debug info sidecars, appended to the bytecode file name.
+const SIDECAR_EXTENSION: &str = "dbg";
+
+/// The extension given to object files by default.
+const OBJECT_EXTENSION: &str = "iro";
 
 fn main() {
-    let mut repl = repl::REPL::new();
-    repl.run();
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        None => {
+            let mut repl = repl::REPL::new();
+            repl.run();
+        }
+        Some("run") => run(&args[1..]),
+        Some("asm") => asm(&args[1..]),
+        Some("link") => link(&args[1..]),
+        Some("fmt") => fmt(&args[1..]),
+        Some("lint") => lint(&args[1..]),
+        Some("cfg") => cfg(&args[1..]),
+        Some("compile") => compile_file(&args[1..]),
+        Some("lsp") if args.len() == 1 => lsp(),
+        Some(_) => usage(),
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
+
+/// Prints `message` prefixed by `path` and exits with a failure status.
+fn fail(path: &str, message: &dyn std::fmt::Display) -> ! {
+    eprintln!("{}: {}", path, message);
+    process::exit(1);
+}
+
+/// Reads `path` as assembly source when it ends in `.iasm`, compiles it when
+/// it ends in `.iri`, and reads it as bytecode with an optional debug info
+/// sidecar otherwise.
+fn load(path: &str, include_path: &[PathBuf]) -> (Vec<u8>, Option<DebugInfo>) {
+    if path.ends_with(".iasm") {
+        let (bytecode, debug_info) = assemble_file(path, include_path);
+        return (bytecode, Some(debug_info));
+    }
+    if path.ends_with(".iri") {
+        let source = fs::read_to_string(path).unwrap_or_else(|e| fail(path, &e));
+        let (bytecode, debug_info) = compile(&source, path).unwrap_or_else(|e| fail(path, &e));
+        return (bytecode, Some(debug_info));
+    }
+    let bytecode = fs::read(path).unwrap_or_else(|e| fail(path, &e));
+    let sidecar_path = format!("{}.{}", path, SIDECAR_EXTENSION);
+    let debug_info = match fs::read_to_string(&sidecar_path) {
+        Ok(sidecar) => Some(DebugInfo::from_sidecar(&sidecar).unwrap_or_else(|e| fail(&sidecar_path, &e))),
+        Err(_) => None,
+    };
+    (bytecode, debug_info)
+}
+
+/// Assembles the source file at `path`, exiting on failure.
+fn assemble_file(path: &str, include_path: &[PathBuf]) -> (Vec<u8>, DebugInfo) {
+    let source = fs::read_to_string(path).unwrap_or_else(|e| fail(path, &e));
+    assemble_with_includes(&source, path, include_path).unwrap_or_else(|e| fail(path, &e))
+}
+
+/// Handles `iridescent run <file> [-I <dir>]... [--profile] [--trace]`.
+fn run(args: &[String]) {
+    let mut path = None;
+    let mut include_path = vec![];
+    let mut profile = false;
+    let mut trace = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--profile" => profile = true,
+            "--trace" => trace = true,
+            "-I" => include_path.push(PathBuf::from(args.next().unwrap_or_else(|| usage()))),
+            _ if path.is_none() => path = Some(arg.as_str()),
+            _ => usage(),
+        }
+    }
+    let path = path.unwrap_or_else(|| usage());
+
+    let (bytecode, debug_info) = load(path, &include_path);
+    let mut vm = VM::builder().program(bytecode).build_verified().unwrap_or_else(|violations| {
+        for violation in violations {
+            eprintln!("{}: {}", path, violation);
+        }
+        process::exit(1);
+    });
+    vm.set_debug_info(debug_info);
+    if profile {
+        vm.enable_profiler();
+    }
+
+    let result = loop {
+        if trace && vm.pc() < vm.program().len() {
+            let opcode = Opcode::from(vm.program()[vm.pc()]);
+            println!("{}: {}", vm.describe(vm.pc()), opcode.mnemonic());
+        }
+        let pc = vm.pc();
+        match vm.step() {
+            Ok(false) => {}
+            Ok(true) => break Ok(()),
+            Err(fault) => break Err((pc, fault)),
+        }
+    };
+    if let Some(report) = vm.profile() {
+        print!("{}", report.report(vm.debug_info()));
+    }
+    if let Err((pc, fault)) = result {
+        fail(path, &format!("{} at {}", fault, vm.describe(pc)));
+    }
+}
+
+/// Handles `iridescent asm <file.iasm> [-I <dir>]... [-o <out>] [-O] [--debug | -c]`.
+/// With `-O` the program is optimized, and each change is printed.
+fn asm(args: &[String]) {
+    let mut path = None;
+    let mut include_path = vec![];
+    let mut out = None;
+    let mut debug = false;
+    let mut object = false;
+    let mut optimize = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--debug" => debug = true,
+            "-c" => object = true,
+            "-O" => optimize = true,
+            "-o" => out = Some(args.next().unwrap_or_else(|| usage()).clone()),
+            "-I" => include_path.push(PathBuf::from(args.next().unwrap_or_else(|| usage()))),
+            _ if path.is_none() => path = Some(arg.as_str()),
+            _ => usage(),
+        }
+    }
+    let path = path.unwrap_or_else(|| usage());
+    if object && (debug || optimize) {
+        usage();
+    }
+    let extension = if object { OBJECT_EXTENSION } else { "bin" };
+    let out = out.unwrap_or_else(|| Path::new(path).with_extension(extension).to_string_lossy().into_owned());
+
+    if object {
+        let source = fs::read_to_string(path).unwrap_or_else(|e| fail(path, &e));
+        let object = assemble_object(&source, path, &include_path).unwrap_or_else(|e| fail(path, &e));
+        fs::write(&out, object.to_bytes()).unwrap_or_else(|e| fail(&out, &e));
+        return;
+    }
+    let (bytecode, debug_info) = if optimize {
+        let source = fs::read_to_string(path).unwrap_or_else(|e| fail(path, &e));
+        let (bytecode, debug_info, changes) =
+            assemble_optimized(&source, path, &include_path).unwrap_or_else(|e| fail(path, &e));
+        for change in changes {
+            println!("{}: {}", path, change);
+        }
+        (bytecode, debug_info)
+    } else {
+        assemble_file(path, &include_path)
+    };
+    fs::write(&out, bytecode).unwrap_or_else(|e| fail(&out, &e));
+    if debug {
+        let sidecar_path = format!("{}.{}", out, SIDECAR_EXTENSION);
+        fs::write(&sidecar_path, debug_info.to_sidecar()).unwrap_or_else(|e| fail(&sidecar_path, &e));
+    }
+}
+
+/// Handles `iridescent link <file.iro>... [-o <out>]`. Execution starts at
+/// the first object.
+fn link(args: &[String]) {
+    let mut paths = vec![];
+    let mut out = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => out = Some(args.next().unwrap_or_else(|| usage()).clone()),
+            _ => paths.push(arg.as_str()),
+        }
+    }
+    let first = paths.first().cloned().unwrap_or_else(|| usage());
+    let out = out.unwrap_or_else(|| Path::new(first).with_extension("bin").to_string_lossy().into_owned());
+
+    let mut linker = Linker::new();
+    for path in &paths {
+        let bytes = fs::read(path).unwrap_or_else(|e| fail(path, &e));
+        let object = Object::from_bytes(&bytes).unwrap_or_else(|e| fail(path, &e));
+        linker = linker.add(path, object);
+    }
+    let program = linker.link().unwrap_or_else(|errors| {
+        for error in errors {
+            eprintln!("{}", error);
+        }
+        process::exit(1);
+    });
+    fs::write(&out, program).unwrap_or_else(|e| fail(&out, &e));
+}
+
+/// Handles `iridescent fmt [--check] <file.iasm>...`. Files are rewritten in
+/// place, or with `--check` left alone and listed if they aren't formatted.
+fn fmt(args: &[String]) {
+    let check = args.iter().any(|arg| arg == "--check");
+    let paths: Vec<&String> = args.iter().filter(|arg| *arg != "--check").collect();
+    if paths.is_empty() {
+        usage();
+    }
+    let mut unformatted = false;
+    for path in paths {
+        let source = fs::read_to_string(path).unwrap_or_else(|e| fail(path, &e));
+        let formatted = format_source(&source).unwrap_or_else(|e| fail(path, &e));
+        if formatted == source {
+            continue;
+        }
+        if check {
+            println!("{}", path);
+            unformatted = true;
+        } else {
+            fs::write(path, formatted).unwrap_or_else(|e| fail(path, &e));
+        }
+    }
+    if unformatted {
+        process::exit(1);
+    }
+}
+
+/// Handles `iridescent lint <file.iasm> [-I <dir>]...`, exiting
+/// unsuccessfully if there are any warnings.
+fn lint(args: &[String]) {
+    let mut path = None;
+    let mut include_path = vec![];
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-I" => include_path.push(PathBuf::from(args.next().unwrap_or_else(|| usage()))),
+            _ if path.is_none() => path = Some(arg.as_str()),
+            _ => usage(),
+        }
+    }
+    let path = path.unwrap_or_else(|| usage());
+
+    let source = fs::read_to_string(path).unwrap_or_else(|e| fail(path, &e));
+    let (warnings, debug_info) = lint_source(&source, path, &include_path).unwrap_or_else(|e| fail(path, &e));
+    for warning in &warnings {
+        println!("{}: {} at {}", path, warning, debug_info.describe(warning.offset()));
+    }
+    if !warnings.is_empty() {
+        process::exit(1);
+    }
+}
+
+/// Handles `iridescent cfg <file> [-I <dir>]... [--json] [-o <out>]`,
+/// writing the graph as Graphviz DOT, or JSON with `--json`, to `out` or
+/// standard output.
+fn cfg(args: &[String]) {
+    let mut path = None;
+    let mut include_path = vec![];
+    let mut json = false;
+    let mut out = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--json" => json = true,
+            "-o" => out = Some(args.next().unwrap_or_else(|| usage()).clone()),
+            "-I" => include_path.push(PathBuf::from(args.next().unwrap_or_else(|| usage()))),
+            _ if path.is_none() => path = Some(arg.as_str()),
+            _ => usage(),
+        }
+    }
+    let path = path.unwrap_or_else(|| usage());
+
+    let (bytecode, debug_info) = load(path, &include_path);
+    let graph = Cfg::build(&bytecode, debug_info.as_ref());
+    let output = if json {
+        format!("{}\n", graph.to_json(debug_info.as_ref()))
+    } else {
+        graph.to_dot(debug_info.as_ref())
+    };
+    match out {
+        Some(out) => fs::write(&out, output).unwrap_or_else(|e| fail(&out, &e)),
+        None => print!("{}", output),
+    }
+}
+
+/// Handles `iridescent compile <file.iri> [-o <out>] [-S | --debug]`. With
+/// `-S` the output is assembly source instead of bytecode.
+fn compile_file(args: &[String]) {
+    let mut path = None;
+    let mut out = None;
+    let mut assembly = false;
+    let mut debug = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-S" => assembly = true,
+            "--debug" => debug = true,
+            "-o" => out = Some(args.next().unwrap_or_else(|| usage()).clone()),
+            _ if path.is_none() => path = Some(arg.as_str()),
+            _ => usage(),
+        }
+    }
+    let path = path.unwrap_or_else(|| usage());
+    if assembly && debug {
+        usage();
+    }
+    let extension = if assembly { "iasm" } else { "bin" };
+    let out = out.unwrap_or_else(|| Path::new(path).with_extension(extension).to_string_lossy().into_owned());
+
+    let source = fs::read_to_string(path).unwrap_or_else(|e| fail(path, &e));
+    if assembly {
+        let assembly = compile_to_assembly(&source).unwrap_or_else(|e| fail(path, &e));
+        fs::write(&out, assembly).unwrap_or_else(|e| fail(&out, &e));
+        return;
+    }
+    let (bytecode, debug_info) = compile(&source, path).unwrap_or_else(|e| fail(path, &e));
+    fs::write(&out, bytecode).unwrap_or_else(|e| fail(&out, &e));
+    if debug {
+        let sidecar_path = format!("{}.{}", out, SIDECAR_EXTENSION);
+        fs::write(&sidecar_path, debug_info.to_sidecar()).unwrap_or_else(|e| fail(&sidecar_path, &e));
+    }
+}
+
+/// Handles `iridescent lsp`, exiting unsuccessfully if the client exits
+/// without shutting the server down first.
+fn lsp() {
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    let shut_down = iridescent::serve_lsp(&mut stdin.lock(), &mut stdout.lock()).unwrap_or_else(|e| fail("lsp", &e));
+    process::exit(if shut_down { 0 } else { 1 });
 }
@@ -0,0 +1,231 @@
+//! `object` holds relocatable object files: bytecode assembled on its own,
+//! along with the labels it exports and the places that must be patched once
+//! the linker knows where everything ends up.
+//!
+//! The layout, with every integer big-endian, is:
+//!
+//! | bytes | contents                                       |
+//! |-------|------------------------------------------------|
+//! | 4     | magic, `IROB`                                  |
+//! | 2     | format version                                 |
+//! | 4     | code length, as `u32`                          |
+//! | n     | code                                           |
+//! | 4     | export count, as `u32`                         |
+//! |       | each export: a name, then its offset as `u32`  |
+//! | 4     | relocation count, as `u32`                     |
+//! |       | each relocation: its offset as `u32`, then 0 for an offset within this object, or 1 and a symbol name |
+//!
+//! Names are written as a `u16` length followed by that many bytes of UTF-8.
+use std::error::Error;
+use std::fmt;
+
+const MAGIC: &[u8; 4] = b"IROB";
+
+/// The object file format version written by `Object::to_bytes`.
+pub const OBJECT_VERSION: u16 = 1;
+
+/// What a relocated operand refers to.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Target {
+    /// An offset within the same object, which moves with the object.
+    Local,
+    /// A label exported by some object, possibly this one.
+    Symbol(String),
+}
+
+/// A 16 bit integer operand to patch at link time. The operand already holds
+/// the addend: the local offset for `Target::Local`, or a value to add to the
+/// symbol's address for `Target::Symbol`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Relocation {
+    /// Where the operand starts in the object's code.
+    pub offset: usize,
+    /// What the operand refers to.
+    pub target: Target,
+}
+
+/// Relocatable bytecode produced by `assemble_object`.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct Object {
+    /// The bytecode, with relocated operands holding their addends.
+    pub code: Vec<u8>,
+    /// The labels declared `.global`, with their offsets in `code`.
+    pub exports: Vec<(String, usize)>,
+    /// The operands to patch when linking.
+    pub relocations: Vec<Relocation>,
+}
+
+/// Reasons an object file can fail to read.
+#[derive(Debug, PartialEq, Clone)]
+pub enum ObjectError {
+    /// The bytes do not start with the object file magic bytes.
+    BadMagic,
+    /// The object was written by a format version this build can't read.
+    UnsupportedVersion(u16),
+    /// The bytes ended before the whole object was read.
+    Truncated,
+    /// The bytes were read fully but describe an impossible object.
+    Malformed(&'static str),
+}
+
+impl fmt::Display for ObjectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ObjectError::BadMagic => write!(f, "not an iridescent object file"),
+            ObjectError::UnsupportedVersion(version) => {
+                write!(f, "unsupported object file version {}", version)
+            }
+            ObjectError::Truncated => write!(f, "object file is truncated"),
+            ObjectError::Malformed(reason) => write!(f, "malformed object file: {}", reason),
+        }
+    }
+}
+
+impl Error for ObjectError {}
+
+/// Reads fields from the front of an object file.
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], ObjectError> {
+        if self.bytes.len() < n {
+            return Err(ObjectError::Truncated);
+        }
+        let (head, tail) = self.bytes.split_at(n);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, ObjectError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, ObjectError> {
+        let mut buf = [0; 2];
+        buf.copy_from_slice(self.take(2)?);
+        Ok(u16::from_be_bytes(buf))
+    }
+
+    fn u32(&mut self) -> Result<u32, ObjectError> {
+        let mut buf = [0; 4];
+        buf.copy_from_slice(self.take(4)?);
+        Ok(u32::from_be_bytes(buf))
+    }
+
+    fn name(&mut self) -> Result<String, ObjectError> {
+        let length = self.u16()? as usize;
+        String::from_utf8(self.take(length)?.to_vec()).map_err(|_| ObjectError::Malformed("name is not UTF-8"))
+    }
+}
+
+fn write_name(bytes: &mut Vec<u8>, name: &str) {
+    bytes.extend_from_slice(&(name.len() as u16).to_be_bytes());
+    bytes.extend_from_slice(name.as_bytes());
+}
+
+impl Object {
+    /// Serializes the object.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&OBJECT_VERSION.to_be_bytes());
+        bytes.extend_from_slice(&(self.code.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&self.code);
+        bytes.extend_from_slice(&(self.exports.len() as u32).to_be_bytes());
+        for (name, offset) in &self.exports {
+            write_name(&mut bytes, name);
+            bytes.extend_from_slice(&(*offset as u32).to_be_bytes());
+        }
+        bytes.extend_from_slice(&(self.relocations.len() as u32).to_be_bytes());
+        for relocation in &self.relocations {
+            bytes.extend_from_slice(&(relocation.offset as u32).to_be_bytes());
+            match &relocation.target {
+                Target::Local => bytes.push(0),
+                Target::Symbol(name) => {
+                    bytes.push(1);
+                    write_name(&mut bytes, name);
+                }
+            }
+        }
+        bytes
+    }
+
+    /// Reads an object written by `to_bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Object, ObjectError> {
+        let mut reader = Reader { bytes };
+        if reader.take(MAGIC.len()).map_err(|_| ObjectError::BadMagic)? != MAGIC {
+            return Err(ObjectError::BadMagic);
+        }
+        let version = reader.u16()?;
+        if version != OBJECT_VERSION {
+            return Err(ObjectError::UnsupportedVersion(version));
+        }
+
+        let mut object = Object::default();
+        let length = reader.u32()? as usize;
+        object.code = reader.take(length)?.to_vec();
+        for _ in 0..reader.u32()? {
+            let name = reader.name()?;
+            let offset = reader.u32()? as usize;
+            if offset > object.code.len() {
+                return Err(ObjectError::Malformed("export is past the end of the code"));
+            }
+            object.exports.push((name, offset));
+        }
+        for _ in 0..reader.u32()? {
+            let offset = reader.u32()? as usize;
+            let target = match reader.u8()? {
+                0 => Target::Local,
+                1 => Target::Symbol(reader.name()?),
+                _ => return Err(ObjectError::Malformed("unknown relocation kind")),
+            };
+            if offset + 2 > object.code.len() {
+                return Err(ObjectError::Malformed("relocation is past the end of the code"));
+            }
+            object.relocations.push(Relocation { offset, target });
+        }
+        if !reader.bytes.is_empty() {
+            return Err(ObjectError::Malformed("trailing bytes after the object"));
+        }
+        Ok(object)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_object() -> Object {
+        Object {
+            code: vec![0, 0, 0, 4, 6, 0, 0, 1, 0, 0],
+            exports: vec![("main".to_string(), 0)],
+            relocations: vec![
+                Relocation { offset: 2, target: Target::Local },
+                Relocation { offset: 8, target: Target::Symbol("print".to_string()) },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_object_round_trip() {
+        let object = test_object();
+        assert_eq!(Object::from_bytes(&object.to_bytes()), Ok(object));
+    }
+
+    #[test]
+    fn test_object_rejects_bad_bytes() {
+        let bytes = test_object().to_bytes();
+        assert_eq!(Object::from_bytes(b"nope"), Err(ObjectError::BadMagic));
+        assert_eq!(Object::from_bytes(&bytes[..bytes.len() - 1]), Err(ObjectError::Truncated));
+
+        let mut future = bytes.clone();
+        future[5] = 9;
+        assert_eq!(Object::from_bytes(&future), Err(ObjectError::UnsupportedVersion(9)));
+
+        let mut trailing = bytes;
+        trailing.push(0);
+        assert!(matches!(Object::from_bytes(&trailing), Err(ObjectError::Malformed(_))));
+    }
+}
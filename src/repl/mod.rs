@@ -1,38 +1,119 @@
-use std;
+//! `repl` provides an interactive prompt for feeding programs into the VM.
+use std::fs;
 use std::io;
 use std::io::Write;
-use nom::types::CompleteStr;
 use std::num::ParseIntError;
-use crate::assembler::program_parsers::{Program, program};
-use crate::instruction;
-use crate::vm::VM;
+use iridescent::{assemble, assemble_with_debug_info};
+use iridescent::VM;
 
+/// Provides a repl
+#[allow(clippy::upper_case_acronyms)]
 pub struct REPL {
-    /// Provides a repl
+    /// Every command entered so far, oldest first.
     pub command_buffer: Vec<String>,
+    /// The VM commands are executed against.
     pub vm: VM,
 }
 
+impl Default for REPL {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 
 impl REPL {
     /// Provides a new instance of a REPL to feed programs into the VM.
     pub fn new() -> REPL {
+        let mut vm = VM::new();
+        vm.enable_journal();
+        vm.enable_profiler();
         REPL {
-            vm: VM::new(),
+            vm,
             command_buffer: vec![]
         }
     }
 
+    /// Handles `.back [n]`, undoing the last `n` steps (1 by default).
+    fn back(&mut self, arg: Option<&str>) {
+        let n = match arg.map(str::parse::<usize>) {
+            None => 1,
+            Some(Ok(n)) => n,
+            Some(Err(_)) => {
+                println!("Usage: .back [n]");
+                return;
+            }
+        };
+        let undone = self.vm.back(n);
+        println!("Went back {} step(s)", undone);
+        self.print_position();
+    }
+
+    /// Handles `.goto-step N`, moving to the state after step `N`.
+    fn goto_step(&mut self, arg: Option<&str>) {
+        let n = match arg.map(str::parse::<usize>) {
+            Some(Ok(n)) => n,
+            _ => {
+                println!("Usage: .goto-step N");
+                return;
+            }
+        };
+        if let Err(fault) = self.vm.goto_step(n) {
+            println!("Fault: {}", fault);
+        }
+        self.print_position();
+    }
+
+    /// Handles `.load <file>`, replacing the program with an assembled file.
+    /// The journal and profile restart from the new program.
+    fn load(&mut self, arg: Option<&str>) {
+        let path = match arg {
+            Some(path) => path,
+            None => {
+                println!("Usage: .load <file>");
+                return;
+            }
+        };
+        let source = match fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(e) => {
+                println!("Unable to read {}: {}", path, e);
+                return;
+            }
+        };
+        match assemble_with_debug_info(&source, path) {
+            Ok((bytecode, debug_info)) => {
+                if let Err(violations) = iridescent::verify(&bytecode) {
+                    for violation in violations {
+                        println!("{}: {}", path, violation);
+                    }
+                    return;
+                }
+                self.vm.load_program(bytecode);
+                self.vm.set_debug_info(Some(debug_info));
+                self.vm.enable_journal();
+                self.vm.enable_profiler();
+                println!("Loaded {} bytes from {}", self.vm.program().len(), path);
+            }
+            Err(e) => println!("{}: {}", path, e),
+        }
+    }
+
+    fn print_position(&self) {
+        let step = self.vm.journal().map_or(0, |journal| journal.step());
+        println!("At step {}, pc {}", step, self.vm.describe(self.vm.pc()));
+    }
+
     /// Expects a hexadecimal string, not including a leading `0x`, and returns
     /// a vector of u8's.
     /// An example LOAD command would be `00 01 03 E8`.
     fn parse_hex(&mut self, instruction: &str) -> Result<Vec<u8>, ParseIntError> {
-        let split = instruction.split(" ").collect::<Vec<&str>>();
+        let split = instruction.split(' ').collect::<Vec<&str>>();
 
         let mut results: Vec<u8> = vec![];
 
         for hex_string in split {
-            let byte = u8::from_str_radix(&hex_string, 16);
+            let byte = u8::from_str_radix(hex_string, 16);
             
             match byte {
                 Ok(result) => {
@@ -45,19 +126,10 @@ impl REPL {
         }
         Ok(results)
     }
-    fn program(&mut self, _args: &[&str]) {
-        // self.send_message("Listing instructions currently in VM's program vector: ".to_string());
-        let mut results = vec![];
-        for instruction in &self.vm.program {
-            results.push(instruction.clone())
-        }
-        // self.send_message(format!("{:#?}", results));
-        // self.send_message("End of Program Listing".to_string());
-    }
 
+    /// Runs the repl in the terminal, allows for viewing
+    /// the history of instructions fed to the repl.
     pub fn run(&mut self) {
-        /// Runs the repl in the terminal, allows for viewing
-        /// the history of instructions fed to the repl.
         println!("Welcome to Iridescent! May your code compile.");
 
         loop {
@@ -73,7 +145,8 @@ impl REPL {
 
             self.command_buffer.push(buffer.to_string());
 
-            match buffer {
+            let mut words = buffer.split_whitespace();
+            match words.next().unwrap_or("") {
                 ".quit" => {
                     println!("Farewell!");
                     std::process::exit(0);
@@ -86,34 +159,45 @@ impl REPL {
                 }
                 ".program" => {
                     println!("Listing instructions current in VM.s program vector:");
-                    for instruction in &self.vm.program {
+                    for instruction in self.vm.program() {
                         println!("{}", instruction);
                     }
                     println!("End of program instructions");
                 }
                 ".registers" => {
                     println!("Listing registers and all contents:");
-                    println!("{:#?}", self.vm.registers);
+                    println!("{:#?}", self.vm.registers());
                     println!("End of Register Listing")
                 }
+                ".profile" => {
+                    if let Some(profile) = self.vm.profile() {
+                        print!("{}", profile.report(self.vm.debug_info()));
+                    }
+                }
+                ".load" => self.load(words.next()),
+                ".back" => self.back(words.next()),
+                ".goto-step" => self.goto_step(words.next()),
                 _ => {
-                    println!("Error");
+                    // Input is either assembly (`load $0 #100`) or raw hex
+                    // bytes (`00 00 00 64`).
+                    let bytecode = match assemble(buffer) {
+                        Ok(bytes) => bytes,
+                        Err(e) => match self.parse_hex(buffer) {
+                            Ok(bytes) => bytes,
+                            Err(_) => {
+                                println!("Unable to parse input: {}", e.message);
+                                continue;
+                            }
+                        },
+                    };
+                    for byte in bytecode {
+                        self.vm.add_byte(byte);
+                    }
+                    if let Err(fault) = self.vm.step() {
+                        println!("Fault: {}", fault);
+                    }
                 }
-                // _ => {
-                //     let parsed_program = program.push((CompleteStr(buffer));
-                //     if !parsed_program.is_ok() {
-                //         println!("Unable to parse input");
-                //         continue;
-                //     }
-                //     let (_, result) = parsed_program.unwrap();
-                //     let bytecode = result.to_bytes();
-                //     // TODO: Make a function to let us add bytes to the VM
-                //     for byte in bytecode {
-                //         self.vm.add_byte(byte);
-                //     }
-                //     self.vm.run_once();
-                // }
             }
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,352 @@
+//! `runtime` runs many VMs concurrently on the current thread, giving each
+//! running VM a quantum of instructions in turn.
+//!
+//! VMs on the same runtime can message each other: SEND names the target by
+//! its `VmId::index`, and the runtime moves the message into the target's
+//! mailbox. A VM whose target's mailbox is full, or that is waiting on RECV,
+//! is blocked and yields its turn until it can continue.
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::vm::{Fault, VM};
+
+/// How many instructions a VM executes per turn unless configured otherwise.
+pub const DEFAULT_QUANTUM: usize = 100;
+
+/// Identifies a VM spawned on a `Runtime`.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
+pub struct VmId(usize);
+
+impl VmId {
+    /// Returns the number programs use to name this VM in SEND.
+    pub fn index(self) -> usize {
+        self.0
+    }
+}
+
+impl fmt::Display for VmId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "vm{}", self.0)
+    }
+}
+
+/// Where a VM spawned on a `Runtime` is in its life.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Status {
+    /// The VM still has instructions to execute.
+    Running,
+    /// The VM is waiting for a message to arrive, or for room in the
+    /// mailbox of the VM it is sending to.
+    Blocked,
+    /// The VM executed HLT or ran off the end of its program.
+    Halted,
+    /// The VM stopped because of a fault.
+    Faulted(Fault),
+    /// The VM was stopped by `Runtime::kill`.
+    Killed,
+}
+
+impl Status {
+    /// Returns true once the VM will execute no more instructions.
+    pub fn is_finished(&self) -> bool {
+        !matches!(self, Status::Running | Status::Blocked)
+    }
+}
+
+#[derive(Debug)]
+struct Task {
+    vm: VM,
+    status: Status,
+}
+
+/// Owns a set of VMs and schedules them round-robin.
+#[derive(Debug)]
+pub struct Runtime {
+    tasks: BTreeMap<VmId, Task>,
+    next_id: usize,
+    quantum: usize,
+}
+
+impl Default for Runtime {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Runtime {
+    /// Returns an empty runtime using `DEFAULT_QUANTUM`.
+    pub fn new() -> Runtime {
+        Runtime::with_quantum(DEFAULT_QUANTUM)
+    }
+
+    /// Returns an empty runtime that lets each VM execute `quantum`
+    /// instructions per turn. A quantum of 0 is treated as 1.
+    pub fn with_quantum(quantum: usize) -> Runtime {
+        Runtime {
+            tasks: BTreeMap::new(),
+            next_id: 0,
+            quantum: quantum.max(1),
+        }
+    }
+
+    /// Adds `vm` to the runtime, to be run from its current pc.
+    pub fn spawn(&mut self, vm: VM) -> VmId {
+        let id = VmId(self.next_id);
+        self.next_id += 1;
+        self.tasks.insert(id, Task { vm, status: Status::Running });
+        id
+    }
+
+    /// Stops `id` from executing any further. Returns false if there is no
+    /// such VM or it had already finished.
+    pub fn kill(&mut self, id: VmId) -> bool {
+        match self.tasks.get_mut(&id) {
+            Some(task) if !task.status.is_finished() => {
+                task.status = Status::Killed;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Runs every VM until `id` finishes, returning its final status, or
+    /// `None` if there is no such VM. If every unfinished VM is blocked the
+    /// VMs are deadlocked, and `Status::Blocked` is returned instead.
+    pub fn join(&mut self, id: VmId) -> Option<Status> {
+        loop {
+            match self.status(id) {
+                None => return None,
+                Some(status) if status.is_finished() => return Some(status.clone()),
+                Some(_) => {
+                    if !self.tick() {
+                        return self.status(id).cloned();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Runs every VM until all of them have finished or are deadlocked.
+    pub fn run(&mut self) {
+        while self.tick() {}
+    }
+
+    /// Gives each unfinished VM one turn. Returns true if any VM made
+    /// progress and some are still unfinished; false means every VM has
+    /// finished or the remaining ones are deadlocked.
+    pub fn tick(&mut self) -> bool {
+        let ids: Vec<VmId> = self.tasks.keys().cloned().collect();
+        let mut progress = false;
+        for id in ids {
+            progress |= self.turn(id);
+        }
+        progress && self.tasks.values().any(|task| !task.status.is_finished())
+    }
+
+    /// Runs up to a quantum of instructions on `id`, returning true if it
+    /// made progress.
+    fn turn(&mut self, id: VmId) -> bool {
+        let mut progress = self.flush(id);
+        for _ in 0..self.quantum {
+            let task = match self.tasks.get_mut(&id) {
+                Some(task) if !task.status.is_finished() => task,
+                _ => break,
+            };
+            if task.vm.outbox().next().is_some() || task.vm.is_blocked() {
+                task.status = Status::Blocked;
+                break;
+            }
+            task.status = Status::Running;
+            progress = true;
+            match task.vm.step() {
+                Ok(false) => {}
+                Ok(true) => task.status = Status::Halted,
+                Err(fault) => task.status = Status::Faulted(fault),
+            }
+            self.flush(id);
+        }
+        progress
+    }
+
+    /// Delivers the messages `id` has sent, in order, stopping at the first
+    /// whose target mailbox is full. A message to a VM that doesn't exist or
+    /// has finished faults the sender. Returns true if anything was delivered.
+    fn flush(&mut self, id: VmId) -> bool {
+        let mut delivered = false;
+        loop {
+            let message = match self.tasks.get_mut(&id) {
+                Some(task) if !task.status.is_finished() => task.vm.take_message(),
+                _ => None,
+            };
+            let (target, value) = match message {
+                Some(message) => message,
+                None => return delivered,
+            };
+            let receiver = if target < 0 {
+                None
+            } else {
+                self.tasks
+                    .get_mut(&VmId(target as usize))
+                    .filter(|task| !task.status.is_finished())
+            };
+            let accepted = match receiver {
+                Some(receiver) => receiver.vm.deliver(value),
+                None => {
+                    if let Some(task) = self.tasks.get_mut(&id) {
+                        task.status = Status::Faulted(Fault::NoSuchVm(target));
+                    }
+                    return delivered;
+                }
+            };
+            if !accepted {
+                if let Some(task) = self.tasks.get_mut(&id) {
+                    task.vm.return_message((target, value));
+                }
+                return delivered;
+            }
+            delivered = true;
+        }
+    }
+
+    /// Returns the status of `id`.
+    pub fn status(&self, id: VmId) -> Option<&Status> {
+        self.tasks.get(&id).map(|task| &task.status)
+    }
+
+    /// Returns the status of every VM, ordered by when it was spawned.
+    pub fn statuses(&self) -> Vec<(VmId, &Status)> {
+        self.tasks.iter().map(|(&id, task)| (id, &task.status)).collect()
+    }
+
+    /// Returns the VM `id`, to inspect its registers or state.
+    pub fn vm(&self, id: VmId) -> Option<&VM> {
+        self.tasks.get(&id).map(|task| &task.vm)
+    }
+
+    /// Returns the VM `id` mutably, for example to pass it the ids of the VMs
+    /// it should talk to.
+    pub fn vm_mut(&mut self, id: VmId) -> Option<&mut VM> {
+        self.tasks.get_mut(&id).map(|task| &mut task.vm)
+    }
+
+    /// Removes `id` from the runtime, handing its VM back.
+    pub fn remove(&mut self, id: VmId) -> Option<VM> {
+        self.tasks.remove(&id).map(|task| task.vm)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // load $5 #1; syscall $5; syscall $5; hlt
+    fn logging_vm(name: i32, log: &Rc<RefCell<Vec<i32>>>) -> VM {
+        let log = Rc::clone(log);
+        VM::builder()
+            .program(vec![0, 5, 0, 1, 17, 5, 17, 5, 5])
+            .syscall(1, move |_| {
+                log.borrow_mut().push(name);
+                Ok(0)
+            })
+            .build()
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let log = Rc::new(RefCell::new(vec![]));
+        let mut runtime = Runtime::with_quantum(1);
+        let a = runtime.spawn(logging_vm(1, &log));
+        let b = runtime.spawn(logging_vm(2, &log));
+        runtime.run();
+
+        assert_eq!(*log.borrow(), vec![1, 2, 1, 2]);
+        assert_eq!(runtime.status(a), Some(&Status::Halted));
+        assert_eq!(runtime.status(b), Some(&Status::Halted));
+    }
+
+    #[test]
+    fn test_join_and_kill() {
+        let log = Rc::new(RefCell::new(vec![]));
+        let mut runtime = Runtime::with_quantum(1);
+        let a = runtime.spawn(logging_vm(1, &log));
+        let b = runtime.spawn(logging_vm(2, &log));
+        runtime.tick();
+        assert!(runtime.kill(b));
+        assert!(!runtime.kill(b));
+
+        assert_eq!(runtime.join(a), Some(Status::Halted));
+        assert_eq!(*log.borrow(), vec![1, 1]);
+        assert_eq!(runtime.status(b), Some(&Status::Killed));
+        assert!(runtime.remove(b).is_some());
+        assert_eq!(runtime.join(b), None);
+    }
+
+    #[test]
+    fn test_fault_status() {
+        let mut runtime = Runtime::new();
+        let id = runtime.spawn(VM::builder().program(vec![17, 0]).build());
+        runtime.run();
+        assert_eq!(runtime.status(id), Some(&Status::Faulted(Fault::UnknownSyscall(0))));
+        assert_eq!(runtime.statuses().len(), 1);
+    }
+
+    #[test]
+    fn test_ping_pong() {
+        // Sends $1 to the VM in $0, then waits for the reply in $2.
+        let ping = VM::builder().program(vec![18, 0, 1, 19, 2, 5]).register(1, 41).build();
+        // Waits for a message in $1, adds one and sends it back to the VM in $0.
+        let pong = VM::builder()
+            .program(vec![19, 1, 0, 2, 0, 1, 1, 1, 2, 1, 18, 0, 1, 5])
+            .build();
+        let mut runtime = Runtime::with_quantum(1);
+        let a = runtime.spawn(ping);
+        let b = runtime.spawn(pong);
+        runtime.vm_mut(a).unwrap().set_register(0, b.index() as i32);
+        runtime.vm_mut(b).unwrap().set_register(0, a.index() as i32);
+
+        assert_eq!(runtime.join(a), Some(Status::Halted));
+        assert_eq!(runtime.vm(a).unwrap().register(2), 42);
+    }
+
+    #[test]
+    fn test_send_to_missing_vm() {
+        let mut runtime = Runtime::new();
+        let id = runtime.spawn(VM::builder().program(vec![18, 0, 1]).register(0, 7).build());
+        runtime.run();
+        assert_eq!(runtime.status(id), Some(&Status::Faulted(Fault::NoSuchVm(7))));
+    }
+
+    #[test]
+    fn test_full_mailbox_blocks_sender() {
+        let mut runtime = Runtime::with_quantum(1);
+        // Jumps to itself forever without ever receiving.
+        let sink = runtime.spawn(VM::builder().program(vec![6, 0]).mailbox_capacity(1).build());
+        // Sends to the sink twice; the second SEND can't be delivered.
+        let sender = runtime.spawn(
+            VM::builder()
+                .program(vec![18, 0, 1, 18, 0, 1, 5])
+                .register(0, sink.index() as i32)
+                .build(),
+        );
+        for _ in 0..4 {
+            runtime.tick();
+        }
+        assert_eq!(runtime.status(sender), Some(&Status::Blocked));
+        assert_eq!(runtime.vm(sink).unwrap().inbox().count(), 1);
+        assert_eq!(runtime.vm(sender).unwrap().outbox().count(), 1);
+
+        runtime.kill(sink);
+        runtime.run();
+        assert_eq!(runtime.status(sender), Some(&Status::Faulted(Fault::NoSuchVm(sink.index() as i32))));
+    }
+
+    #[test]
+    fn test_deadlock_returns() {
+        let mut runtime = Runtime::new();
+        let a = runtime.spawn(VM::builder().program(vec![19, 0]).build());
+        assert_eq!(runtime.join(a), Some(Status::Blocked));
+    }
+}
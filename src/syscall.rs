@@ -0,0 +1,76 @@
+//! `syscall` holds the host services an iridescent program can reach through
+//! the SYSCALL opcode.
+//!
+//! SYSCALL takes a single register operand holding the syscall number. The
+//! handler registered for that number is given the values of
+//! `ARGUMENT_REGISTERS` and whatever it returns is written to
+//! `RESULT_REGISTER`.
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Range;
+
+/// Registers passed, in order, as the arguments of a syscall ($1-$4).
+pub const ARGUMENT_REGISTERS: Range<usize> = 1..5;
+
+/// Register a syscall's return value is stored in ($0).
+pub const RESULT_REGISTER: usize = 0;
+
+/// A host function callable from bytecode. It receives the argument registers
+/// and returns either the result value or a message describing the failure.
+pub type SyscallHandler = Box<dyn FnMut(&[i32]) -> Result<i32, String>>;
+
+/// Maps syscall numbers to the handlers an embedder registered for them.
+#[derive(Default)]
+pub struct SyscallTable {
+    handlers: HashMap<i32, SyscallHandler>,
+}
+
+impl SyscallTable {
+    /// Returns an empty table.
+    pub fn new() -> SyscallTable {
+        SyscallTable {
+            handlers: HashMap::new(),
+        }
+    }
+
+    /// Registers `handler` under `number`, replacing any previous handler.
+    pub fn register<F>(&mut self, number: i32, handler: F)
+    where
+        F: FnMut(&[i32]) -> Result<i32, String> + 'static,
+    {
+        self.handlers.insert(number, Box::new(handler));
+    }
+
+    /// Calls the handler for `number`, or returns `None` if there isn't one.
+    pub fn call(&mut self, number: i32, args: &[i32]) -> Option<Result<i32, String>> {
+        self.handlers.get_mut(&number).map(|handler| handler(args))
+    }
+}
+
+impl fmt::Debug for SyscallTable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut numbers: Vec<&i32> = self.handlers.keys().collect();
+        numbers.sort();
+        f.debug_struct("SyscallTable")
+            .field("numbers", &numbers)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register_and_call() {
+        let mut table = SyscallTable::new();
+        table.register(1, |args| Ok(args[0] + args[1]));
+        assert_eq!(table.call(1, &[2, 3, 0, 0]), Some(Ok(5)));
+    }
+
+    #[test]
+    fn test_call_unknown() {
+        let mut table = SyscallTable::new();
+        assert_eq!(table.call(7, &[0, 0, 0, 0]), None);
+    }
+}
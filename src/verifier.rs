@@ -0,0 +1,360 @@
+//! `verifier` statically checks bytecode before it is run, so malformed
+//! programs are reported up front instead of panicking the VM. The VM runs
+//! it before executing a program it hasn't verified yet.
+//!
+//! Jump targets are checked when they are constant: the target register was
+//! set by a LOAD earlier in the same straight-line run of instructions, as in
+//! `load $3 @loop` followed by `jmp $3`.
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::instruction::{Opcode, Operand};
+use crate::syscall::RESULT_REGISTER;
+use crate::vm::REGISTER_COUNT;
+
+/// A problem found in a program by `verify`.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Violation {
+    /// The byte at `offset` is not a known opcode.
+    UnknownOpcode {
+        /// Where the instruction starts.
+        offset: usize,
+        /// The unknown byte.
+        byte: u8,
+    },
+    /// A register operand names a register that doesn't exist.
+    RegisterOutOfRange {
+        /// Where the instruction starts.
+        offset: usize,
+        /// The register named.
+        register: u8,
+    },
+    /// The program ends part way through an instruction.
+    Truncated {
+        /// Where the instruction starts.
+        offset: usize,
+        /// The instruction's opcode.
+        opcode: Opcode,
+    },
+    /// A jump's constant target is not the start of an instruction.
+    BadJumpTarget {
+        /// Where the jump starts.
+        offset: usize,
+        /// The offset it jumps to.
+        target: i64,
+    },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Violation::UnknownOpcode { offset, byte } => {
+                write!(f, "{}: unknown opcode {}", offset, byte)
+            }
+            Violation::RegisterOutOfRange { offset, register } => {
+                write!(f, "{}: register ${} is out of range", offset, register)
+            }
+            Violation::Truncated { offset, opcode } => {
+                write!(f, "{}: {} is cut off by the end of the program", offset, opcode.mnemonic())
+            }
+            Violation::BadJumpTarget { offset, target } => {
+                write!(f, "{}: jump target {} is not the start of an instruction", offset, target)
+            }
+        }
+    }
+}
+
+/// An instruction decoded from bytecode.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Decoded {
+    /// Where the instruction starts.
+    pub offset: usize,
+    /// The instruction's opcode.
+    pub opcode: Opcode,
+    /// The register operands, in order.
+    pub registers: Vec<u8>,
+    /// The integer operand, if the opcode has one.
+    pub integer: Option<u16>,
+}
+
+impl Decoded {
+    /// Returns the offset of the instruction after this one.
+    pub fn next(&self) -> usize {
+        self.offset + self.opcode.instruction_len()
+    }
+
+    /// Returns the `n`th register operand, if it exists and names a real
+    /// register.
+    pub fn register(&self, n: usize) -> Option<usize> {
+        self.registers.get(n).map(|&r| r as usize).filter(|&r| r < REGISTER_COUNT)
+    }
+}
+
+impl fmt::Display for Decoded {
+    /// Writes the instruction the way it is written in assembly, such as
+    /// `load $0 #500`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.opcode.mnemonic())?;
+        let mut registers = self.registers.iter();
+        for operand in self.opcode.operands() {
+            match operand {
+                Operand::Register => write!(f, " ${}", registers.next().expect("decoded with every register"))?,
+                Operand::Integer => write!(f, " #{}", self.integer.expect("decoded with its integer"))?,
+                Operand::Padding => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Decodes every instruction in `program`, skipping unknown opcodes, along
+/// with the problems found on the way.
+pub fn decode(program: &[u8]) -> (Vec<Decoded>, Vec<Violation>) {
+    let mut violations = vec![];
+    let mut decoded = vec![];
+    let mut offset = 0;
+    while offset < program.len() {
+        let byte = program[offset];
+        let opcode = Opcode::from(byte);
+        if opcode == Opcode::IGL {
+            violations.push(Violation::UnknownOpcode { offset, byte });
+            offset += 1;
+            continue;
+        }
+        if offset + opcode.instruction_len() > program.len() {
+            violations.push(Violation::Truncated { offset, opcode });
+            break;
+        }
+
+        let mut instruction = Decoded { offset, opcode, registers: vec![], integer: None };
+        let mut cursor = offset + 1;
+        for operand in opcode.operands() {
+            match operand {
+                Operand::Register => {
+                    let register = program[cursor];
+                    if register as usize >= REGISTER_COUNT {
+                        violations.push(Violation::RegisterOutOfRange { offset, register });
+                    }
+                    instruction.registers.push(register);
+                }
+                Operand::Integer => {
+                    instruction.integer = Some(((program[cursor] as u16) << 8) | program[cursor + 1] as u16);
+                }
+                Operand::Padding => {}
+            }
+            cursor += operand.width();
+        }
+        decoded.push(instruction);
+        offset = cursor;
+    }
+    (decoded, violations)
+}
+
+/// The registers whose values are known in a straight-line run of
+/// instructions, such as one set by `load $3 @loop`. Arithmetic wraps, as it
+/// does in the VM.
+#[derive(Debug, PartialEq, Clone)]
+pub struct KnownValues {
+    values: [Option<i32>; REGISTER_COUNT],
+}
+
+impl KnownValues {
+    /// Returns values where nothing is known.
+    pub fn unknown() -> KnownValues {
+        KnownValues { values: [None; REGISTER_COUNT] }
+    }
+
+    /// Returns the value of `register`, if it is known.
+    pub fn get(&self, register: usize) -> Option<i32> {
+        self.values.get(register).cloned().flatten()
+    }
+
+    /// Records that `register` holds `value`, or that it isn't known.
+    pub fn set(&mut self, register: usize, value: Option<i32>) {
+        if let Some(slot) = self.values.get_mut(register) {
+            *slot = value;
+        }
+    }
+
+    /// Returns where `instruction` jumps to, if it is a jump and its target is
+    /// known.
+    pub fn jump_target(&self, instruction: &Decoded) -> Option<i64> {
+        let register = instruction.register(0)?;
+        let next = instruction.next() as i64;
+        let value = |register: usize| self.values[register].map(i64::from);
+        match instruction.opcode {
+            Opcode::JMP | Opcode::JEQ | Opcode::JNEQ => value(register),
+            Opcode::JMPF | Opcode::JEQF | Opcode::JNEQF => value(register).map(|value| next + value),
+            Opcode::JMPB | Opcode::JEQB | Opcode::JNEQB => value(register).map(|value| next - value),
+            opcode if opcode.is_jump() => instruction.register(2).and_then(value),
+            _ => None,
+        }
+    }
+
+    /// Updates the values with the registers `instruction` writes.
+    pub fn apply(&mut self, instruction: &Decoded) {
+        let values = &mut self.values;
+        match (instruction.opcode, instruction.register(0)) {
+            (Opcode::LOAD, Some(r)) => values[r] = instruction.integer.map(i32::from),
+            (Opcode::ADD, _) | (Opcode::SUB, _) | (Opcode::MUL, _) | (Opcode::DIV, _) | (Opcode::REM, _) => {
+                if let Some(r) = instruction.register(2) {
+                    values[r] = None;
+                }
+            }
+            (Opcode::RECV, Some(r)) | (Opcode::MFR, Some(r)) => values[r] = None,
+            (Opcode::INC, Some(r)) => values[r] = values[r].map(|value| value.wrapping_add(1)),
+            (Opcode::DEC, Some(r)) => values[r] = values[r].map(|value| value.wrapping_sub(1)),
+            (Opcode::ADDI, Some(r)) | (Opcode::SUBI, Some(r)) | (Opcode::MULI, Some(r)) => {
+                if let Some(d) = instruction.register(1) {
+                    let number = instruction.integer.map(i32::from);
+                    values[d] = match (values[r], number) {
+                        (Some(value), Some(number)) => Some(match instruction.opcode {
+                            Opcode::ADDI => value.wrapping_add(number),
+                            Opcode::SUBI => value.wrapping_sub(number),
+                            _ => value.wrapping_mul(number),
+                        }),
+                        _ => None,
+                    };
+                }
+            }
+            (Opcode::MOV, Some(r)) | (Opcode::SWAP, Some(r)) | (Opcode::LOADX, Some(r)) => {
+                if let Some(d) = instruction.register(1) {
+                    match instruction.opcode {
+                        Opcode::MOV => values[d] = values[r],
+                        Opcode::SWAP => values.swap(r, d),
+                        _ => values[d] = None,
+                    }
+                }
+            }
+            (Opcode::SYSCALL, _) => values[RESULT_REGISTER] = None,
+            _ => {}
+        }
+    }
+}
+
+/// Checks that every opcode in `program` is known, every register operand
+/// exists, no instruction is cut off by the end of the program, and constant
+/// jump targets land on instruction boundaries.
+pub fn verify(program: &[u8]) -> Result<(), Vec<Violation>> {
+    let (decoded, mut violations) = decode(program);
+    let boundaries: HashSet<usize> = decoded.iter().map(|instruction| instruction.offset).collect();
+    let mut known = KnownValues::unknown();
+    for instruction in &decoded {
+        if let Some(target) = known.jump_target(instruction) {
+            let lands = target == program.len() as i64 || (target >= 0 && boundaries.contains(&(target as usize)));
+            if !lands {
+                violations.push(Violation::BadJumpTarget { offset: instruction.offset, target });
+            }
+        }
+        known.apply(instruction);
+        // Anything may jump to the next instruction, so forget what we know.
+        if is_control_flow(instruction.opcode) {
+            known = KnownValues::unknown();
+        }
+    }
+
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(violations)
+    }
+}
+
+/// Returns true for opcodes after which execution may not simply fall through.
+pub fn is_control_flow(opcode: Opcode) -> bool {
+    opcode.is_jump() || opcode == Opcode::HLT
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_valid_program() {
+        // load $0 #6; jmp $0; hlt
+        assert_eq!(verify(&[0, 0, 0, 6, 6, 0, 5]), Ok(()));
+        assert_eq!(verify(&[]), Ok(()));
+    }
+
+    #[test]
+    fn test_verify_unknown_opcode_and_register() {
+        let violations = verify(&[200, 1, 0, 40, 2]).unwrap_err();
+        assert_eq!(
+            violations,
+            vec![
+                Violation::UnknownOpcode { offset: 0, byte: 200 },
+                Violation::RegisterOutOfRange { offset: 1, register: 40 },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_verify_truncated() {
+        let violations = verify(&[5, 0, 1]).unwrap_err();
+        assert_eq!(violations, vec![Violation::Truncated { offset: 1, opcode: Opcode::LOAD }]);
+    }
+
+    #[test]
+    fn test_verify_jump_targets() {
+        // load $0 #5; jmp $0 -- 5 is the middle of the jmp
+        let violations = verify(&[0, 0, 0, 5, 6, 0]).unwrap_err();
+        assert_eq!(violations, vec![Violation::BadJumpTarget { offset: 4, target: 5 }]);
+
+        // load $0 #10; jmpb $0 -- lands before the start of the program
+        let violations = verify(&[0, 0, 0, 10, 8, 0]).unwrap_err();
+        assert_eq!(violations, vec![Violation::BadJumpTarget { offset: 4, target: -4 }]);
+
+        // load $0 #0; jmpf $0 -- falls through to the end, which is allowed
+        assert_eq!(verify(&[0, 0, 0, 0, 7, 0]), Ok(()));
+    }
+
+    #[test]
+    fn test_verify_branch_targets() {
+        // load $2 #3; beq $0 $1 $2 -- 3 is the middle of the load
+        let violations = verify(&[0, 2, 0, 3, 24, 0, 1, 2]).unwrap_err();
+        assert_eq!(violations, vec![Violation::BadJumpTarget { offset: 4, target: 3 }]);
+
+        // load $2 #0; bltu $0 $1 $2
+        assert_eq!(verify(&[0, 2, 0, 0, 31, 0, 1, 2]), Ok(()));
+    }
+
+    #[test]
+    fn test_verify_relative_conditional_targets() {
+        // load $0 #2; jneqf $0; hlt; hlt -- lands on the end of the program
+        assert_eq!(verify(&[0, 0, 0, 2, 36, 0, 5, 5]), Ok(()));
+
+        // load $0 #9; jeqb $0 -- lands before the start of the program
+        let violations = verify(&[0, 0, 0, 9, 35, 0]).unwrap_err();
+        assert_eq!(violations, vec![Violation::BadJumpTarget { offset: 4, target: -3 }]);
+    }
+
+    #[test]
+    fn test_verify_follows_moves() {
+        // load $0 #5; mov $0 $1; jmp $1 -- 5 is the middle of the mov
+        let violations = verify(&[0, 0, 0, 5, 40, 0, 1, 6, 1]).unwrap_err();
+        assert_eq!(violations, vec![Violation::BadJumpTarget { offset: 7, target: 5 }]);
+
+        // load $0 #4; load $1 #5; swap $0 $1; jmp $1
+        assert_eq!(verify(&[0, 0, 0, 4, 0, 1, 0, 5, 41, 0, 1, 6, 1]), Ok(()));
+    }
+
+    #[test]
+    fn test_verify_follows_arithmetic_on_constants() {
+        // load $0 #3; inc $0; addi $0 #1 $1; jmp $1 -- 5 is inside the inc
+        let violations = verify(&[0, 0, 0, 3, 43, 0, 45, 0, 0, 1, 1, 6, 1]).unwrap_err();
+        assert_eq!(violations, vec![Violation::BadJumpTarget { offset: 11, target: 5 }]);
+
+        // load $0 #2; muli $0 #2 $1; jmp $1
+        assert_eq!(verify(&[0, 0, 0, 2, 47, 0, 0, 2, 1, 6, 1]), Ok(()));
+
+        // load $0 #65535; inc $0; muli $0 #256 $0; muli $0 #256 $0; jmp $0
+        // -- 65536 * 256 * 256 wraps to 0, as it does in the VM
+        assert_eq!(verify(&[0, 0, 255, 255, 43, 0, 47, 0, 1, 0, 0, 47, 0, 1, 0, 0, 6, 0]), Ok(()));
+    }
+
+    #[test]
+    fn test_verify_forgets_overwritten_constants() {
+        // load $0 #5; add $1 $1 $0; jmp $0
+        assert_eq!(verify(&[0, 0, 0, 5, 1, 1, 1, 0, 6, 0]), Ok(()));
+    }
+}
@@ -0,0 +1,234 @@
+//! `journal` records what every executed instruction changed, so a VM can be
+//! wound back to an earlier step without rerunning the program from scratch.
+use crate::vm::{Fault, VM};
+
+/// The changes made by a single executed instruction.
+#[derive(Debug, PartialEq, Clone)]
+pub struct JournalEntry {
+    /// The pc before the instruction ran.
+    pub pc_before: usize,
+    /// The pc after the instruction ran.
+    pub pc_after: usize,
+    /// Each register written, as `(index, before, after)`.
+    pub registers: Vec<(usize, i32, i32)>,
+    /// The equal flag before and after the instruction ran.
+    pub equal_flag: (bool, bool),
+    /// The remainder before and after the instruction ran.
+    pub remainder: (i32, i32),
+    /// The message taken from the mailbox by RECV, if any. Messages sent by
+    /// SEND can't be recalled, so undoing a step never unsends them.
+    pub received: Option<i32>,
+}
+
+/// The steps a VM has executed since its journal was enabled, plus any steps
+/// that were wound back and can be replayed.
+#[derive(Debug, Default)]
+pub struct Journal {
+    entries: Vec<JournalEntry>,
+    undone: Vec<JournalEntry>,
+}
+
+impl Journal {
+    /// Returns the entries for the steps currently applied, oldest first.
+    pub fn entries(&self) -> &[JournalEntry] {
+        &self.entries
+    }
+
+    /// Returns how many steps are currently applied.
+    pub fn step(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+impl VM {
+    /// Starts recording a journal of every step from the current state on.
+    /// Any journal already being recorded is discarded.
+    pub fn enable_journal(&mut self) {
+        self.journal = Some(Journal::default());
+    }
+
+    /// Stops recording and discards the journal.
+    pub fn disable_journal(&mut self) {
+        self.journal = None;
+    }
+
+    /// Returns the journal, if one is being recorded.
+    pub fn journal(&self) -> Option<&Journal> {
+        self.journal.as_ref()
+    }
+
+    /// Undoes up to `n` journaled steps, returning how many were undone. Does
+    /// nothing if no journal is being recorded.
+    pub fn back(&mut self, n: usize) -> usize {
+        let mut undone = 0;
+        while undone < n {
+            let entry = match self.journal.as_mut().and_then(|journal| journal.entries.pop()) {
+                Some(entry) => entry,
+                None => break,
+            };
+            for &(index, before, _) in entry.registers.iter().rev() {
+                self.registers[index] = before;
+            }
+            self.pc = entry.pc_before;
+            self.equal_flag = entry.equal_flag.0;
+            self.remainder = entry.remainder.0;
+            if let Some(value) = entry.received {
+                self.inbox.push_front(value);
+            }
+            if let Some(journal) = self.journal.as_mut() {
+                journal.undone.push(entry);
+            }
+            undone += 1;
+        }
+        undone
+    }
+
+    /// Moves to the state after journaled step `n`, counting from when the
+    /// journal was enabled. Steps that were wound back are replayed from the
+    /// journal; steps never reached are executed. Stops early if the program
+    /// finishes first. Does nothing if no journal is being recorded.
+    pub fn goto_step(&mut self, n: usize) -> Result<(), Fault> {
+        let current = match self.journal.as_ref() {
+            Some(journal) => journal.step(),
+            None => return Ok(()),
+        };
+        if n <= current {
+            self.back(current - n);
+            return Ok(());
+        }
+        for _ in current..n {
+            if !self.redo() && self.step()? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reapplies the most recently undone step, if there is one.
+    fn redo(&mut self) -> bool {
+        let entry = match self.journal.as_mut().and_then(|journal| journal.undone.pop()) {
+            Some(entry) => entry,
+            None => return false,
+        };
+        for &(index, _, after) in entry.registers.iter() {
+            self.registers[index] = after;
+        }
+        self.pc = entry.pc_after;
+        self.equal_flag = entry.equal_flag.1;
+        self.remainder = entry.remainder.1;
+        if entry.received.is_some() {
+            self.inbox.pop_front();
+        }
+        if let Some(journal) = self.journal.as_mut() {
+            journal.entries.push(entry);
+        }
+        true
+    }
+
+    /// Executes one instruction, journaling its effects if a journal is being
+    /// recorded.
+    pub(super) fn journaled_step(&mut self) -> Result<bool, Fault> {
+        if self.journal.is_none() || self.pc >= self.program.len() {
+            return self.execute_instruction();
+        }
+        let registers = self.registers;
+        let pc_before = self.pc;
+        let equal_flag = self.equal_flag;
+        let remainder = self.remainder;
+        let inbox_len = self.inbox.len();
+        let next_message = self.inbox.front().cloned();
+
+        let result = self.execute_instruction();
+
+        let entry = JournalEntry {
+            pc_before,
+            pc_after: self.pc,
+            registers: (0..registers.len())
+                .filter(|&index| registers[index] != self.registers[index])
+                .map(|index| (index, registers[index], self.registers[index]))
+                .collect(),
+            equal_flag: (equal_flag, self.equal_flag),
+            remainder: (remainder, self.remainder),
+            received: if self.inbox.len() < inbox_len { next_message } else { None },
+        };
+        if let Some(journal) = self.journal.as_mut() {
+            journal.entries.push(entry);
+            journal.undone.clear();
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // load $0 #1; load $1 #2; add $0 $1 $2; eq $0 $1 $0; hlt
+    fn test_vm() -> VM {
+        let mut test_vm = VM::builder()
+            .program(vec![0, 0, 0, 1, 0, 1, 0, 2, 1, 0, 1, 2, 9, 0, 1, 0, 5])
+            .build();
+        test_vm.enable_journal();
+        test_vm
+    }
+
+    #[test]
+    fn test_journal_records_deltas() {
+        let mut test_vm = test_vm();
+        test_vm.run().unwrap();
+        let journal = test_vm.journal().unwrap();
+        assert_eq!(journal.step(), 5);
+        assert_eq!(journal.entries()[2].registers, vec![(2, 0, 3)]);
+        assert_eq!(journal.entries()[2].pc_before, 8);
+        assert_eq!(journal.entries()[2].pc_after, 12);
+    }
+
+    #[test]
+    fn test_back() {
+        let mut test_vm = test_vm();
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.back(3), 3);
+        assert_eq!(test_vm.pc(), 8);
+        assert_eq!(test_vm.registers()[..3], [1, 2, 0]);
+
+        assert_eq!(test_vm.back(10), 2);
+        assert_eq!(test_vm.pc(), 0);
+        assert_eq!(test_vm.registers()[..3], [0, 0, 0]);
+    }
+
+    #[test]
+    fn test_goto_step() {
+        let mut test_vm = test_vm();
+        test_vm.run().unwrap();
+        test_vm.goto_step(1).unwrap();
+        assert_eq!(test_vm.registers()[..3], [1, 0, 0]);
+
+        test_vm.goto_step(4).unwrap();
+        assert_eq!(test_vm.registers()[..3], [1, 2, 3]);
+        assert!(!test_vm.equal_flag());
+        assert_eq!(test_vm.pc(), 16);
+    }
+
+    #[test]
+    fn test_goto_step_executes_unreached_steps() {
+        let mut test_vm = test_vm();
+        test_vm.goto_step(3).unwrap();
+        assert_eq!(test_vm.registers()[2], 3);
+        assert_eq!(test_vm.journal().unwrap().step(), 3);
+    }
+
+    #[test]
+    fn test_back_restores_received_message() {
+        let mut test_vm = VM::builder().program(vec![19, 0]).build();
+        test_vm.enable_journal();
+        test_vm.deliver(7);
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.journal().unwrap().entries()[0].received, Some(7));
+
+        test_vm.back(1);
+        assert_eq!(test_vm.inbox().cloned().collect::<Vec<i32>>(), vec![7]);
+        test_vm.goto_step(1).unwrap();
+        assert_eq!(test_vm.register(0), 7);
+        assert_eq!(test_vm.inbox().count(), 0);
+    }
+}
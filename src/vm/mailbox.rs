@@ -0,0 +1,83 @@
+//! `mailbox` holds the messages a VM exchanges with other VMs through the
+//! SEND and RECV opcodes. Messages are only moved between VMs by whoever owns
+//! them, usually a `Runtime`.
+use crate::instruction::Opcode;
+use crate::vm::VM;
+
+/// How many messages a VM's mailbox holds unless configured otherwise.
+pub const DEFAULT_MAILBOX_CAPACITY: usize = 16;
+
+impl VM {
+    /// Adds `value` to the VM's mailbox. Returns false, leaving the mailbox
+    /// unchanged, if it is already full.
+    pub fn deliver(&mut self, value: i32) -> bool {
+        if self.inbox.len() >= self.mailbox_capacity {
+            return false;
+        }
+        self.inbox.push_back(value);
+        true
+    }
+
+    /// Returns the messages waiting to be received, oldest first.
+    pub fn inbox(&self) -> impl Iterator<Item = &i32> {
+        self.inbox.iter()
+    }
+
+    /// Returns the messages sent but not yet delivered, as `(target, value)`.
+    pub fn outbox(&self) -> impl Iterator<Item = &(i32, i32)> {
+        self.outbox.iter()
+    }
+
+    /// Removes and returns the oldest undelivered message.
+    pub fn take_message(&mut self) -> Option<(i32, i32)> {
+        self.outbox.pop_front()
+    }
+
+    /// Puts back a message that could not be delivered yet, so it is the next
+    /// one taken.
+    pub fn return_message(&mut self, message: (i32, i32)) {
+        self.outbox.push_front(message);
+    }
+
+    /// Returns true if the VM is waiting on a RECV for a message to arrive.
+    pub fn is_blocked(&self) -> bool {
+        self.inbox.is_empty() && self.program.get(self.pc).map(|&b| Opcode::from(b)) == Some(Opcode::RECV)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::Fault;
+
+    #[test]
+    fn test_send_queues_message() {
+        let mut test_vm = VM::builder().program(vec![18, 0, 1]).register(0, 3).register(1, 42).build();
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.take_message(), Some((3, 42)));
+        assert_eq!(test_vm.take_message(), None);
+    }
+
+    #[test]
+    fn test_recv_blocks_until_delivery() {
+        let mut test_vm = VM::builder().program(vec![19, 4, 5]).build();
+        assert_eq!(test_vm.run(), Err(Fault::Blocked));
+        assert!(test_vm.is_blocked());
+        assert_eq!(test_vm.pc(), 0);
+
+        assert!(test_vm.deliver(9));
+        assert!(!test_vm.is_blocked());
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.register(4), 9);
+        assert_eq!(test_vm.pc(), 3);
+    }
+
+    #[test]
+    fn test_mailbox_capacity() {
+        let mut test_vm = VM::builder().mailbox_capacity(2).build();
+        assert!(test_vm.deliver(1));
+        assert!(test_vm.deliver(2));
+        assert!(!test_vm.deliver(3));
+        assert_eq!(test_vm.inbox().cloned().collect::<Vec<i32>>(), vec![1, 2]);
+    }
+}
@@ -0,0 +1,1093 @@
+//! `vm` contains the iridescent virtual machine and the faults it can raise.
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+
+use crate::debug_info::DebugInfo;
+use crate::instruction::Opcode;
+use crate::syscall::{SyscallTable, ARGUMENT_REGISTERS, RESULT_REGISTER};
+use crate::verifier::{self, Violation};
+
+mod journal;
+mod mailbox;
+mod profiler;
+mod snapshot;
+
+pub use self::journal::{Journal, JournalEntry};
+pub use self::mailbox::DEFAULT_MAILBOX_CAPACITY;
+pub use self::profiler::Profile;
+pub use self::snapshot::{SnapshotError, SNAPSHOT_VERSION};
+
+/// How many registers the VM has.
+pub const REGISTER_COUNT: usize = 32;
+
+/// Reasons a program can stop executing abnormally.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Fault {
+    /// SYSCALL named a number no handler was registered for.
+    UnknownSyscall(i32),
+    /// A syscall handler reported an error.
+    SyscallFailed {
+        /// The syscall number that was called.
+        number: i32,
+        /// The message returned by the handler.
+        message: String,
+    },
+    /// SEND named a VM that doesn't exist or has finished.
+    NoSuchVm(i32),
+    /// DIV or REM had a divisor of zero.
+    DivisionByZero,
+    /// An instruction named a register past the last one, either directly or
+    /// through LOADX.
+    InvalidRegister(i32),
+    /// RECV found the mailbox empty. The VM can carry on once a message is
+    /// delivered.
+    Blocked,
+    /// The program ended partway through an instruction's operands.
+    Truncated,
+    /// A jump's target was before the start of the program.
+    JumpOutOfRange(i64),
+    /// The program failed the verifier, so none of it was run.
+    Unverified(Vec<Violation>),
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Fault::UnknownSyscall(number) => write!(f, "unknown syscall {}", number),
+            Fault::SyscallFailed { number, message } => {
+                write!(f, "syscall {} failed: {}", number, message)
+            }
+            Fault::NoSuchVm(id) => write!(f, "no VM with id {} to send to", id),
+            Fault::DivisionByZero => write!(f, "division by zero"),
+            Fault::InvalidRegister(register) => {
+                write!(f, "register ${} is out of range", register)
+            }
+            Fault::Blocked => write!(f, "blocked on RECV with an empty mailbox"),
+            Fault::Truncated => write!(f, "instruction runs past the end of the program"),
+            Fault::JumpOutOfRange(target) => write!(f, "jump to offset {} is before the start of the program", target),
+            Fault::Unverified(violations) => {
+                write!(f, "program failed verification:")?;
+                for violation in violations {
+                    write!(f, " {};", violation)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Error for Fault {}
+
+#[derive(Debug)]
+
+/// VM provides the ability to instantiate a new VM, via `new` or `builder`
+pub struct VM {
+    registers: [i32; REGISTER_COUNT],
+    // Contains a small amount of fast storage, usually
+    // indicated by the number of bits they can hold.
+    pc: usize,
+    // program counter: will track which byte is currently executing
+    program: Vec<u8>,
+    // A series of bytes representing opcodes to be executed as instructions.
+    remainder: i32,
+    // Stores the potential remainder of DIV opcode executions.
+    equal_flag: bool,
+    // Stores the result of the most recent comparison operation.
+    syscalls: SyscallTable,
+    // Host functions reachable through the SYSCALL opcode.
+    journal: Option<Journal>,
+    // Per-step deltas used to wind execution back, when enabled.
+    profile: Option<Profile>,
+    // Execution counts gathered by the profiler, when enabled.
+    debug_info: Option<DebugInfo>,
+    // Source locations for the loaded program, when the assembler produced them.
+    inbox: VecDeque<i32>,
+    // Messages received from other VMs, oldest first.
+    outbox: VecDeque<(i32, i32)>,
+    // Messages sent by SEND as (target, value), waiting to be delivered.
+    mailbox_capacity: usize,
+    // The most messages the inbox will hold.
+    verified: bool,
+    // Whether the program has passed the verifier since it last changed.
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VM {
+    /// Returns a new instance of the iridescent VM, with registers, program counter, and remainder register initialized
+    /// to zero.
+    pub fn new() -> VM {
+        VM {
+            registers: [0; REGISTER_COUNT],
+            program: vec![], // Vector for storing opcode programs.
+            pc: 0,
+            remainder: 0,
+            equal_flag: false, // Handles results of equality opcodes.
+            syscalls: SyscallTable::new(),
+            journal: None,
+            profile: None,
+            debug_info: None,
+            inbox: VecDeque::new(),
+            outbox: VecDeque::new(),
+            mailbox_capacity: DEFAULT_MAILBOX_CAPACITY,
+            verified: false,
+        }
+    }
+
+    /// Returns a `VMBuilder` for setting up a VM before it runs.
+    pub fn builder() -> VMBuilder {
+        VMBuilder::new()
+    }
+
+    /// Replaces the program and rewinds the program counter to its start.
+    /// Registers are left untouched so they can carry inputs into the program.
+    pub fn load_program(&mut self, program: Vec<u8>) {
+        self.program = program;
+        self.pc = 0;
+        self.verified = false;
+    }
+
+    /// Attaches debug info describing the loaded program, or detaches it.
+    pub fn set_debug_info(&mut self, debug_info: Option<DebugInfo>) {
+        self.debug_info = debug_info;
+    }
+
+    /// Returns the debug info describing the loaded program, if any.
+    pub fn debug_info(&self) -> Option<&DebugInfo> {
+        self.debug_info.as_ref()
+    }
+
+    /// Describes `offset` using the debug info if there is any, as
+    /// `loop+4 (prog.iasm:12)`, or as the bare offset otherwise.
+    pub fn describe(&self, offset: usize) -> String {
+        match &self.debug_info {
+            Some(debug_info) => debug_info.describe(offset),
+            None => offset.to_string(),
+        }
+    }
+
+    /// Runs the verifier over the loaded program.
+    pub fn verify(&self) -> Result<(), Vec<Violation>> {
+        verifier::verify(&self.program)
+    }
+
+    /// Returns the bytecode currently loaded.
+    pub fn program(&self) -> &[u8] {
+        &self.program
+    }
+
+    /// Returns all 32 registers.
+    pub fn registers(&self) -> &[i32; REGISTER_COUNT] {
+        &self.registers
+    }
+
+    /// Returns the value of register `index`. Panics if `index` is 32 or more.
+    pub fn register(&self, index: usize) -> i32 {
+        self.registers[index]
+    }
+
+    /// Sets register `index` to `value`. Panics if `index` is 32 or more.
+    pub fn set_register(&mut self, index: usize, value: i32) {
+        self.registers[index] = value;
+    }
+
+    /// Returns the offset of the next byte to execute.
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    /// Returns the remainder left by the most recent DIV, which has the sign
+    /// of the dividend.
+    pub fn remainder(&self) -> i32 {
+        self.remainder
+    }
+
+    /// Returns the result of the most recent comparison.
+    pub fn equal_flag(&self) -> bool {
+        self.equal_flag
+    }
+
+    /// Appends a single byte to the end of the program.
+    pub fn add_byte(&mut self, b: u8) {
+        self.program.push(b);
+        self.verified = false;
+    }
+
+    /// Makes `handler` reachable from bytecode as syscall `number`. See the
+    /// `syscall` module for the registers used to pass arguments and results.
+    pub fn register_syscall<F>(&mut self, number: i32, handler: F)
+    where
+        F: FnMut(&[i32]) -> Result<i32, String> + 'static,
+    {
+        self.syscalls.register(number, handler);
+    }
+
+    /// This function starts the VM, and proceeds to execute available instructions until there are no more left,
+    /// or until it blocks on a RECV with an empty mailbox, which is reported
+    /// as `Fault::Blocked`.
+    pub fn run(&mut self) -> Result<(), Fault> {
+        let mut is_done = false;
+
+        while !is_done {
+            is_done = self.step()?;
+        }
+        Ok(())
+    }
+
+    /// Executes a single instruction, returning true once the program is done.
+    /// A VM blocked on RECV does nothing and returns `Fault::Blocked` until a
+    /// message is delivered.
+    ///
+    /// The first step after the program changes runs the verifier, and a
+    /// program that fails it returns `Fault::Unverified` without running.
+    pub fn step(&mut self) -> Result<bool, Fault> {
+        if !self.verified {
+            self.verify().map_err(Fault::Unverified)?;
+            self.verified = true;
+        }
+        if self.is_blocked() {
+            return Err(Fault::Blocked);
+        }
+        self.profiled_step()
+    }
+
+    fn execute_instruction(&mut self) -> Result<bool, Fault> {
+        // Executes an individual instruction, useful for observing/debugging.
+        // When this conditional is true, we will have executed all of the
+        // instructions given in our program.
+        if self.pc >= self.program.len() {
+            return Ok(true);
+        }
+        // Checking the whole instruction is there up front means the operand
+        // reads below can't run off the end of the program.
+        if self.pc + Opcode::from(self.program[self.pc]).instruction_len() > self.program.len() {
+            return Err(Fault::Truncated);
+        }
+        match self.decode_opcode() {
+            Opcode::LOAD => {
+ 
+                let register = self.next_register()?;
+
+                // *Assuming LOAD is the first instruction in our program* 
+                // Initially, the program counter is set to 0, targeting the first
+                // byte in the program field on our VM struct.
+                // We call `next_8_bits()`, where we increment the program counter,
+                // which we use to index into our program vector, and return the
+                // byte (*the next 8 bits*) containing the address of the register.
+                let number = self.next_16_bits() as u32;
+
+                // Similar to register, except when this is called, we increment
+                // the program counter twice, to indicate we've moved through
+                // two bytes, or sixteen bits.
+
+                self.registers[register] = number as i32;
+
+            }
+            Opcode::HLT => {
+                // Represents a halting instruction, signaling that program execution should cease.
+                return Ok(true);
+            }
+
+            Opcode::ADD => {
+                let register_1 = self.registers[self.next_register()?];
+                let register_2 = self.registers[self.next_register()?];
+                self.registers[self.next_register()?] = register_1.wrapping_add(register_2);
+            }
+            Opcode::SUB => {
+                let register_1 = self.registers[self.next_register()?];
+                let register_2 = self.registers[self.next_register()?];
+                self.registers[self.next_register()?] = register_1.wrapping_sub(register_2);
+            }
+            Opcode::MUL => {
+                let register_1 = self.registers[self.next_register()?];
+                let register_2 = self.registers[self.next_register()?];
+                self.registers[self.next_register()?] = register_1.wrapping_mul(register_2);
+            }
+
+            Opcode::DIV => {
+                let register_1 = self.registers[self.next_register()?];
+                let register_2 = self.registers[self.next_register()?];
+                if register_2 == 0 {
+                    return Err(Fault::DivisionByZero);
+                }
+                self.registers[self.next_register()?] = register_1.wrapping_div(register_2);
+                self.remainder = register_1.wrapping_rem(register_2);
+            },
+            Opcode::REM => {
+                let register_1 = self.registers[self.next_register()?];
+                let register_2 = self.registers[self.next_register()?];
+                if register_2 == 0 {
+                    return Err(Fault::DivisionByZero);
+                }
+                self.registers[self.next_register()?] = register_1.wrapping_rem(register_2);
+            },
+            Opcode::MFR => {
+                let register = self.next_register()?;
+                self.registers[register] = self.remainder;
+            },
+
+            Opcode::MOV => {
+                let value = self.registers[self.next_register()?];
+                self.registers[self.next_register()?] = value;
+            },
+            Opcode::SWAP => {
+                let register_1 = self.next_register()?;
+                let register_2 = self.next_register()?;
+                self.registers.swap(register_1, register_2);
+            },
+            Opcode::LOADX => {
+                let index = self.registers[self.next_register()?];
+                if index < 0 || index as usize >= REGISTER_COUNT {
+                    return Err(Fault::InvalidRegister(index));
+                }
+                self.registers[self.next_register()?] = self.registers[index as usize];
+            },
+
+            Opcode::INC => {
+                let register = self.next_register()?;
+                self.registers[register] = self.registers[register].wrapping_add(1);
+            },
+            Opcode::DEC => {
+                let register = self.next_register()?;
+                self.registers[register] = self.registers[register].wrapping_sub(1);
+            },
+            Opcode::ADDI => {
+                let register = self.registers[self.next_register()?];
+                let number = self.next_16_bits() as i32;
+                self.registers[self.next_register()?] = register.wrapping_add(number);
+            },
+            Opcode::SUBI => {
+                let register = self.registers[self.next_register()?];
+                let number = self.next_16_bits() as i32;
+                self.registers[self.next_register()?] = register.wrapping_sub(number);
+            },
+            Opcode::MULI => {
+                let register = self.registers[self.next_register()?];
+                let number = self.next_16_bits() as i32;
+                self.registers[self.next_register()?] = register.wrapping_mul(number);
+            },
+
+            Opcode::JMP => {
+                let target = self.registers[self.next_register()?];
+
+                self.jump_to(target)?;
+            },
+
+            Opcode::JMPB => {
+                let value = self.registers[self.next_register()?];
+                self.jump_by(-(value as i64))?;
+            },
+
+            Opcode::JMPF => { 
+                let value = self.registers[self.next_register()?];
+                self.jump_by(value as i64)?;
+            },
+
+            Opcode::EQ => {
+                let register_1 = self.registers[self.next_register()?];
+                let register_2 = self.registers[self.next_register()?];
+
+                self.equal_flag = register_1 == register_2;
+
+                self.next_8_bits();
+            }
+            Opcode::NEQ => {
+                let register_1 = self.registers[self.next_register()?];
+                let register_2 = self.registers[self.next_register()?];
+
+                self.equal_flag = register_1 != register_2;
+
+                self.next_8_bits();
+            },
+
+            Opcode::GT => {
+                let register_1 = self.registers[self.next_register()?];
+                let register_2 = self.registers[self.next_register()?];
+
+                self.equal_flag = register_1 > register_2;
+
+                self.next_8_bits();
+
+            },
+            Opcode::LT => {
+                let register_1 = self.registers[self.next_register()?];
+                let register_2 = self.registers[self.next_register()?];
+
+                self.equal_flag = register_1 < register_2;
+
+                self.next_8_bits();
+
+            }
+            Opcode::GTQ => {
+                let register_1 = self.registers[self.next_register()?];
+                let register_2 = self.registers[self.next_register()?];
+
+                self.equal_flag = register_1 >= register_2;
+
+                self.next_8_bits();
+
+            },
+            Opcode::LTQ => {
+                let register_1 = self.registers[self.next_register()?];
+                let register_2 = self.registers[self.next_register()?];
+
+                self.equal_flag = register_1 <= register_2;
+
+                self.next_8_bits();
+
+            },
+
+            Opcode::JEQ => {
+                let register = self.next_register()?;
+                let target = self.registers[register];
+                if self.equal_flag {
+                    self.jump_to(target)?;
+                }
+            },
+            Opcode::JNEQ => {
+                let register = self.next_register()?;
+                let target = self.registers[register];
+                if !self.equal_flag {
+                    self.jump_to(target)?;
+                }
+            },
+
+            Opcode::SYSCALL => {
+                let number = self.registers[self.next_register()?];
+                match self.syscalls.call(number, &self.registers[ARGUMENT_REGISTERS]) {
+                    Some(Ok(result)) => self.registers[RESULT_REGISTER] = result,
+                    Some(Err(message)) => return Err(Fault::SyscallFailed { number, message }),
+                    None => return Err(Fault::UnknownSyscall(number)),
+                }
+            },
+
+            Opcode::SEND => {
+                let target = self.registers[self.next_register()?];
+                let value = self.registers[self.next_register()?];
+                self.outbox.push_back((target, value));
+            },
+
+            Opcode::RECV => {
+                let register = self.next_register()?;
+                match self.inbox.pop_front() {
+                    Some(value) => self.registers[register] = value,
+                    // Leave the pc on the RECV so it is retried once a message arrives.
+                    None => self.pc -= 2,
+                }
+            },
+
+            Opcode::GTU => {
+                let register_1 = self.registers[self.next_register()?] as u32;
+                let register_2 = self.registers[self.next_register()?] as u32;
+
+                self.equal_flag = register_1 > register_2;
+
+                self.next_8_bits();
+            },
+            Opcode::LTU => {
+                let register_1 = self.registers[self.next_register()?] as u32;
+                let register_2 = self.registers[self.next_register()?] as u32;
+
+                self.equal_flag = register_1 < register_2;
+
+                self.next_8_bits();
+            },
+            Opcode::GEU => {
+                let register_1 = self.registers[self.next_register()?] as u32;
+                let register_2 = self.registers[self.next_register()?] as u32;
+
+                self.equal_flag = register_1 >= register_2;
+
+                self.next_8_bits();
+            },
+            Opcode::LEU => {
+                let register_1 = self.registers[self.next_register()?] as u32;
+                let register_2 = self.registers[self.next_register()?] as u32;
+
+                self.equal_flag = register_1 <= register_2;
+
+                self.next_8_bits();
+            },
+
+            Opcode::BEQ => self.branch_if(|a, b| a == b)?,
+            Opcode::BNE => self.branch_if(|a, b| a != b)?,
+            Opcode::BGT => self.branch_if(|a, b| a > b)?,
+            Opcode::BLT => self.branch_if(|a, b| a < b)?,
+            Opcode::BGE => self.branch_if(|a, b| a >= b)?,
+            Opcode::BLE => self.branch_if(|a, b| a <= b)?,
+            Opcode::BGTU => self.branch_if(|a, b| a as u32 > b as u32)?,
+            Opcode::BLTU => self.branch_if(|a, b| (a as u32) < b as u32)?,
+            Opcode::BGEU => self.branch_if(|a, b| a as u32 >= b as u32)?,
+            Opcode::BLEU => self.branch_if(|a, b| a as u32 <= b as u32)?,
+
+            Opcode::JEQF => {
+                let value = self.registers[self.next_register()?];
+                if self.equal_flag {
+                    self.jump_by(value as i64)?;
+                }
+            },
+            Opcode::JEQB => {
+                let value = self.registers[self.next_register()?];
+                if self.equal_flag {
+                    self.jump_by(-(value as i64))?;
+                }
+            },
+            Opcode::JNEQF => {
+                let value = self.registers[self.next_register()?];
+                if !self.equal_flag {
+                    self.jump_by(value as i64)?;
+                }
+            },
+            Opcode::JNEQB => {
+                let value = self.registers[self.next_register()?];
+                if !self.equal_flag {
+                    self.jump_by(-(value as i64))?;
+                }
+            },
+
+            Opcode::IGL => return Ok(true),
+        }
+
+        Ok(false)
+    }
+
+    /// Reads two registers to compare and a register holding the target,
+    /// jumping to the target if `condition` holds for the two values.
+    fn branch_if(&mut self, condition: fn(i32, i32) -> bool) -> Result<(), Fault> {
+        let register_1 = self.registers[self.next_register()?];
+        let register_2 = self.registers[self.next_register()?];
+        let target = self.registers[self.next_register()?];
+        if condition(register_1, register_2) {
+            self.jump_to(target)?;
+        }
+        Ok(())
+    }
+
+    /// Moves the program counter to `target`, which a register holds.
+    fn jump_to(&mut self, target: i32) -> Result<(), Fault> {
+        self.pc = usize::try_from(target).map_err(|_| Fault::JumpOutOfRange(target as i64))?;
+        Ok(())
+    }
+
+    /// Moves the program counter `offset` bytes on from the end of the
+    /// current instruction, or back for a negative `offset`. The offset is
+    /// signed, as the verifier assumes.
+    fn jump_by(&mut self, offset: i64) -> Result<(), Fault> {
+        let target = (self.pc as i64).checked_add(offset).ok_or(Fault::JumpOutOfRange(offset))?;
+        self.pc = usize::try_from(target).map_err(|_| Fault::JumpOutOfRange(target))?;
+        Ok(())
+    }
+
+    fn decode_opcode(&mut self) -> Opcode {
+        let opcode = Opcode::from(self.program[self.pc]);
+        self.pc += 1;
+
+        opcode
+    }
+
+    fn next_8_bits(&mut self) -> u8 {
+        let result = self.program[self.pc];
+        self.pc += 1;
+        result
+    }
+
+    /// Reads a register operand, faulting if it names a register that
+    /// doesn't exist.
+    fn next_register(&mut self) -> Result<usize, Fault> {
+        let register = self.next_8_bits();
+        if register as usize >= REGISTER_COUNT {
+            return Err(Fault::InvalidRegister(register as i32));
+        }
+        Ok(register as usize)
+    }
+
+    fn next_16_bits(&mut self) -> u16 {
+        // Original state of two bytes: a = [x,x,x,x,x,x,x,x], b = [y,y,y,y,y,y,y,y].
+
+        // x as u16 -> [0,0,0,0,0,0,0,0, x,x,x,x,x,x,x,x]
+        // y as u16 -> [0,0,0,0,0,0,0,0, y,y,y,y,y,y,y,y]
+        // x << 8   -> [x,x,x,x,x,x,x,x, 0,0,0,0,0,0,0,0]
+        // v = x | y -> [x,x,x,x,x,x,x,x,y,y,y,y,y,y,y,y]
+        // The pipe (|) is a bitwise or operator, that returns a 1 in each bit 
+        // position for which the associated bits of each operand are 1, else 0.
+        let result = ((self.program[self.pc] as u16) << 8) | self.program[self.pc + 1] as u16;
+        self.pc += 2;
+        result
+    }
+}
+
+/// Builds a `VM` with its program, registers and syscalls set up front.
+#[derive(Debug, Default)]
+pub struct VMBuilder {
+    vm: VM,
+}
+
+impl VMBuilder {
+    /// Returns a builder for an empty VM.
+    pub fn new() -> VMBuilder {
+        VMBuilder { vm: VM::new() }
+    }
+
+    /// Sets the bytecode the VM will execute.
+    pub fn program(mut self, program: Vec<u8>) -> VMBuilder {
+        self.vm.load_program(program);
+        self
+    }
+
+    /// Sets the initial value of register `index`.
+    pub fn register(mut self, index: usize, value: i32) -> VMBuilder {
+        self.vm.set_register(index, value);
+        self
+    }
+
+    /// Attaches debug info describing the program.
+    pub fn debug_info(mut self, debug_info: DebugInfo) -> VMBuilder {
+        self.vm.set_debug_info(Some(debug_info));
+        self
+    }
+
+    /// Sets the most messages the VM's mailbox will hold.
+    pub fn mailbox_capacity(mut self, capacity: usize) -> VMBuilder {
+        self.vm.mailbox_capacity = capacity;
+        self
+    }
+
+    /// Registers a syscall handler, as `VM::register_syscall` does.
+    pub fn syscall<F>(mut self, number: i32, handler: F) -> VMBuilder
+    where
+        F: FnMut(&[i32]) -> Result<i32, String> + 'static,
+    {
+        self.vm.register_syscall(number, handler);
+        self
+    }
+
+    /// Returns the configured VM. Its program is verified when it first
+    /// steps; see `build_verified` to find violations sooner.
+    pub fn build(self) -> VM {
+        self.vm
+    }
+
+    /// Returns the configured VM if its program passes the verifier, or
+    /// every violation found otherwise.
+    pub fn build_verified(self) -> Result<VM, Vec<Violation>> {
+        self.vm.verify()?;
+        Ok(self.vm)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_create_vm() {
+        let test_vm = VM::new();
+        assert_eq!(test_vm.registers[0], 0);
+    }
+    #[test]
+    fn test_opcode_hlt() {
+        let mut test_vm = VM::new();
+        let test_bytes = vec![5, 0, 0, 0, 1];
+        test_vm.program = test_bytes;
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.pc, 1);
+    }
+    #[test]
+    fn test_load_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![0,0,1,244];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[0], 500);
+    }
+
+    #[test]
+    fn test_add_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 5;
+        test_vm.registers[1] = 4;
+        test_vm.program = vec![1,0,1,2];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[2], 9);
+    }
+    #[test]
+    fn test_sub_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 5;
+        test_vm.registers[1] = 4;
+        test_vm.program = vec![2,0,1,2];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[2], 1);
+    }
+    #[test]
+    fn test_mul_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 5;
+        test_vm.registers[1] = 4;
+        test_vm.program = vec![3,0,1,2];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[2], 20);
+    }
+    #[test]
+    fn test_arithmetic_wraps() {
+        let mut test_vm = VM::builder()
+            .program(vec![1, 0, 1, 2, 2, 3, 1, 4, 3, 0, 1, 5])
+            .register(0, i32::MAX)
+            .register(1, 2)
+            .register(3, i32::MIN)
+            .build();
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.register(2), i32::MIN + 1);
+        assert_eq!(test_vm.register(4), i32::MAX - 1);
+        assert_eq!(test_vm.register(5), -2);
+    }
+
+    #[test]
+    fn test_div_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 5;
+        test_vm.registers[1] = 4;
+        test_vm.program = vec![4,0,1,2];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[2], 1);
+        assert_eq!(test_vm.remainder, 1);
+    }
+    #[test]
+    fn test_div_negative_remainder() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = -7;
+        test_vm.registers[1] = 2;
+        test_vm.program = vec![4,0,1,2,39,3];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[2], -3);
+        assert_eq!(test_vm.remainder, -1);
+        assert_eq!(test_vm.registers[3], -1);
+    }
+    #[test]
+    fn test_rem_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 17;
+        test_vm.registers[1] = -5;
+        test_vm.program = vec![38,0,1,2];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[2], 2);
+        assert_eq!(test_vm.remainder, 0);
+    }
+    #[test]
+    fn test_mfr_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 17;
+        test_vm.registers[1] = 5;
+        test_vm.program = vec![4,0,1,2, 39,3];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[3], 2);
+    }
+    #[test]
+    fn test_mov_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 5;
+        test_vm.program = vec![40,0,1];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[1], 5);
+        assert_eq!(test_vm.registers[0], 5);
+    }
+    #[test]
+    fn test_swap_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 5;
+        test_vm.registers[1] = 4;
+        test_vm.program = vec![41,0,1];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[..2], [4, 5]);
+    }
+    #[test]
+    fn test_loadx_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 7;
+        test_vm.registers[7] = 42;
+        test_vm.program = vec![42,0,1];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[1], 42);
+
+        test_vm.registers[0] = 32;
+        test_vm.load_program(vec![42,0,1]);
+        assert_eq!(test_vm.run(), Err(Fault::InvalidRegister(32)));
+    }
+    #[test]
+    fn test_inc_dec_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 5;
+        test_vm.program = vec![43,0,43,0,44,1];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[0], 7);
+        assert_eq!(test_vm.registers[1], -1);
+    }
+    #[test]
+    fn test_immediate_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 5;
+        test_vm.program = vec![45,0,0,5,1, 46,0,1,4,2, 47,0,0,3,3];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[1], 10);
+        assert_eq!(test_vm.registers[2], 5 - 260);
+        assert_eq!(test_vm.registers[3], 15);
+    }
+    #[test]
+    fn test_division_by_zero_faults() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 5;
+        test_vm.program = vec![4,0,1,2];
+        assert_eq!(test_vm.run(), Err(Fault::DivisionByZero));
+
+        test_vm.load_program(vec![38,0,1,2]);
+        assert_eq!(test_vm.run(), Err(Fault::DivisionByZero));
+    }
+    #[test]
+    fn test_opcode_igl() {
+        let mut test_vm = VM::new();
+        let test_bytes = vec![200,0,0,0];
+        test_vm.program = test_bytes;
+        assert_eq!(test_vm.execute_instruction(), Ok(true));
+        assert_eq!(test_vm.pc, 1);
+    }
+    #[test]
+
+    fn test_jmp_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 1;
+        test_vm.program = vec![6, 0, 0, 0];
+        test_vm.execute_instruction().unwrap();
+        assert_eq!(test_vm.pc, 1);
+    }
+
+    #[test]
+
+    fn test_jmpf_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 2;
+        test_vm.program = vec![7, 0, 0, 0, 6, 0, 0, 0,];
+        test_vm.execute_instruction().unwrap();
+        assert_eq!(test_vm.pc, 4);
+    }
+
+    #[test]
+    fn test_syscall_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.register_syscall(3, |args| Ok(args[0] * args[1]));
+        test_vm.registers[1] = 6;
+        test_vm.registers[2] = 7;
+        test_vm.registers[5] = 3;
+        test_vm.program = vec![17, 5];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[0], 42);
+    }
+
+    #[test]
+    fn test_syscall_unknown_number() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 99;
+        test_vm.program = vec![17, 0];
+        assert_eq!(test_vm.run(), Err(Fault::UnknownSyscall(99)));
+    }
+
+    #[test]
+    fn test_syscall_handler_error() {
+        let mut test_vm = VM::new();
+        test_vm.register_syscall(1, |_| Err("no such file".to_string()));
+        test_vm.registers[0] = 1;
+        test_vm.program = vec![17, 0];
+        assert_eq!(
+            test_vm.run(),
+            Err(Fault::SyscallFailed { number: 1, message: "no such file".to_string() })
+        );
+    }
+
+    #[test]
+    fn test_unsigned_comparisons() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = -1;
+        test_vm.registers[1] = 1;
+        test_vm.program = vec![20, 0, 1, 0];
+        test_vm.run().unwrap();
+        assert!(test_vm.equal_flag);
+
+        test_vm.load_program(vec![11, 0, 1, 0]);
+        test_vm.run().unwrap();
+        assert!(!test_vm.equal_flag);
+
+        test_vm.load_program(vec![21, 0, 1, 0]);
+        test_vm.run().unwrap();
+        assert!(!test_vm.equal_flag);
+
+        test_vm.load_program(vec![22, 0, 0, 0, 23, 1, 0, 0]);
+        test_vm.step().unwrap();
+        assert!(test_vm.equal_flag);
+        test_vm.step().unwrap();
+        assert!(test_vm.equal_flag);
+    }
+
+    #[test]
+    fn test_compare_and_branch() {
+        // Counts $0 up to $1, branching back to 0 with bltu while it is below.
+        // add $0 $2 $0; bltu $0 $1 $3; hlt
+        let mut test_vm = VM::builder()
+            .program(vec![1, 0, 2, 0, 31, 0, 1, 3, 5])
+            .register(1, 5)
+            .register(2, 1)
+            .build();
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.register(0), 5);
+        assert_eq!(test_vm.pc(), 9);
+        assert!(!test_vm.equal_flag());
+
+        // beq $0 $1 $2 with $0 != $1 falls through; bgt $0 $1 $2 jumps.
+        let mut test_vm = VM::builder()
+            .program(vec![24, 0, 1, 2, 26, 0, 1, 2])
+            .register(0, 3)
+            .register(2, 40)
+            .build();
+        test_vm.step().unwrap();
+        assert_eq!(test_vm.pc(), 4);
+        test_vm.step().unwrap();
+        assert_eq!(test_vm.pc(), 40);
+    }
+
+    #[test]
+    fn test_relative_conditional_jumps() {
+        // The same loop placed at two different offsets: counts $0 up to $1,
+        // then jneqb $3 jumps 10 bytes back to the add while they differ.
+        // add $0 $2 $0; eq $0 $1 $0; jneqb $3; hlt
+        let body = vec![1, 0, 2, 0, 9, 0, 1, 0, 37, 3, 5];
+        for offset in [0, 6] {
+            let mut program = vec![5; offset];
+            program.extend(&body);
+            let mut test_vm = VM::builder()
+                .program(program)
+                .register(1, 3)
+                .register(2, 1)
+                .register(3, 10)
+                .build();
+            test_vm.pc = offset;
+            test_vm.run().unwrap();
+            assert_eq!(test_vm.register(0), 3);
+            assert_eq!(test_vm.pc(), offset + 11);
+        }
+
+        // jeqf $0 skips the hlt only when the equal flag is set.
+        let mut test_vm = VM::builder().program(vec![34, 0, 5, 5]).register(0, 1).build();
+        test_vm.equal_flag = true;
+        test_vm.step().unwrap();
+        assert_eq!(test_vm.pc(), 3);
+        test_vm.load_program(vec![34, 0, 5, 5]);
+        test_vm.equal_flag = false;
+        test_vm.step().unwrap();
+        assert_eq!(test_vm.pc(), 2);
+    }
+
+    #[test]
+    fn test_absolute_jump_range() {
+        // jmp, jeq, jneq and the branches fault on a negative target rather
+        // than ending the program.
+        for program in [vec![6, 0], vec![15, 0], vec![16, 0], vec![24, 1, 1, 0]] {
+            let mut test_vm = VM::builder().program(program.clone()).register(0, -4).build();
+            test_vm.equal_flag = program[0] == 15;
+            assert_eq!(test_vm.step(), Err(Fault::JumpOutOfRange(-4)), "{:?}", program);
+            assert_eq!(test_vm.pc(), program.len());
+        }
+    }
+
+    #[test]
+    fn test_relative_jump_range() {
+        // jeqb $0 with $0 past the start of the program faults, leaving the
+        // pc on the jump.
+        let mut test_vm = VM::builder().program(vec![35, 0, 5]).register(0, 10).build();
+        test_vm.equal_flag = true;
+        assert_eq!(test_vm.step(), Err(Fault::JumpOutOfRange(-8)));
+
+        // A negative jeqf offset jumps back, and a negative jmpb one forward.
+        let mut test_vm = VM::builder().program(vec![5, 34, 0]).register(0, -3).build();
+        test_vm.pc = 1;
+        test_vm.equal_flag = true;
+        test_vm.step().unwrap();
+        assert_eq!(test_vm.pc(), 0);
+        let mut test_vm = VM::builder().program(vec![8, 0, 5, 5]).register(0, -1).build();
+        test_vm.step().unwrap();
+        assert_eq!(test_vm.pc(), 3);
+    }
+
+    #[test]
+    fn test_invalid_register_faults() {
+        // The verifier rejects these up front, so they are executed directly.
+        let mut test_vm = VM::new();
+        test_vm.program = vec![0, 32, 0, 1];
+        assert_eq!(test_vm.execute_instruction(), Err(Fault::InvalidRegister(32)));
+
+        test_vm.load_program(vec![1, 0, 1, 255]);
+        assert_eq!(test_vm.execute_instruction(), Err(Fault::InvalidRegister(255)));
+    }
+
+    #[test]
+    fn test_truncated_instruction_faults() {
+        let mut test_vm = VM::builder().program(vec![0, 0, 1]).build();
+        assert_eq!(test_vm.execute_instruction(), Err(Fault::Truncated));
+        assert_eq!(test_vm.pc(), 0);
+
+        test_vm.load_program(vec![0, 0, 0, 7, 1, 0, 0]);
+        test_vm.execute_instruction().unwrap();
+        assert_eq!(test_vm.execute_instruction(), Err(Fault::Truncated));
+        assert_eq!((test_vm.register(0), test_vm.pc()), (7, 4));
+    }
+
+    #[test]
+    fn test_run_verifies_first() {
+        // None of a program that fails the verifier runs, not even the
+        // instructions before the problem.
+        let mut test_vm = VM::builder().program(vec![0, 0, 0, 7, 1, 0, 0]).build();
+        let truncated = Violation::Truncated { offset: 4, opcode: Opcode::ADD };
+        assert_eq!(test_vm.run(), Err(Fault::Unverified(vec![truncated.clone()])));
+        assert_eq!(test_vm.step(), Err(Fault::Unverified(vec![truncated])));
+        assert_eq!((test_vm.register(0), test_vm.pc()), (0, 0));
+
+        // Changing the program verifies it again.
+        test_vm.add_byte(2);
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.register(0), 7);
+        test_vm.add_byte(200);
+        assert!(matches!(test_vm.step(), Err(Fault::Unverified(_))));
+        test_vm.load_program(vec![5]);
+        assert_eq!(test_vm.step(), Ok(true));
+    }
+
+    #[test]
+    fn test_builder() {
+        let mut test_vm = VM::builder()
+            .program(vec![1, 0, 1, 2])
+            .register(0, 5)
+            .register(1, 4)
+            .build();
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.register(2), 9);
+        assert_eq!(test_vm.pc(), 4);
+    }
+
+    #[test]
+    fn test_load_program_rewinds_pc() {
+        let mut test_vm = VM::new();
+        test_vm.load_program(vec![0, 0, 0, 7]);
+        test_vm.run().unwrap();
+        test_vm.load_program(vec![0, 1, 0, 8]);
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers()[..2], [7, 8]);
+    }
+
+    #[test]
+    fn test_build_verified() {
+        assert!(VM::builder().program(vec![0, 0, 1, 244]).build_verified().is_ok());
+        let violations = VM::builder().program(vec![0, 0, 1]).build_verified().unwrap_err();
+        assert_eq!(violations.len(), 1);
+    }
+}
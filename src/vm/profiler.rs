@@ -0,0 +1,173 @@
+//! `profiler` counts what a VM executes, to help find hot loops.
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use crate::debug_info::DebugInfo;
+use crate::instruction::Opcode;
+use crate::vm::{Fault, VM};
+
+/// How many of the hottest addresses `Profile`'s report lists.
+const HOT_ADDRESSES: usize = 10;
+
+/// Execution counts gathered while a VM's profiler is enabled.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Profile {
+    total: u64,
+    jumps_taken: u64,
+    opcodes: HashMap<Opcode, u64>,
+    addresses: BTreeMap<usize, u64>,
+}
+
+impl Profile {
+    /// Returns how many instructions were executed.
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// Returns how many jumps moved the pc, conditional or not.
+    pub fn jumps_taken(&self) -> u64 {
+        self.jumps_taken
+    }
+
+    /// Returns how many times `opcode` was executed.
+    pub fn opcode_count(&self, opcode: Opcode) -> u64 {
+        self.opcodes.get(&opcode).cloned().unwrap_or(0)
+    }
+
+    /// Returns how many times the instruction at `pc` was executed.
+    pub fn address_count(&self, pc: usize) -> u64 {
+        self.addresses.get(&pc).cloned().unwrap_or(0)
+    }
+
+    /// Returns every executed opcode with its count, most frequent first.
+    pub fn opcodes(&self) -> Vec<(Opcode, u64)> {
+        let mut opcodes: Vec<(Opcode, u64)> = self.opcodes.iter().map(|(&op, &n)| (op, n)).collect();
+        opcodes.sort_by(|a, b| b.1.cmp(&a.1).then((a.0 as u8).cmp(&(b.0 as u8))));
+        opcodes
+    }
+
+    /// Returns every executed address with its count, most frequent first.
+    pub fn hot_addresses(&self) -> Vec<(usize, u64)> {
+        let mut addresses: Vec<(usize, u64)> = self.addresses.iter().map(|(&pc, &n)| (pc, n)).collect();
+        addresses.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        addresses
+    }
+
+    /// Writes a report of the profile, naming addresses by label and source
+    /// line when `debug_info` is given.
+    pub fn report(&self, debug_info: Option<&DebugInfo>) -> String {
+        let mut report = format!(
+            "Instructions executed: {}\nJumps taken: {}\nBy opcode:\n",
+            self.total, self.jumps_taken
+        );
+        for (opcode, count) in self.opcodes() {
+            report.push_str(&format!("  {:<8} {}\n", opcode.mnemonic(), count));
+        }
+        report.push_str("Hottest addresses:\n");
+        for (pc, count) in self.hot_addresses().into_iter().take(HOT_ADDRESSES) {
+            match debug_info {
+                Some(debug_info) => {
+                    report.push_str(&format!("  {:<8} {:<8} {}\n", pc, count, debug_info.describe(pc)))
+                }
+                None => report.push_str(&format!("  {:<8} {}\n", pc, count)),
+            }
+        }
+        report
+    }
+
+    fn record(&mut self, pc: usize, opcode: Opcode, pc_after: usize) {
+        self.total += 1;
+        *self.opcodes.entry(opcode).or_insert(0) += 1;
+        *self.addresses.entry(pc).or_insert(0) += 1;
+        if opcode.is_jump() && pc_after != pc + opcode.instruction_len() {
+            self.jumps_taken += 1;
+        }
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.report(None))
+    }
+}
+
+impl VM {
+    /// Starts counting executed instructions from the current state on. Any
+    /// profile already being gathered is discarded.
+    pub fn enable_profiler(&mut self) {
+        self.profile = Some(Profile::default());
+    }
+
+    /// Stops counting and discards the profile.
+    pub fn disable_profiler(&mut self) {
+        self.profile = None;
+    }
+
+    /// Returns the profile, if the profiler is enabled.
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    /// Executes one instruction, counting it if the profiler is enabled.
+    pub(super) fn profiled_step(&mut self) -> Result<bool, Fault> {
+        let pc = self.pc;
+        let opcode = match (&self.profile, self.program.get(pc)) {
+            (Some(_), Some(&byte)) => Opcode::from(byte),
+            _ => return self.journaled_step(),
+        };
+        let result = self.journaled_step();
+        let pc_after = self.pc;
+        if let Some(profile) = self.profile.as_mut() {
+            profile.record(pc, opcode, pc_after);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profile_counts_loop() {
+        // load $0 #0; load $1 #1; load $2 #3; load $3 #16
+        // 16: add $0 $1 $0; eq $0 $2 $0; jneq $3; hlt
+        let mut test_vm = VM::builder()
+            .program(vec![
+                0, 0, 0, 0, 0, 1, 0, 1, 0, 2, 0, 3, 0, 3, 0, 16,
+                1, 0, 1, 0, 9, 0, 2, 0, 16, 3, 5,
+            ])
+            .build();
+        test_vm.enable_profiler();
+        test_vm.run().unwrap();
+
+        let profile = test_vm.profile().unwrap();
+        assert_eq!(profile.total(), 4 + 3 * 3 + 1);
+        assert_eq!(profile.jumps_taken(), 2);
+        assert_eq!(profile.opcode_count(Opcode::ADD), 3);
+        assert_eq!(profile.opcode_count(Opcode::SUB), 0);
+        assert_eq!(profile.address_count(16), 3);
+        assert_eq!(profile.hot_addresses()[0], (16, 3));
+        assert_eq!(profile.opcodes()[0], (Opcode::LOAD, 4));
+    }
+
+    #[test]
+    fn test_profile_disabled_by_default() {
+        let mut test_vm = VM::builder().program(vec![5]).build();
+        test_vm.run().unwrap();
+        assert!(test_vm.profile().is_none());
+    }
+
+    #[test]
+    fn test_report_names_labels() {
+        let mut debug_info = DebugInfo::new("prog.iasm");
+        debug_info.add_label("loop", 0);
+        debug_info.add_line(0, 3);
+        let mut test_vm = VM::builder().program(vec![5]).build();
+        test_vm.enable_profiler();
+        test_vm.run().unwrap();
+
+        let report = test_vm.profile().unwrap().report(Some(&debug_info));
+        assert!(report.contains("0        1        loop (prog.iasm:3)"));
+    }
+}
@@ -0,0 +1,263 @@
+//! `snapshot` serializes the full state of a `VM` to a versioned binary blob,
+//! so a paused program can be persisted and resumed later or elsewhere.
+//!
+//! The layout, with every integer big-endian, is:
+//!
+//! | bytes | contents                          |
+//! |-------|-----------------------------------|
+//! | 4     | magic, `IRVM`                     |
+//! | 2     | format version                    |
+//! | 128   | the 32 registers, as `i32`        |
+//! | 8     | pc, as `u64`                      |
+//! | 4     | remainder, as `i32`               |
+//! | 1     | equal flag, 0 or 1                |
+//! | 8     | program length, as `u64`          |
+//! | n     | program                           |
+//! | 4     | mailbox capacity, as `u32`        |
+//! | 4     | inbox length, as `u32`            |
+//! | 4 * n | inbox messages, as `i32`          |
+//! | 4     | outbox length, as `u32`           |
+//! | 8 * n | outbox `(target, value)` pairs    |
+//!
+//! Syscall handlers live in the host and are not part of a snapshot; they
+//! must be registered again on the restored VM.
+use std::error::Error;
+use std::fmt;
+
+use crate::vm::VM;
+
+const MAGIC: &[u8; 4] = b"IRVM";
+
+/// The snapshot format version written by `VM::snapshot`.
+pub const SNAPSHOT_VERSION: u16 = 1;
+
+/// Reasons a snapshot can fail to be taken or restored.
+#[derive(Debug, PartialEq, Clone)]
+pub enum SnapshotError {
+    /// The blob does not start with the snapshot magic bytes.
+    BadMagic,
+    /// The blob was written by a format version this build can't read.
+    UnsupportedVersion(u16),
+    /// The blob ended before all of the VM state was read.
+    Truncated,
+    /// The blob was read fully but describes an impossible VM.
+    Malformed(&'static str),
+    /// The VM holds a value too big for its field in the format.
+    TooLarge(&'static str),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnapshotError::BadMagic => write!(f, "not an iridescent snapshot"),
+            SnapshotError::UnsupportedVersion(version) => {
+                write!(f, "unsupported snapshot version {}", version)
+            }
+            SnapshotError::Truncated => write!(f, "snapshot is truncated"),
+            SnapshotError::Malformed(reason) => write!(f, "malformed snapshot: {}", reason),
+            SnapshotError::TooLarge(field) => write!(f, "{} is too large for a snapshot", field),
+        }
+    }
+}
+
+impl Error for SnapshotError {}
+
+/// Reads fixed-size fields from the front of a snapshot.
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], SnapshotError> {
+        if self.bytes.len() < n {
+            return Err(SnapshotError::Truncated);
+        }
+        let (head, tail) = self.bytes.split_at(n);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, SnapshotError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, SnapshotError> {
+        let mut buf = [0; 2];
+        buf.copy_from_slice(self.take(2)?);
+        Ok(u16::from_be_bytes(buf))
+    }
+
+    fn u32(&mut self) -> Result<u32, SnapshotError> {
+        let mut buf = [0; 4];
+        buf.copy_from_slice(self.take(4)?);
+        Ok(u32::from_be_bytes(buf))
+    }
+
+    fn u64(&mut self) -> Result<u64, SnapshotError> {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(self.take(8)?);
+        Ok(u64::from_be_bytes(buf))
+    }
+}
+
+impl VM {
+    /// Serializes the VM's registers, pc, program, remainder, equal flag and
+    /// mailboxes. Fails if the mailbox capacity or a mailbox's length doesn't
+    /// fit in the `u32` the format stores it as.
+    pub fn snapshot(&self) -> Result<Vec<u8>, SnapshotError> {
+        let u32_field = |value: usize, field| u32::try_from(value).map_err(|_| SnapshotError::TooLarge(field));
+
+        let mut bytes = Vec::with_capacity(155 + self.program.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&SNAPSHOT_VERSION.to_be_bytes());
+        for register in self.registers.iter() {
+            bytes.extend_from_slice(&register.to_be_bytes());
+        }
+        bytes.extend_from_slice(&(self.pc as u64).to_be_bytes());
+        bytes.extend_from_slice(&self.remainder.to_be_bytes());
+        bytes.push(self.equal_flag as u8);
+        bytes.extend_from_slice(&(self.program.len() as u64).to_be_bytes());
+        bytes.extend_from_slice(&self.program);
+        bytes.extend_from_slice(&u32_field(self.mailbox_capacity, "mailbox capacity")?.to_be_bytes());
+        bytes.extend_from_slice(&u32_field(self.inbox.len(), "inbox length")?.to_be_bytes());
+        for value in &self.inbox {
+            bytes.extend_from_slice(&value.to_be_bytes());
+        }
+        bytes.extend_from_slice(&u32_field(self.outbox.len(), "outbox length")?.to_be_bytes());
+        for (target, value) in &self.outbox {
+            bytes.extend_from_slice(&target.to_be_bytes());
+            bytes.extend_from_slice(&value.to_be_bytes());
+        }
+        Ok(bytes)
+    }
+
+    /// Reconstructs a VM from a blob produced by `snapshot`. The restored VM
+    /// continues exactly where the original left off, once any syscalls it
+    /// uses have been registered again.
+    pub fn restore(bytes: &[u8]) -> Result<VM, SnapshotError> {
+        let mut reader = Reader { bytes };
+        if reader.take(MAGIC.len()).map_err(|_| SnapshotError::BadMagic)? != MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+        let version = reader.u16()?;
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+
+        let mut vm = VM::new();
+        for register in vm.registers.iter_mut() {
+            *register = reader.u32()? as i32;
+        }
+        let pc = reader.u64()?;
+        vm.remainder = reader.u32()? as i32;
+        vm.equal_flag = match reader.u8()? {
+            0 => false,
+            1 => true,
+            _ => return Err(SnapshotError::Malformed("equal flag is not 0 or 1")),
+        };
+        let length = reader.u64()?;
+        if length > reader.bytes.len() as u64 {
+            return Err(SnapshotError::Truncated);
+        }
+        vm.program = reader.take(length as usize)?.to_vec();
+        vm.mailbox_capacity = reader.u32()? as usize;
+        let inbox = reader.u32()?;
+        if inbox as usize > vm.mailbox_capacity {
+            return Err(SnapshotError::Malformed("inbox holds more messages than its capacity"));
+        }
+        for _ in 0..inbox {
+            vm.inbox.push_back(reader.u32()? as i32);
+        }
+        for _ in 0..reader.u32()? {
+            let target = reader.u32()? as i32;
+            let value = reader.u32()? as i32;
+            vm.outbox.push_back((target, value));
+        }
+        if !reader.bytes.is_empty() {
+            return Err(SnapshotError::Malformed("trailing bytes after the VM state"));
+        }
+        if pc > vm.program.len() as u64 {
+            return Err(SnapshotError::Malformed("pc is past the end of the program"));
+        }
+        vm.pc = pc as usize;
+        Ok(vm)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_restore_continues_deterministically() {
+        // load $0 #10; load $1 #3; div $0 $1 $2; eq $0 $0 $0; add $2 $0 $3
+        let program = vec![0, 0, 0, 10, 0, 1, 0, 3, 4, 0, 1, 2, 9, 0, 0, 0, 1, 2, 0, 3];
+        let mut reference = VM::builder().program(program.clone()).build();
+        reference.run().unwrap();
+
+        let mut paused = VM::builder().program(program).build();
+        for _ in 0..4 {
+            paused.step().unwrap();
+        }
+        let mut restored = VM::restore(&paused.snapshot().unwrap()).unwrap();
+        assert_eq!(restored.pc(), paused.pc());
+        assert_eq!(restored.remainder(), 1);
+        assert!(restored.equal_flag());
+
+        restored.run().unwrap();
+        assert_eq!(restored.registers(), reference.registers());
+        assert_eq!(restored.pc(), reference.pc());
+    }
+
+    #[test]
+    fn test_restore_rejects_bad_blobs() {
+        let blob = VM::builder().program(vec![5]).build().snapshot().unwrap();
+
+        assert_eq!(VM::restore(b"nope").unwrap_err(), SnapshotError::BadMagic);
+        assert_eq!(VM::restore(&blob[..blob.len() - 1]).unwrap_err(), SnapshotError::Truncated);
+
+        let mut future = blob.clone();
+        future[5] = 99;
+        assert_eq!(VM::restore(&future).unwrap_err(), SnapshotError::UnsupportedVersion(99));
+
+        let mut trailing = blob;
+        trailing.push(0);
+        assert!(matches!(VM::restore(&trailing), Err(SnapshotError::Malformed(_))));
+    }
+
+    #[test]
+    fn test_restore_mailboxes() {
+        let mut test_vm = VM::builder().program(vec![18, 0, 1, 19, 2]).mailbox_capacity(4).build();
+        test_vm.set_register(0, 1);
+        test_vm.set_register(1, -5);
+        test_vm.step().unwrap();
+        test_vm.deliver(8);
+
+        let mut restored = VM::restore(&test_vm.snapshot().unwrap()).unwrap();
+        assert_eq!(restored.take_message(), Some((1, -5)));
+        restored.run().unwrap();
+        assert_eq!(restored.register(2), 8);
+        for value in 0..4 {
+            assert!(restored.deliver(value));
+        }
+        assert!(!restored.deliver(4));
+    }
+
+    #[test]
+    fn test_snapshot_checks_mailbox_sizes() {
+        let huge = VM::builder().mailbox_capacity(u32::MAX as usize + 1).build();
+        assert_eq!(huge.snapshot(), Err(SnapshotError::TooLarge("mailbox capacity")));
+
+        let mut full = VM::builder().mailbox_capacity(2).build();
+        full.deliver(1);
+        full.deliver(2);
+        let mut blob = full.snapshot().unwrap();
+        // Shrink the stored capacity below the two messages held.
+        let capacity = blob.len() - 4 - 8 - 4 - 4;
+        blob[capacity..capacity + 4].copy_from_slice(&1u32.to_be_bytes());
+        assert_eq!(
+            VM::restore(&blob).unwrap_err(),
+            SnapshotError::Malformed("inbox holds more messages than its capacity")
+        );
+    }
+}